@@ -0,0 +1,304 @@
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use serde_json::{json, Map};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::{
+    config::AuthPolicy,
+    middleware::rate_limit::store::RateLimitStore,
+    routing::{cache::ResponseCache, ServiceState},
+};
+
+// Most rate limit buckets listed at once
+const MAX_BUCKETS: usize = 1000;
+
+// Every request to the admin listener, enforced by its `JwtMiddleware`
+// before any handler or extractor runs
+pub fn policy() -> AuthPolicy {
+    AuthPolicy::Require {
+        roles: vec!["admin".into()],
+        scopes: vec![],
+    }
+}
+
+// POST /admin/reload: re-read the gateway config and swap the routing table
+pub async fn reload_config(state: web::Data<Arc<ServiceState>>) -> HttpResponse {
+    match state.reload() {
+        Ok(services) => HttpResponse::Ok().json(json!({
+            "status": "reloaded",
+            "services": services
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "error": format!("Reload failed: {}", err)
+        })),
+    }
+}
+
+// GET /admin/breakers: circuit breaker state of every upstream, grouped by service
+pub async fn breakers(state: web::Data<Arc<ServiceState>>) -> HttpResponse {
+    let registry = state.registry();
+    let mut services = Map::new();
+    for service in registry.services() {
+        let upstreams: Vec<_> = service
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                json!({
+                    "url": upstream.url,
+                    "breaker": upstream.breaker.snapshot(&service.circuit_breaker),
+                })
+            })
+            .collect();
+        services.insert(
+            service.name.clone(),
+            json!({
+                "enabled": service.circuit_breaker.enabled,
+                "upstreams": upstreams,
+            }),
+        );
+    }
+
+    HttpResponse::Ok().json(json!({ "services": services }))
+}
+
+// GET /admin/routes: the live routing table, services and aggregates in match order
+pub async fn routes(state: web::Data<Arc<ServiceState>>) -> HttpResponse {
+    let registry = state.registry();
+    let services: Vec<_> = registry
+        .services()
+        .iter()
+        .map(|service| {
+            let routes: Vec<_> = service
+                .routes
+                .iter()
+                .map(|route| {
+                    json!({
+                        "path": route.path,
+                        "methods": route.methods.iter().map(|m| m.as_str()).collect::<Vec<_>>(),
+                        "auth": route.auth,
+                        "rate_limit": route.rate_limit,
+                        "timeouts": route.timeouts,
+                        "cache": route.cache,
+                        "protocol": route.protocol,
+                    })
+                })
+                .collect();
+            json!({
+                "name": service.name,
+                "prefix": service.prefix,
+                "aliases": service.aliases,
+                "protocol": service.protocol,
+                "auth": service.auth,
+                "rate_limit": service.rate_limit,
+                "timeouts": service.timeouts,
+                "strategy": service.strategy,
+                "upstreams": service.upstreams.iter().map(|u| &u.url).collect::<Vec<_>>(),
+                "routes": routes,
+            })
+        })
+        .collect();
+    let aggregates: Vec<_> = registry
+        .aggregates()
+        .iter()
+        .map(|aggregate| {
+            let parts: Vec<_> = aggregate
+                .parts
+                .iter()
+                .map(|part| {
+                    json!({
+                        "name": part.name,
+                        "path": part.template,
+                        "required": part.required,
+                    })
+                })
+                .collect();
+            json!({
+                "path": aggregate.path,
+                "auth": aggregate.auth,
+                "rate_limit": aggregate.rate_limit,
+                "total_ms": aggregate.total.as_millis() as u64,
+                "parts": parts,
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(json!({ "services": services, "aggregates": aggregates }))
+}
+
+// GET /admin/upstreams: health, breaker, load and rotation state of every upstream
+pub async fn upstreams(state: web::Data<Arc<ServiceState>>) -> HttpResponse {
+    let registry = state.registry();
+    let mut services = Map::new();
+    for service in registry.services() {
+        let upstreams: Vec<_> = service
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                json!({
+                    "url": upstream.url,
+                    "drained": upstream.is_drained(),
+                    "in_flight": upstream.in_flight(),
+                    "health": upstream.health.snapshot(),
+                    "breaker": upstream.breaker.snapshot(&service.circuit_breaker),
+                })
+            })
+            .collect();
+        services.insert(
+            service.name.clone(),
+            json!({
+                "checked": service.health_check.is_some(),
+                "upstreams": upstreams,
+            }),
+        );
+    }
+
+    HttpResponse::Ok().json(json!({ "services": services }))
+}
+
+#[derive(Deserialize)]
+pub struct DrainRequest {
+    service: String,
+    url: String,
+    // `false` puts the upstream back into rotation
+    #[serde(default = "default_drained")]
+    drained: bool,
+}
+
+fn default_drained() -> bool {
+    true
+}
+
+// POST /admin/upstreams/drain: take an upstream out of rotation (or put it
+// back). Requests already in flight finish; `in_flight` tells when it's idle.
+// The flag survives reloads for as long as the upstream stays configured.
+pub async fn drain_upstream(
+    state: web::Data<Arc<ServiceState>>,
+    body: web::Json<DrainRequest>,
+) -> HttpResponse {
+    let registry = state.registry();
+    let upstream = registry
+        .services()
+        .iter()
+        .find(|service| service.name == body.service)
+        .and_then(|service| service.upstreams.iter().find(|u| u.url == body.url));
+    let Some(upstream) = upstream else {
+        return HttpResponse::NotFound().json(json!({
+            "error": format!("No upstream {} in service {}", body.url, body.service)
+        }));
+    };
+
+    upstream.set_drained(body.drained);
+    info!(service = %body.service, upstream = %body.url, drained = body.drained, "upstream rotation changed");
+    HttpResponse::Ok().json(json!({
+        "service": body.service,
+        "url": upstream.url,
+        "drained": upstream.is_drained(),
+        "in_flight": upstream.in_flight(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct BucketQuery {
+    // Key prefix such as `post|` or `auth:/api/v1/auth/login|`
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+// GET /admin/rate-limits: current rate limit buckets of this gateway's store
+pub async fn rate_limits(
+    store: web::Data<Arc<dyn RateLimitStore>>,
+    query: web::Query<BucketQuery>,
+) -> HttpResponse {
+    let limit = query.limit.unwrap_or(MAX_BUCKETS).min(MAX_BUCKETS);
+    match store.buckets(&query.prefix, limit).await {
+        Ok(buckets) => HttpResponse::Ok().json(json!({ "buckets": buckets })),
+        Err(err) => HttpResponse::ServiceUnavailable().json(json!({
+            "error": format!("Rate limit store unavailable: {}", err)
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PurgeRequest {
+    // Path prefix such as `/api/v1/posts`; `/` empties the cache
+    prefix: String,
+}
+
+// POST /admin/cache/purge: drop cached responses under a path prefix
+pub async fn purge_cache(
+    cache: web::Data<ResponseCache>,
+    body: web::Json<PurgeRequest>,
+) -> HttpResponse {
+    let purged = cache.purge(&body.prefix);
+    HttpResponse::Ok().json(json!({
+        "status": "purged",
+        "entries": purged
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{Claims, JwtVerifier},
+        config::{AuthConfig, GatewayConfig},
+        middleware::jwt::JwtMiddleware,
+    };
+    use actix_web::{
+        http::{header, StatusCode},
+        test, App,
+    };
+    use jsonwebtoken::{EncodingKey, Header};
+
+    const SECRET: &str = "test-secret";
+
+    fn token(role: &str) -> String {
+        let claims = Claims {
+            sub: "1".into(),
+            role: role.into(),
+            exp: jsonwebtoken::get_current_timestamp() as usize + 60,
+            scope: None,
+            scp: None,
+        };
+        let key = EncodingKey::from_secret(SECRET.as_bytes());
+        jsonwebtoken::encode(&Header::default(), &claims, &key).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn callers_are_checked_before_the_body_is_parsed() {
+        let config: GatewayConfig = toml::from_str("services = []").unwrap();
+        let state = Arc::new(ServiceState::new("gateway.toml".into(), &config).unwrap());
+        let verifier = Arc::new(JwtVerifier::new(&AuthConfig::default(), Some(SECRET), None));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ResponseCache::new(&config.cache)))
+                .route("/admin/cache/purge", web::post().to(purge_cache))
+                .wrap(JwtMiddleware {
+                    verifier,
+                    state,
+                    policy: Some(policy()),
+                }),
+        )
+        .await;
+
+        let cases = [
+            (None, StatusCode::UNAUTHORIZED),
+            (Some(token("user")), StatusCode::FORBIDDEN),
+            (Some(token("admin")), StatusCode::BAD_REQUEST),
+        ];
+        for (token, expected) in cases {
+            let mut req = test::TestRequest::post()
+                .uri("/admin/cache/purge")
+                .insert_header((header::CONTENT_TYPE, "application/json"))
+                .set_payload("not json");
+            if let Some(token) = &token {
+                req = req.insert_header((header::AUTHORIZATION, format!("Bearer {}", token)));
+            }
+            let res = test::call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), expected, "{:?}", token);
+        }
+    }
+}
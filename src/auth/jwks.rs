@@ -0,0 +1,194 @@
+use actix_web::{
+    rt::{self, time},
+    web,
+};
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, JwkSet, PublicKeyUse},
+    Algorithm, DecodingKey,
+};
+use std::{
+    fs, io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::config::JwksConfig;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A signature verification key from the set
+pub struct Key {
+    kid: Option<String>,
+    // Set when the JWK pins its algorithm
+    alg: Option<Algorithm>,
+    pub decoding: DecodingKey,
+}
+
+impl Key {
+    // Whether this key may have signed a token with the given header fields.
+    // A token without `kid` is tried against every key of the right algorithm.
+    pub fn matches(&self, kid: Option<&str>, alg: Algorithm) -> bool {
+        let kid_ok = kid.is_none_or(|kid| self.kid.as_deref() == Some(kid));
+        kid_ok && self.alg.is_none_or(|pinned| pinned == alg)
+    }
+}
+
+enum Source {
+    File(PathBuf),
+    Url(String),
+}
+
+// Cached JWKS. The whole set is swapped on refresh, so during a rotation the
+// issuer publishes old and new keys side by side and both keep verifying.
+pub struct KeySet {
+    source: Source,
+    client: reqwest::Client,
+    min_refresh: Duration,
+    keys: RwLock<Arc<Vec<Key>>>,
+    last_refresh: Mutex<Option<Instant>>,
+}
+
+impl KeySet {
+    pub fn new(config: &JwksConfig) -> Self {
+        let source = match (&config.file, &config.url) {
+            (Some(file), _) => Source::File(file.clone()),
+            (None, Some(url)) => Source::Url(url.clone()),
+            // Rejected when the config is validated
+            (None, None) => unreachable!("auth.jwks without file or url"),
+        };
+
+        KeySet {
+            source,
+            client: reqwest::Client::builder()
+                .timeout(FETCH_TIMEOUT)
+                .build()
+                .expect("failed to build JWKS HTTP client"),
+            min_refresh: Duration::from_secs(config.min_refresh_secs),
+            keys: RwLock::new(Arc::new(Vec::new())),
+            last_refresh: Mutex::new(None),
+        }
+    }
+
+    pub fn keys(&self) -> Arc<Vec<Key>> {
+        self.keys.read().unwrap().clone()
+    }
+
+    // Fetch and swap in the current set. On error the cached keys stay in place.
+    pub async fn refresh(&self) -> io::Result<usize> {
+        *self.last_refresh.lock().unwrap() = Some(Instant::now());
+
+        let raw = match &self.source {
+            Source::File(path) => {
+                let path = path.clone();
+                // Off the async workers, which a slow disk would otherwise stall
+                web::block(move || fs::read(path))
+                    .await
+                    .map_err(io::Error::other)??
+            }
+            Source::Url(url) => self
+                .client
+                .get(url)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+                .map_err(io::Error::other)?
+                .bytes()
+                .await
+                .map_err(io::Error::other)?
+                .to_vec(),
+        };
+        let set: JwkSet = serde_json::from_slice(&raw)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let keys: Vec<Key> = set.keys.iter().filter_map(to_key).collect();
+        let count = keys.len();
+        *self.keys.write().unwrap() = Arc::new(keys);
+        Ok(count)
+    }
+
+    // Refresh ahead of schedule because a token named a key we don't have,
+    // e.g. right after the issuer rotated. Rate limited so tokens with made-up
+    // `kid`s can't make us hammer the issuer. Returns whether a refresh happened.
+    pub async fn refresh_for_unknown_kid(&self) -> bool {
+        let due = self
+            .last_refresh
+            .lock()
+            .unwrap()
+            .is_none_or(|last| last.elapsed() >= self.min_refresh);
+        if !due {
+            return false;
+        }
+
+        match self.refresh().await {
+            Ok(_) => true,
+            Err(err) => {
+                warn!(%err, "JWKS refresh failed; keeping cached keys");
+                false
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.source {
+            Source::File(path) => path.display().to_string(),
+            Source::Url(url) => url.clone(),
+        }
+    }
+}
+
+// Signing keys only; symmetric (`oct`) keys never come from a JWKS
+fn to_key(jwk: &jsonwebtoken::jwk::Jwk) -> Option<Key> {
+    if matches!(jwk.common.public_key_use, Some(PublicKeyUse::Encryption))
+        || matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_))
+    {
+        return None;
+    }
+
+    let kid = jwk.common.key_id.clone();
+    let alg = match jwk.common.key_algorithm {
+        Some(alg) => match Algorithm::from_str(&alg.to_string()) {
+            Ok(alg) => Some(alg),
+            // An encryption algorithm such as RSA-OAEP
+            Err(_) => return None,
+        },
+        None => None,
+    };
+    match DecodingKey::from_jwk(jwk) {
+        Ok(decoding) => Some(Key { kid, alg, decoding }),
+        Err(err) => {
+            warn!(kid = ?jwk.common.key_id, %err, "skipping unusable JWK");
+            None
+        }
+    }
+}
+
+// Re-read the key set every `refresh_secs`
+pub fn spawn_refresher(keys: Arc<KeySet>, refresh_secs: u64) {
+    rt::spawn(async move {
+        let mut ticker = time::interval(Duration::from_secs(refresh_secs));
+        // The first tick fires immediately; the set was loaded at startup
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            match keys.refresh().await {
+                Ok(count) => info!(source = %keys.describe(), keys = count, "JWKS refreshed"),
+                Err(err) => {
+                    warn!(source = %keys.describe(), %err, "JWKS refresh failed; keeping cached keys")
+                }
+            }
+        }
+    });
+}
+
+// Initial load at startup. A failure is not fatal: the issuer may simply be
+// unreachable for now, and the refresher keeps trying.
+pub async fn load(keys: &KeySet) {
+    match keys.refresh().await {
+        Ok(count) => info!(source = %keys.describe(), keys = count, "JWKS loaded"),
+        Err(err) => warn!(source = %keys.describe(), %err, "JWKS load failed"),
+    }
+}
@@ -0,0 +1,176 @@
+use actix_web::{
+    http::header::{self, HeaderValue},
+    HttpResponse,
+};
+use serde_json::json;
+
+use super::Claims;
+use crate::config::{AuthLevel, AuthPolicy};
+
+// Apply a route's access policy to the caller. `identity` is the verified
+// token, or the 401 explaining why there is none (missing, malformed,
+// expired, ...), which is returned when the policy needs a caller.
+pub fn authorize(
+    policy: &AuthPolicy,
+    identity: Result<&Claims, HttpResponse>,
+) -> Result<(), HttpResponse> {
+    let (roles, scopes) = match policy {
+        AuthPolicy::Level(AuthLevel::Public) => return Ok(()),
+        AuthPolicy::Level(AuthLevel::Authenticated) => (&[][..], &[][..]),
+        AuthPolicy::Require { roles, scopes } => (&roles[..], &scopes[..]),
+    };
+
+    let claims = match identity {
+        Ok(claims) => claims,
+        Err(mut unauthenticated) => {
+            unauthenticated
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return Err(unauthenticated);
+        }
+    };
+
+    if !roles.is_empty() && !roles.contains(&claims.role) {
+        return Err(HttpResponse::Forbidden().json(json!({
+            "error": format!("Requires role: {}", roles.join(" or "))
+        })));
+    }
+
+    let missing: Vec<&str> = scopes
+        .iter()
+        .map(String::as_str)
+        .filter(|scope| !claims.has_scope(scope))
+        .collect();
+    if !missing.is_empty() {
+        // RFC 6750 section 3.1
+        let challenge = format!(
+            "Bearer error=\"insufficient_scope\", scope=\"{}\"",
+            scopes.join(" ")
+        );
+        let mut res = HttpResponse::Forbidden();
+        if let Ok(value) = HeaderValue::from_str(&challenge) {
+            res.insert_header((header::WWW_AUTHENTICATE, value));
+        }
+        return Err(res.json(json!({
+            "error": format!("Missing scope: {}", missing.join(" "))
+        })));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::registry::Registry;
+    use actix_web::http::{Method, StatusCode};
+
+    fn claims(role: &str, scope: &str) -> Claims {
+        Claims {
+            sub: "1".into(),
+            role: role.into(),
+            exp: usize::MAX,
+            scope: Some(scope.into()),
+            scp: None,
+        }
+    }
+
+    fn policy(toml: &str) -> AuthPolicy {
+        #[derive(serde::Deserialize)]
+        struct Wrapper {
+            auth: AuthPolicy,
+        }
+        toml::from_str::<Wrapper>(&format!("auth = {}", toml))
+            .unwrap()
+            .auth
+    }
+
+    fn unauthenticated() -> Result<&'static Claims, HttpResponse> {
+        Err(HttpResponse::Unauthorized().finish())
+    }
+
+    fn challenge(res: &HttpResponse) -> Option<&str> {
+        res.headers()
+            .get(header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    #[test]
+    fn public_routes_need_no_caller() {
+        let public = policy(r#""public""#);
+        assert!(authorize(&public, unauthenticated()).is_ok());
+        assert!(authorize(&public, Ok(&claims("user", ""))).is_ok());
+    }
+
+    #[test]
+    fn missing_callers_get_a_bearer_challenge() {
+        for toml in [r#""authenticated""#, r#"{ roles = ["admin"] }"#] {
+            let denied = authorize(&policy(toml), unauthenticated()).unwrap_err();
+            assert_eq!(denied.status(), StatusCode::UNAUTHORIZED, "{}", toml);
+            assert_eq!(challenge(&denied), Some("Bearer"), "{}", toml);
+        }
+        assert!(authorize(&policy(r#""authenticated""#), Ok(&claims("user", ""))).is_ok());
+    }
+
+    #[test]
+    fn roles_need_one_of_them() {
+        let admins = policy(r#"{ roles = ["admin", "moderator"] }"#);
+        assert!(authorize(&admins, Ok(&claims("moderator", ""))).is_ok());
+
+        let denied = authorize(&admins, Ok(&claims("user", ""))).unwrap_err();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        assert_eq!(challenge(&denied), None);
+    }
+
+    #[test]
+    fn scopes_need_all_of_them() {
+        let writers = policy(r#"{ scopes = ["posts:read", "posts:write"] }"#);
+        assert!(authorize(&writers, Ok(&claims("", "posts:write posts:read"))).is_ok());
+        let listed = Claims {
+            scope: None,
+            scp: Some(vec!["posts:read".into(), "posts:write".into()]),
+            ..claims("", "")
+        };
+        assert!(authorize(&writers, Ok(&listed)).is_ok());
+
+        let denied = authorize(&writers, Ok(&claims("admin", "posts:read"))).unwrap_err();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            challenge(&denied),
+            Some(r#"Bearer error="insufficient_scope", scope="posts:read posts:write""#)
+        );
+    }
+
+    #[test]
+    fn route_policies_override_the_service_policy() {
+        let registry = Registry::from_toml(
+            r#"
+            [[services]]
+            name = "post"
+            prefix = "/api/v1/posts"
+            auth = "public"
+            upstreams = [{ url = "http://posts:8082" }]
+
+            [[services.routes]]
+            path = "/api/v1/posts/{id}"
+            methods = ["DELETE"]
+            auth = { roles = ["admin"] }
+            "#,
+            None,
+        )
+        .unwrap();
+        let user = claims("user", "");
+
+        let read = registry
+            .auth_policy(&Method::GET, "/api/v1/posts/7")
+            .unwrap();
+        assert!(authorize(read, unauthenticated()).is_ok());
+
+        let delete = registry
+            .auth_policy(&Method::DELETE, "/api/v1/posts/7")
+            .unwrap();
+        let denied = authorize(delete, Ok(&user)).unwrap_err();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        assert!(authorize(delete, Ok(&claims("admin", ""))).is_ok());
+    }
+}
@@ -0,0 +1,1176 @@
+use actix_web::http::Method;
+use jsonwebtoken::Algorithm;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+use tracing_subscriber::filter::Targets;
+
+const DEFAULT_CONFIG_PATH: &str = "gateway.toml";
+
+// Top-level gateway configuration, loaded from `GATEWAY_CONFIG` (default `gateway.toml`)
+#[derive(Debug, Clone, Deserialize)]
+pub struct GatewayConfig {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub reload: ReloadConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub cache: CacheSettings,
+    #[serde(default)]
+    pub services: Vec<ServiceConfig>,
+    #[serde(default)]
+    pub aggregates: Vec<AggregateConfig>,
+}
+
+// Client-facing listener. Read once at startup; changes require a restart
+// (certificate files themselves are reloaded when they change).
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfig {
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    // Serve HTTPS instead of plain HTTP
+    #[serde(default)]
+    pub tls: Option<ServerTlsConfig>,
+    // On SIGTERM, `/ready` fails for this long before the listeners close
+    #[serde(default = "default_drain_delay_secs")]
+    pub drain_delay_secs: u64,
+    // Then in-flight requests and open streams get this long to finish
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    // Proxies (load balancers) in front of the gateway that append to
+    // `X-Forwarded-For`; 0 takes the client IP from the connection itself
+    #[serde(default)]
+    pub trusted_proxy_hops: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: default_bind(),
+            tls: None,
+            drain_delay_secs: default_drain_delay_secs(),
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            trusted_proxy_hops: 0,
+        }
+    }
+}
+
+// Listener for the `/admin` API, kept off the public port. Read once at startup.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminConfig {
+    #[serde(default = "default_admin_bind")]
+    pub bind: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            bind: default_admin_bind(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerTlsConfig {
+    // PEM certificate chain and private key
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // CA bundle for verifying client certificates; enables mutual TLS
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    // Reject clients without a certificate instead of merely verifying presented ones
+    #[serde(default)]
+    pub require_client_cert: bool,
+    // Check `cert`/`key` for changes every N seconds (0 disables reloading)
+    #[serde(default = "default_cert_watch_interval_secs")]
+    pub watch_interval_secs: u64,
+}
+
+// TLS towards a service's `https://` upstreams
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UpstreamTlsConfig {
+    // CA bundle trusted for upstream certificates, in addition to the public roots
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+    // Client certificate chain and key presented to upstreams (mutual TLS)
+    #[serde(default)]
+    pub cert: Option<PathBuf>,
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+}
+
+// How client JWTs are verified. Read once at startup; JWKS keys themselves are
+// refreshed while running.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    // Signing algorithms accepted from clients. HMAC tokens are checked against
+    // `JWT_SECRET`, everything else against the JWKS.
+    #[serde(default = "default_jwt_algorithms")]
+    pub algorithms: Vec<Algorithm>,
+    // Required `iss` claim
+    #[serde(default)]
+    pub issuer: Option<String>,
+    // Accepted `aud` values; tokens must name at least one of them
+    #[serde(default)]
+    pub audience: Option<Vec<String>>,
+    // Clock skew tolerated when checking `exp` and `nbf`
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub leeway_secs: u64,
+    #[serde(default)]
+    pub jwks: Option<JwksConfig>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            algorithms: default_jwt_algorithms(),
+            issuer: None,
+            audience: None,
+            leeway_secs: default_jwt_leeway_secs(),
+            jwks: None,
+        }
+    }
+}
+
+// Public keys for asymmetric tokens, from a local file or the issuer's endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct JwksConfig {
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub url: Option<String>,
+    // Re-read the key set every N seconds
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub refresh_secs: u64,
+    // A token with an unknown `kid` triggers an early refresh, at most this often
+    #[serde(default = "default_jwks_min_refresh_secs")]
+    pub min_refresh_secs: u64,
+}
+
+// How the gateway picks up config changes without a restart.
+// Read once at startup; changing these values requires a restart.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReloadConfig {
+    // Poll the config file for changes every N seconds (0 disables polling)
+    #[serde(default = "default_watch_interval_secs")]
+    pub watch_interval_secs: u64,
+    // Reload on SIGHUP
+    #[serde(default = "default_true")]
+    pub on_sighup: bool,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        ReloadConfig {
+            watch_interval_secs: default_watch_interval_secs(),
+            on_sighup: true,
+        }
+    }
+}
+
+// Log output and the per-request access log. Read once at startup;
+// `RUST_LOG`, when set, takes precedence over `level`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    // `tracing` filter directives, e.g. `info` or `warn,gateway=debug`
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    // Include the client's request headers in access log entries
+    #[serde(default)]
+    pub headers: bool,
+    // Headers whose values are never logged (case-insensitive)
+    #[serde(default = "default_redact_headers")]
+    pub redact_headers: Vec<String>,
+    // Query parameters whose values are never logged (case-insensitive)
+    #[serde(default = "default_redact_params")]
+    pub redact_params: Vec<String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::default(),
+            level: default_log_level(),
+            headers: false,
+            redact_headers: default_redact_headers(),
+            redact_params: default_redact_params(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // One JSON object per line
+    #[default]
+    Json,
+    // Human-readable lines, for local development
+    Text,
+}
+
+// Storage for cached responses, shared by every route with a `cache` policy.
+// Read once at startup.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheSettings {
+    // Total size of cached bodies and headers; least recently used entries go first
+    #[serde(default = "default_cache_max_bytes")]
+    pub max_bytes: usize,
+    // Larger responses are relayed without being cached
+    #[serde(default = "default_cache_max_entry_bytes")]
+    pub max_entry_bytes: usize,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            max_bytes: default_cache_max_bytes(),
+            max_entry_bytes: default_cache_max_entry_bytes(),
+        }
+    }
+}
+
+// Caching of a route's anonymous GET responses. The upstream's own
+// `Cache-Control` (`max-age`, `s-maxage`, `stale-while-revalidate`) wins.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CacheConfig {
+    // How long a response stays fresh
+    pub ttl_secs: u64,
+    // How long after that a stale copy is served while it is refreshed
+    #[serde(default)]
+    pub stale_while_revalidate_secs: u64,
+}
+
+// Gateway-wide rate limiting. `backend` is read once at startup; `default`
+// is reloadable and applies to services and routes without their own limit.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RateLimitSettings {
+    #[serde(default)]
+    pub backend: RateLimitBackend,
+    #[serde(default)]
+    pub default: Option<RateLimitConfig>,
+    // SHA-256 digests (hex) of the API keys issued to clients. `api_key`
+    // limits count only these keys on their own; other requests by client IP.
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+}
+
+// Where counters live. Replicas only share limits with a shared store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitBackend {
+    // Per-process sharded map
+    #[default]
+    Memory,
+}
+
+// Allow `limit` requests per `window_secs` for each distinct `key`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub algorithm: RateLimitAlgorithm,
+    #[serde(default)]
+    pub key: RateLimitKey,
+    pub limit: u32,
+    pub window_secs: u64,
+    // Token bucket size, i.e. the largest burst; defaults to `limit`
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    // Refills continuously at limit/window, allowing bursts up to `burst`
+    #[default]
+    TokenBucket,
+    // Weighted count over the current and previous window
+    SlidingWindow,
+}
+
+// What a bucket is counted per. `user` and `api_key` fall back to the client
+// IP for guests and requests without a key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    // JWT `sub`
+    User,
+    // `X-API-Key` header, if it is one of `[rate_limit] api_keys`
+    ApiKey,
+    // One bucket shared by every caller of the route
+    Route,
+}
+
+// A backend service reachable through the gateway
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServiceConfig {
+    pub name: String,
+    pub prefix: String,
+    // More path prefixes routed to the service, e.g. a new API version
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub upstreams: Vec<UpstreamConfig>,
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    #[serde(default)]
+    pub load_balancer: LoadBalancerConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub tls: Option<UpstreamTlsConfig>,
+    // Speak HTTP/2 to the upstreams: cleartext (h2c) for `http://`, ALPN for `https://`
+    #[serde(default)]
+    pub http2: bool,
+    // How requests are forwarded, unless a route says otherwise
+    #[serde(default)]
+    pub protocol: Protocol,
+    // Who may call the service; `authenticated` unless set
+    #[serde(default)]
+    pub auth: Option<AuthPolicy>,
+    #[serde(default)]
+    pub body: Option<BodyConfig>,
+    // Per-route overrides, matched in order against method and path
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    // Path rewrites towards the upstreams; the first matching rule applies
+    #[serde(default)]
+    pub rewrite: Vec<RewriteConfig>,
+}
+
+// Maps a client path to the upstream's: `strip_prefix` is replaced by
+// `add_prefix`, then the first match of `regex` in the result is replaced by
+// `replace` (`$1`, `$name`). A rule applies when the path starts with
+// `strip_prefix` and the result matches `regex`, whichever are set.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RewriteConfig {
+    #[serde(default)]
+    pub strip_prefix: Option<String>,
+    #[serde(default)]
+    pub add_prefix: Option<String>,
+    #[serde(default)]
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub replace: Option<String>,
+}
+
+// Upstream timeouts in milliseconds. At service level unset values fall back to
+// the defaults below; at route level they fall back to the service's values.
+// `connect_ms` only applies at service level since it belongs to the connection pool.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TimeoutConfig {
+    pub connect_ms: Option<u64>,
+    // Wait for response headers, per attempt
+    pub read_ms: Option<u64>,
+    // Whole request including retries and the response body; becomes the deadline
+    pub total_ms: Option<u64>,
+    // Longest silence on a WebSocket or event stream, which `total_ms` doesn't bound
+    pub idle_ms: Option<u64>,
+}
+
+pub const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 2_000;
+pub const DEFAULT_READ_TIMEOUT_MS: u64 = 15_000;
+pub const DEFAULT_TOTAL_TIMEOUT_MS: u64 = 30_000;
+pub const DEFAULT_IDLE_TIMEOUT_MS: u64 = 60_000;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteConfig {
+    // actix-style pattern, e.g. `/api/v1/posts/{id}` or `/api/v1/storage/{tail}*`
+    pub path: String,
+    // Empty matches every method
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub auth: Option<AuthPolicy>,
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    #[serde(default)]
+    pub body: Option<BodyConfig>,
+    #[serde(default)]
+    pub protocol: Option<Protocol>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    // Plain HTTP requests, relayed as they are
+    #[default]
+    Http,
+    // gRPC-Web from clients, translated to gRPC over HTTP/2 towards the upstream
+    Grpc,
+}
+
+// A composite GET endpoint: `parts` are fetched in parallel through their
+// services and their JSON bodies merged into one document under their names
+#[derive(Debug, Clone, Deserialize)]
+pub struct AggregateConfig {
+    // actix-style pattern such as `/api/v1/profiles/{user_id}`
+    pub path: String,
+    // Who may call it; `authenticated` unless set. Each part's own policy still applies.
+    #[serde(default)]
+    pub auth: Option<AuthPolicy>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    // Budget for all parts together; each part is also bounded by its route's timeouts
+    #[serde(default)]
+    pub total_ms: Option<u64>,
+    pub parts: Vec<AggregatePartConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AggregatePartConfig {
+    // Key of the part's body in the merged document
+    pub name: String,
+    // Gateway path, optionally with a query. `{param}` takes a segment of the
+    // aggregate's path, `{sub}` the caller's user id.
+    pub path: String,
+    // Fail the whole response with 502 instead of reporting the part's error
+    #[serde(default)]
+    pub required: bool,
+}
+
+// Placeholder in part paths for the caller's user id (the JWT `sub`)
+pub const SUBJECT_PLACEHOLDER: &str = "sub";
+
+// Checks on request bodies at the edge. A route's rules replace its service's.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BodyConfig {
+    // Larger bodies are answered 413
+    #[serde(default)]
+    pub max_bytes: Option<usize>,
+    // Accepted media types such as `application/json` or `image/*`; empty accepts any
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    // JSON Schema file the body must match; such bodies are buffered to be checked
+    #[serde(default)]
+    pub schema: Option<PathBuf>,
+}
+
+// Access rule for a service or route: `"public"`, `"authenticated"`, or a table
+// of requirements such as `{ roles = ["admin"], scopes = ["posts:write"] }`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum AuthPolicy {
+    Level(AuthLevel),
+    // Authenticated, with one of `roles` (if any) and every one of `scopes`
+    Require {
+        #[serde(default)]
+        roles: Vec<String>,
+        #[serde(default)]
+        scopes: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthLevel {
+    // Anyone; a valid token is still passed on to the service
+    Public,
+    // Any valid token
+    Authenticated,
+}
+
+impl Default for AuthPolicy {
+    fn default() -> Self {
+        AuthPolicy::Level(AuthLevel::Authenticated)
+    }
+}
+
+// Retries of idempotent requests on another upstream. Only connect errors and
+// the statuses in `retry_on` are retried; POST/PATCH need an `Idempotency-Key`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    // Extra attempts after the first one (0 disables retries)
+    pub max_retries: u32,
+    pub retry_on: Vec<u16>,
+    pub base_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // Retries allowed per 10s window: `budget_min_retries` + `budget_ratio` x requests
+    pub budget_ratio: f64,
+    pub budget_min_retries: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 2,
+            retry_on: vec![502, 503, 504],
+            base_backoff_ms: 25,
+            max_backoff_ms: 250,
+            budget_ratio: 0.2,
+            budget_min_retries: 10,
+        }
+    }
+}
+
+// Per-upstream circuit breaker settings, shared by all upstreams of a service
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    // Open after this many failures in a row
+    pub consecutive_failures: u32,
+    // ...or when this fraction of requests in the window failed
+    pub failure_rate: f64,
+    // Minimum requests in the window before `failure_rate` applies
+    pub min_requests: u32,
+    pub window_secs: u64,
+    // How long to fail fast before letting trial requests through
+    pub open_secs: u64,
+    // Trial requests allowed while half-open; all must succeed to close
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            enabled: true,
+            consecutive_failures: 5,
+            failure_rate: 0.5,
+            min_requests: 20,
+            window_secs: 30,
+            open_secs: 10,
+            half_open_requests: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LoadBalancerConfig {
+    #[serde(default)]
+    pub strategy: Strategy,
+    // Key for `consistent_hash`: `header:<name>` or `path:<pattern>` (e.g.
+    // `path:/api/v1/posts/post-by-permalink/{permalink}`). Falls back to the request path.
+    #[serde(default)]
+    pub hash_on: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    RoundRobin,
+    // With equal weights this is plain round-robin
+    #[default]
+    WeightedRoundRobin,
+    LeastOutstanding,
+    RandomTwoChoices,
+    ConsistentHash,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamConfig {
+    pub url: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+// Active health probing of a service's upstreams. Any response below 500 counts
+// as a pass, so a service without a dedicated health route can still be probed.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_health_path")]
+    pub path: String,
+    #[serde(default = "default_health_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_timeout_ms")]
+    pub timeout_ms: u64,
+    // Consecutive passes needed to bring a down upstream back into rotation
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    // Consecutive failures needed to eject an upstream from rotation
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_bind() -> String {
+    "0.0.0.0:8000".into()
+}
+
+fn default_drain_delay_secs() -> u64 {
+    5
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_admin_bind() -> String {
+    "127.0.0.1:9901".into()
+}
+
+fn default_jwt_algorithms() -> Vec<Algorithm> {
+    vec![
+        Algorithm::HS256,
+        Algorithm::RS256,
+        Algorithm::ES256,
+        Algorithm::EdDSA,
+    ]
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    60
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    300
+}
+
+fn default_jwks_min_refresh_secs() -> u64 {
+    30
+}
+
+fn default_watch_interval_secs() -> u64 {
+    5
+}
+
+fn default_cert_watch_interval_secs() -> u64 {
+    10
+}
+
+fn default_health_path() -> String {
+    "/health".into()
+}
+
+fn default_health_interval_secs() -> u64 {
+    10
+}
+
+fn default_health_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+fn default_log_level() -> String {
+    "info".into()
+}
+
+fn default_redact_headers() -> Vec<String> {
+    [
+        "authorization",
+        "proxy-authorization",
+        "cookie",
+        "set-cookie",
+        "x-api-key",
+        "x-internal-assertion",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_redact_params() -> Vec<String> {
+    [
+        "password",
+        "token",
+        "access_token",
+        "refresh_token",
+        "api_key",
+        "secret",
+        "code",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_cache_max_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_cache_max_entry_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "cannot read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "cannot parse {}: {}", path.display(), err),
+            ConfigError::Invalid(msg) => write!(f, "invalid gateway config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for std::io::Error {
+    fn from(err: ConfigError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+    }
+}
+
+impl GatewayConfig {
+    pub fn path() -> PathBuf {
+        env::var("GATEWAY_CONFIG")
+            .unwrap_or_else(|_| DEFAULT_CONFIG_PATH.into())
+            .into()
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let raw = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let config: GatewayConfig =
+            toml::from_str(&raw).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut names = HashSet::new();
+
+        if let Some(tls) = &self.server.tls {
+            if tls.require_client_cert && tls.client_ca.is_none() {
+                return Err(ConfigError::Invalid(
+                    "server.tls.require_client_cert needs a client_ca".into(),
+                ));
+            }
+        }
+        if self.admin.bind == self.server.bind {
+            return Err(ConfigError::Invalid(
+                "admin.bind must differ from server.bind".into(),
+            ));
+        }
+        if self.auth.algorithms.is_empty() {
+            return Err(ConfigError::Invalid(
+                "auth.algorithms must not be empty".into(),
+            ));
+        }
+        if let Some(jwks) = &self.auth.jwks {
+            if jwks.file.is_some() == jwks.url.is_some() {
+                return Err(ConfigError::Invalid(
+                    "auth.jwks needs exactly one of file or url".into(),
+                ));
+            }
+            if jwks.refresh_secs == 0 {
+                return Err(ConfigError::Invalid(
+                    "auth.jwks.refresh_secs must be greater than 0".into(),
+                ));
+            }
+        }
+        if let Some(limit) = &self.rate_limit.default {
+            limit.validate("rate_limit.default")?;
+        }
+        if let Err(err) = self.logging.level.parse::<Targets>() {
+            return Err(ConfigError::Invalid(format!(
+                "logging.level `{}`: {}",
+                self.logging.level, err
+            )));
+        }
+        if self.cache.max_entry_bytes == 0 || self.cache.max_entry_bytes > self.cache.max_bytes {
+            return Err(ConfigError::Invalid(
+                "cache.max_entry_bytes must be non-zero and at most cache.max_bytes".into(),
+            ));
+        }
+
+        for service in &self.services {
+            if !names.insert(service.name.as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "duplicate service name `{}`",
+                    service.name
+                )));
+            }
+            if !service.prefix.starts_with('/') {
+                return Err(ConfigError::Invalid(format!(
+                    "prefix of service `{}` must start with `/`",
+                    service.name
+                )));
+            }
+            if let Some(alias) = service.aliases.iter().find(|a| !a.starts_with('/')) {
+                return Err(ConfigError::Invalid(format!(
+                    "alias `{}` of service `{}` must start with `/`",
+                    alias, service.name
+                )));
+            }
+            for rule in &service.rewrite {
+                rule.validate(&service.name)?;
+            }
+            if service.upstreams.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "service `{}` has no upstreams",
+                    service.name
+                )));
+            }
+            if let Some(upstream) = service.upstreams.iter().find(|u| u.weight == 0) {
+                return Err(ConfigError::Invalid(format!(
+                    "upstream `{}` of service `{}` has weight 0",
+                    upstream.url, service.name
+                )));
+            }
+            let balancer = &service.load_balancer;
+            match &balancer.hash_on {
+                Some(spec) if !spec.starts_with("header:") && !spec.starts_with("path:/") => {
+                    return Err(ConfigError::Invalid(format!(
+                        "hash_on of service `{}` must be `header:<name>` or `path:<pattern>`",
+                        service.name
+                    )));
+                }
+                Some(spec) if spec.starts_with("path:") => {
+                    if let Err(err) = check_path_pattern(&spec["path:".len()..]) {
+                        return Err(ConfigError::Invalid(format!(
+                            "hash_on of service `{}`: {}",
+                            service.name, err
+                        )));
+                    }
+                }
+                None if balancer.strategy == Strategy::ConsistentHash => {
+                    return Err(ConfigError::Invalid(format!(
+                        "service `{}` uses consistent_hash but sets no hash_on",
+                        service.name
+                    )));
+                }
+                _ => {}
+            }
+            let breaker = &service.circuit_breaker;
+            if breaker.consecutive_failures == 0
+                || breaker.half_open_requests == 0
+                || breaker.window_secs == 0
+                || !(0.0..=1.0).contains(&breaker.failure_rate)
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "circuit_breaker of service `{}` needs non-zero thresholds and a failure_rate in 0..=1",
+                    service.name
+                )));
+            }
+            if service.retry.budget_ratio < 0.0 {
+                return Err(ConfigError::Invalid(format!(
+                    "retry.budget_ratio of service `{}` must not be negative",
+                    service.name
+                )));
+            }
+            if let Some(limit) = &service.rate_limit {
+                limit.validate(&format!("rate_limit of service `{}`", service.name))?;
+            }
+            if service.body.as_ref().is_some_and(|b| b.max_bytes == Some(0)) {
+                return Err(ConfigError::Invalid(format!(
+                    "body.max_bytes of service `{}` must be non-zero",
+                    service.name
+                )));
+            }
+            if let Some(tls) = &service.tls {
+                if tls.cert.is_some() != tls.key.is_some() {
+                    return Err(ConfigError::Invalid(format!(
+                        "tls of service `{}` needs both cert and key, or neither",
+                        service.name
+                    )));
+                }
+            }
+            if service.timeouts.connect_ms == Some(0)
+                || service.timeouts.read_ms == Some(0)
+                || service.timeouts.total_ms == Some(0)
+                || service.timeouts.idle_ms == Some(0)
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "timeouts of service `{}` must be non-zero",
+                    service.name
+                )));
+            }
+            for route in &service.routes {
+                if !route.path.starts_with('/') {
+                    return Err(ConfigError::Invalid(format!(
+                        "route `{}` of service `{}` must start with `/`",
+                        route.path, service.name
+                    )));
+                }
+                if let Some(method) = route
+                    .methods
+                    .iter()
+                    .find(|m| Method::from_bytes(m.as_bytes()).is_err())
+                {
+                    return Err(ConfigError::Invalid(format!(
+                        "route `{}` of service `{}` has invalid method `{}`",
+                        route.path, service.name, method
+                    )));
+                }
+                if route.timeouts.connect_ms.is_some() {
+                    return Err(ConfigError::Invalid(format!(
+                        "route `{}` of service `{}`: connect_ms can only be set per service",
+                        route.path, service.name
+                    )));
+                }
+                if route.timeouts.read_ms == Some(0)
+                    || route.timeouts.total_ms == Some(0)
+                    || route.timeouts.idle_ms == Some(0)
+                {
+                    return Err(ConfigError::Invalid(format!(
+                        "timeouts of route `{}` must be non-zero",
+                        route.path
+                    )));
+                }
+                if let Some(limit) = &route.rate_limit {
+                    limit.validate(&format!("rate_limit of route `{}`", route.path))?;
+                }
+                if route.body.as_ref().is_some_and(|b| b.max_bytes == Some(0)) {
+                    return Err(ConfigError::Invalid(format!(
+                        "body.max_bytes of route `{}` must be non-zero",
+                        route.path
+                    )));
+                }
+                if route.cache.is_some()
+                    && !route.methods.is_empty()
+                    && !route.methods.iter().any(|m| m.eq_ignore_ascii_case("GET"))
+                {
+                    return Err(ConfigError::Invalid(format!(
+                        "route `{}` of service `{}` sets cache but doesn't match GET",
+                        route.path, service.name
+                    )));
+                }
+                let protocol = route.protocol.unwrap_or(service.protocol);
+                if route.cache.is_some() && protocol == Protocol::Grpc {
+                    return Err(ConfigError::Invalid(format!(
+                        "route `{}` of service `{}`: gRPC calls can't be cached",
+                        route.path, service.name
+                    )));
+                }
+            }
+            if let Some(check) = &service.health_check {
+                if check.interval_secs == 0
+                    || check.healthy_threshold == 0
+                    || check.unhealthy_threshold == 0
+                {
+                    return Err(ConfigError::Invalid(format!(
+                        "health_check of service `{}` needs a non-zero interval and thresholds",
+                        service.name
+                    )));
+                }
+            }
+        }
+
+        for aggregate in &self.aggregates {
+            aggregate.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AggregateConfig {
+    // Whether parts match a service is checked when the registry is built
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| {
+            Err(ConfigError::Invalid(format!(
+                "aggregate `{}`: {}",
+                self.path, msg
+            )))
+        };
+        if !self.path.starts_with('/') {
+            return invalid("path must start with `/`".into());
+        }
+        if self.parts.is_empty() {
+            return invalid("needs at least one part".into());
+        }
+        if self.total_ms == Some(0) {
+            return invalid("total_ms must be non-zero".into());
+        }
+        if let Some(limit) = &self.rate_limit {
+            limit.validate(&format!("rate_limit of aggregate `{}`", self.path))?;
+        }
+        let params = placeholders(&self.path);
+        if params.contains(&SUBJECT_PLACEHOLDER) {
+            return invalid(format!(
+                "`{{{}}}` is reserved for the caller",
+                SUBJECT_PLACEHOLDER
+            ));
+        }
+
+        let mut names = HashSet::new();
+        for part in &self.parts {
+            // `errors` holds the failures of the other parts
+            if part.name.is_empty() || part.name == "errors" || !names.insert(part.name.as_str()) {
+                return invalid(format!(
+                    "part name `{}` is empty, reserved or repeated",
+                    part.name
+                ));
+            }
+            if !part.path.starts_with('/') {
+                return invalid(format!("path of part `{}` must start with `/`", part.name));
+            }
+            if let Some(param) = placeholders(&part.path)
+                .into_iter()
+                .find(|p| *p != SUBJECT_PLACEHOLDER && !params.contains(p))
+            {
+                return invalid(format!(
+                    "part `{}` uses `{{{}}}`, which the path doesn't capture",
+                    part.name, param
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Names of the `{name}` and `{name:regex}` segments of a pattern
+pub fn placeholders(pattern: &str) -> Vec<&str> {
+    pattern
+        .split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split(['}', ':']).next())
+        .collect()
+}
+
+// Dynamic segments actix-router accepts in one pattern
+const MAX_PATTERN_SEGMENTS: usize = 16;
+
+// Checks a path pattern the way actix-router parses it, since `ResourceDef::new`
+// panics on an unclosed `{`, a regex on a tail segment, too many segments or
+// a segment regex that doesn't compile
+pub fn check_path_pattern(pattern: &str) -> Result<(), String> {
+    let mut re = String::from("^");
+    let mut rest = pattern;
+    let mut segments = 0;
+    while let Some(start) = rest.find('{') {
+        re.push_str(&regex::escape(&rest[..start]));
+        let mut depth = 0;
+        let end = rest[start..]
+            .char_indices()
+            .find(|&(_, c)| {
+                match c {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    _ => {}
+                }
+                depth == 0
+            })
+            .map(|(i, _)| start + i)
+            .ok_or_else(|| "unclosed `{`".to_string())?;
+        let param = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        let tail = rest == "*";
+        let (name, segment) = match param.split_once(':') {
+            Some(_) if tail => return Err("tail segments can't have a regex".into()),
+            Some((name, segment)) => (name, segment),
+            None if tail => {
+                rest = "";
+                (param, ".*")
+            }
+            None => (param, "[^/]+"),
+        };
+        re.push_str(&format!("(?P<{}>{})", name, segment));
+        segments += 1;
+    }
+    re.push_str(&regex::escape(rest));
+
+    if segments > MAX_PATTERN_SEGMENTS {
+        return Err(format!(
+            "at most {} dynamic segments are allowed",
+            MAX_PATTERN_SEGMENTS
+        ));
+    }
+    regex::Regex::new(&re)
+        .map(drop)
+        .map_err(|err| err.to_string())
+}
+
+impl RewriteConfig {
+    // The regex itself is compiled, and checked, when the registry is built
+    fn validate(&self, service: &str) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| {
+            Err(ConfigError::Invalid(format!(
+                "rewrite of service `{}`: {}",
+                service, msg
+            )))
+        };
+        if self.strip_prefix.is_none() && self.add_prefix.is_none() && self.regex.is_none() {
+            return invalid("needs strip_prefix, add_prefix or regex");
+        }
+        if self.regex.is_some() != self.replace.is_some() {
+            return invalid("regex and replace go together");
+        }
+        let prefixes = [&self.strip_prefix, &self.add_prefix];
+        if prefixes.iter().any(|p| p.as_ref().is_some_and(|p| !p.starts_with('/'))) {
+            return invalid("prefixes must start with `/`");
+        }
+        Ok(())
+    }
+}
+
+impl RateLimitConfig {
+    fn validate(&self, what: &str) -> Result<(), ConfigError> {
+        if self.limit == 0 || self.window_secs == 0 || self.burst == Some(0) {
+            return Err(ConfigError::Invalid(format!(
+                "{} needs a non-zero limit, window_secs and burst",
+                what
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_hash_on_path_patterns() {
+        let validate = |hash_on: &str| {
+            toml::from_str::<GatewayConfig>(&format!(
+                r#"
+                [[services]]
+                name = "post"
+                prefix = "/api/v1/posts"
+                upstreams = [{{ url = "http://post:8082" }}]
+                load_balancer = {{ strategy = "consistent_hash", hash_on = '{}' }}
+                "#,
+                hash_on
+            ))
+            .unwrap()
+            .validate()
+        };
+        assert!(validate("path:/api/v1/posts/{id}").is_ok());
+        assert!(validate("header:x-tenant").is_ok());
+        for hash_on in [
+            "path:/api/v1/posts/{id",
+            "path:/api/v1/posts/{id:[}",
+            "cookie:id",
+        ] {
+            assert!(
+                matches!(validate(hash_on), Err(ConfigError::Invalid(_))),
+                "{}",
+                hash_on
+            );
+        }
+    }
+}
@@ -1,49 +1,188 @@
+mod admin;
 mod auth;
+mod config;
 mod health;
+mod metrics;
 mod middleware;
 mod routing;
+mod telemetry;
 mod utils;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use actix_cors::Cors;
-use actix_web::{http, middleware::Logger, web, App, HttpResponse, HttpServer};
+use actix_web::{http, web, App, HttpServer};
+use auth::{jwks, InternalSigner, JwtVerifier};
+use config::GatewayConfig;
 use dotenv::dotenv;
+use futures::future;
 use health::health_check;
-use middleware::jwt::JwtMiddleware;
-use routing::{gateway::forward_request, ServiceState};
+use metrics::Metrics;
+use middleware::{
+    access_log::{AccessLog, Redactor},
+    jwt::JwtMiddleware,
+    metrics::RequestMetrics,
+    rate_limit::{self, store::RateLimitStore, RateLimiter, API_KEY_HEADER},
+    tls,
+    trace::{RequestTracing, REQUEST_ID_HEADER, TRACEPARENT_HEADER},
+};
+use routing::{
+    cache::ResponseCache, deadline::DEADLINE_HEADER, gateway::forward_request, grpc,
+    health::spawn_health_checker, reload, retry::IDEMPOTENCY_KEY, ServiceState,
+};
+use tracing::info;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    tracing_subscriber::fmt::init();
-    let state = Arc::new(ServiceState::new());
 
-    HttpServer::new(move || {
+    let config_path = GatewayConfig::path();
+    let config = GatewayConfig::load(&config_path)?;
+    let _telemetry = telemetry::init(&config.logging);
+    let state = Arc::new(ServiceState::new(config_path, &config)?);
+    reload::spawn_reloaders(state.clone(), &config.reload);
+    spawn_health_checker(state.clone());
+    let rate_limit_store: Arc<dyn RateLimitStore> = rate_limit::store::from_config(&config.rate_limit).into();
+    let jwks = match &config.auth.jwks {
+        Some(jwks_config) => {
+            let keys = Arc::new(jwks::KeySet::new(jwks_config));
+            jwks::load(&keys).await;
+            jwks::spawn_refresher(keys.clone(), jwks_config.refresh_secs);
+            Some(keys)
+        }
+        None => None,
+    };
+    let verifier = Arc::new(JwtVerifier::new(
+        &config.auth,
+        std::env::var("JWT_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .as_deref(),
+        jwks,
+    ));
+    let metrics = web::Data::new(Metrics::new());
+    let cache = web::Data::new(ResponseCache::new(&config.cache));
+    let redactor = Arc::new(Redactor::new(&config.logging));
+    let signer = web::Data::new(InternalSigner::new(
+        &std::env::var("INTERNAL_SECRET_KEY")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .expect("INTERNAL_SECRET_KEY missing"),
+    ));
+    let readiness = shutdown::Readiness::default();
+    let trusted_hops = config.server.trusted_proxy_hops;
+
+    // Operators only: a separate listener that can stay off the public network
+    let admin_server = {
+        let (state, cache, metrics) = (state.clone(), cache.clone(), metrics.clone());
+        let store = web::Data::new(rate_limit_store.clone());
+        let (verifier, redactor) = (verifier.clone(), redactor.clone());
+        HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .app_data(store.clone())
+                .app_data(cache.clone())
+                .app_data(metrics.clone())
+                // For scrapers, which can reach this listener but carry no token
+                .route("/metrics", web::get().to(metrics::metrics))
+                .service(
+                    web::scope("/admin")
+                        .route("/routes", web::get().to(admin::routes))
+                        .route("/upstreams", web::get().to(admin::upstreams))
+                        .route("/upstreams/drain", web::post().to(admin::drain_upstream))
+                        .route("/breakers", web::get().to(admin::breakers))
+                        .route("/rate-limits", web::get().to(admin::rate_limits))
+                        .route("/reload", web::post().to(admin::reload_config))
+                        .route("/cache/purge", web::post().to(admin::purge_cache))
+                        .wrap(JwtMiddleware {
+                            verifier: verifier.clone(),
+                            state: state.clone(),
+                            policy: Some(admin::policy()),
+                        }),
+                )
+                .wrap(AccessLog {
+                    redactor: redactor.clone(),
+                    state: state.clone(),
+                    trusted_hops,
+                })
+                .wrap(RequestTracing)
+        })
+        .workers(1)
+        .shutdown_timeout(config.server.shutdown_timeout_secs)
+        .disable_signals()
+        .bind(&config.admin.bind)?
+    };
+
+    let ready = web::Data::new(readiness.clone());
+    let server = HttpServer::new(move || {
         let cors = Cors::permissive()
             .allow_any_origin()
-            .allowed_methods(vec!["GET", "POST", "PATCH", "DELETE"])
+            .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
             .allowed_headers(vec![
                 http::header::AUTHORIZATION,
                 http::header::ACCEPT,
-                http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
                 http::header::CONTENT_TYPE,
+                REQUEST_ID_HEADER,
+                TRACEPARENT_HEADER,
             ])
+            // Headers the gateway itself acts on: retries, rate limit keys, deadlines
+            .allowed_headers([IDEMPOTENCY_KEY, API_KEY_HEADER, DEADLINE_HEADER])
+            .allowed_headers(grpc::CLIENT_HEADERS)
             .supports_credentials()
             .max_age(3600);
 
         App::new()
-            .wrap(Logger::default())
             .app_data(web::Data::new(state.clone()))
+            .app_data(signer.clone())
+            .app_data(metrics.clone())
+            .app_data(cache.clone())
+            .app_data(ready.clone())
             .route("/health", web::get().to(health_check))
+            .route("/ready", web::get().to(shutdown::ready))
+            // Registered before the JWT middleware so it runs after it and sees the claims
+            .wrap(RateLimiter {
+                state: state.clone(),
+                store: rate_limit_store.clone(),
+                trusted_hops,
+            })
             .wrap(JwtMiddleware {
-                secret: std::env::var("JWT_SECRET").expect("JWT_SECRET missing"),
+                verifier: verifier.clone(),
+                state: state.clone(),
+                policy: None,
             })
-            .route("/api/v1/{tail:.*}", web::route().to(forward_request))
-            .default_service(web::route().to(|| HttpResponse::NotFound()))
+            // Everything else is matched against the service registry
+            .default_service(web::route().to(forward_request))
             .wrap(cors)
+            .wrap(AccessLog {
+                redactor: redactor.clone(),
+                state: state.clone(),
+                trusted_hops,
+            })
+            .wrap(RequestMetrics {
+                metrics: metrics.clone().into_inner(),
+                state: state.clone(),
+            })
+            .wrap(RequestTracing)
     })
-    .bind("0.0.0.0:8000")?
-    .run()
-    .await
+    .shutdown_timeout(config.server.shutdown_timeout_secs)
+    .disable_signals();
+
+    let server = match &config.server.tls {
+        Some(tls_config) => {
+            let (rustls_config, resolver) = tls::server_config(tls_config)?;
+            tls::spawn_cert_watcher(resolver, tls_config.watch_interval_secs);
+            server.bind_rustls_021(&config.server.bind, rustls_config)?
+        }
+        None => server.bind(&config.server.bind)?,
+    };
+
+    let (server, admin_server) = (server.run(), admin_server.run());
+    shutdown::spawn_signal_handlers(
+        vec![server.handle(), admin_server.handle()],
+        readiness,
+        Duration::from_secs(config.server.drain_delay_secs),
+    );
+    future::try_join(server, admin_server).await?;
+    info!("gateway stopped");
+    Ok(())
 }
@@ -0,0 +1,262 @@
+use actix_web::{
+    http::{Method, StatusCode},
+    web, HttpResponse,
+};
+use prometheus::{
+    Encoder as _, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::{sync::Arc, time::Duration};
+use tracing::error;
+
+use crate::{
+    middleware::access_log::redact_url,
+    routing::{circuit_breaker::BreakerState, gateway::UpstreamError, ServiceState},
+};
+
+// `service` label of requests outside every configured service
+pub const NO_SERVICE: &str = "none";
+
+// Gateway traffic, upstream outcomes, and breaker/health state in the
+// Prometheus text format
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    in_flight: IntGaugeVec,
+    upstream_requests: IntCounterVec,
+    upstream_errors: IntCounterVec,
+    upstream_duration: HistogramVec,
+    // Snapshots of the registry, refreshed on every scrape
+    upstream_in_flight: IntGaugeVec,
+    upstream_healthy: IntGaugeVec,
+    breaker_state: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("gateway".into()), None).expect("valid metrics namespace");
+
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Client requests handled"),
+            &["service", "method", "status_class"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "request_duration_seconds",
+                "Time until the response head was ready",
+            ),
+            &["service", "method", "status_class"],
+        )
+        .unwrap();
+        let in_flight = IntGaugeVec::new(
+            Opts::new("requests_in_flight", "Client requests being handled"),
+            &["service"],
+        )
+        .unwrap();
+        let upstream_requests = IntCounterVec::new(
+            Opts::new(
+                "upstream_requests_total",
+                "Attempts against upstreams, retries included",
+            ),
+            &["service", "backend", "method", "status_class"],
+        )
+        .unwrap();
+        let upstream_errors = IntCounterVec::new(
+            Opts::new(
+                "upstream_errors_total",
+                "Upstream attempts that failed with a 5xx, a timeout or a transport error",
+            ),
+            &["service", "backend", "method", "status_class"],
+        )
+        .unwrap();
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "Time until an upstream answered or the attempt failed",
+            ),
+            &["service", "backend"],
+        )
+        .unwrap();
+        let upstream_in_flight = IntGaugeVec::new(
+            Opts::new("upstream_in_flight", "Requests in flight to an upstream"),
+            &["service", "backend"],
+        )
+        .unwrap();
+        let upstream_healthy = IntGaugeVec::new(
+            Opts::new(
+                "upstream_healthy",
+                "1 while the health checker considers the upstream healthy",
+            ),
+            &["service", "backend"],
+        )
+        .unwrap();
+        let breaker_state = IntGaugeVec::new(
+            Opts::new(
+                "circuit_breaker_state",
+                "1 for the state the upstream's circuit breaker is in",
+            ),
+            &["service", "backend", "state"],
+        )
+        .unwrap();
+
+        for collector in [
+            Box::new(requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(request_duration.clone()),
+            Box::new(in_flight.clone()),
+            Box::new(upstream_requests.clone()),
+            Box::new(upstream_errors.clone()),
+            Box::new(upstream_duration.clone()),
+            Box::new(upstream_in_flight.clone()),
+            Box::new(upstream_healthy.clone()),
+            Box::new(breaker_state.clone()),
+        ] {
+            registry.register(collector).unwrap();
+        }
+
+        Metrics {
+            registry,
+            requests,
+            request_duration,
+            in_flight,
+            upstream_requests,
+            upstream_errors,
+            upstream_duration,
+            upstream_in_flight,
+            upstream_healthy,
+            breaker_state,
+        }
+    }
+
+    // Counts the request as in flight until the guard is dropped
+    pub fn start_request(&self, service: &str) -> InFlightGuard {
+        let gauge = self.in_flight.with_label_values(&[service]);
+        gauge.inc();
+        InFlightGuard(gauge)
+    }
+
+    pub fn record_request(
+        &self,
+        service: &str,
+        method: &Method,
+        status: StatusCode,
+        elapsed: Duration,
+    ) {
+        let labels = [service, method_label(method), status_class(status)];
+        self.requests.with_label_values(&labels).inc();
+        self.request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_upstream(
+        &self,
+        service: &str,
+        backend: &str,
+        method: &Method,
+        outcome: &Result<reqwest::Response, UpstreamError>,
+        elapsed: Duration,
+    ) {
+        let (class, failed) = match outcome {
+            Ok(resp) => (status_class(resp.status()), resp.status().is_server_error()),
+            Err(UpstreamError::Timeout) => ("timeout", true),
+            Err(UpstreamError::Request(err)) if err.is_timeout() => ("timeout", true),
+            Err(UpstreamError::Request(_)) => ("error", true),
+        };
+        let backend = redact_url(backend);
+        let labels = [service, backend.as_str(), method_label(method), class];
+        self.upstream_requests.with_label_values(&labels).inc();
+        if failed {
+            self.upstream_errors.with_label_values(&labels).inc();
+        }
+        self.upstream_duration
+            .with_label_values(&[service, backend.as_str()])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    // Re-read per-upstream state. Reset first so upstreams removed by a
+    // config reload stop being reported.
+    fn refresh(&self, state: &ServiceState) {
+        self.upstream_in_flight.reset();
+        self.upstream_healthy.reset();
+        self.breaker_state.reset();
+
+        let registry = state.registry();
+        for service in registry.services() {
+            for upstream in &service.upstreams {
+                let backend = redact_url(&upstream.url);
+                let labels = [service.name.as_str(), backend.as_str()];
+                self.upstream_in_flight
+                    .with_label_values(&labels)
+                    .set(upstream.in_flight() as i64);
+                self.upstream_healthy
+                    .with_label_values(&labels)
+                    .set(upstream.health.is_healthy() as i64);
+
+                let current = upstream.breaker.snapshot(&service.circuit_breaker).state;
+                for (state, name) in [
+                    (BreakerState::Closed, "closed"),
+                    (BreakerState::Open, "open"),
+                    (BreakerState::HalfOpen, "half_open"),
+                ] {
+                    self.breaker_state
+                        .with_label_values(&[labels[0], labels[1], name])
+                        .set((state == current) as i64);
+                }
+            }
+        }
+    }
+}
+
+pub struct InFlightGuard(prometheus::IntGauge);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// Anything outside the standard methods would let clients mint new series
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        _ => "OTHER",
+    }
+}
+
+fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+// GET /metrics, on the admin listener. Upstream URLs in labels have any
+// password masked, as in the access log.
+pub async fn metrics(
+    state: web::Data<Arc<ServiceState>>,
+    metrics: web::Data<Metrics>,
+) -> HttpResponse {
+    metrics.refresh(&state);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(err) = encoder.encode(&metrics.registry.gather(), &mut body) {
+        error!(%err, "failed to encode metrics");
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body)
+}
@@ -1,27 +1,37 @@
 use actix_service::{Service, Transform};
 use actix_web::{
+    body::{BoxBody, EitherBody},
     dev::{ServiceRequest, ServiceResponse},
     Error, HttpMessage as _,
 };
 use futures::future::{ok, LocalBoxFuture, Ready};
 use std::rc::Rc;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use crate::{
-    auth::{verify_jwt_from_header, Claims},
-    utils::public_service,
+    auth::{policy::authorize, verify_jwt_from_header, Claims, JwtVerifier},
+    config::AuthPolicy,
+    routing::ServiceState,
 };
 
+// Verifies the caller's token and enforces the access policy of the route it
+// targets: 401 without a valid token, 403 when role or scopes don't match.
+// Requests outside every service (health) only get their claims attached.
 pub struct JwtMiddleware {
-    pub secret: String,
+    pub verifier: Arc<JwtVerifier>,
+    pub state: Arc<ServiceState>,
+    // Enforced on every request instead of the routes' policies (the admin listener)
+    pub policy: Option<AuthPolicy>,
 }
 
 impl<S, B> Transform<S, ServiceRequest> for JwtMiddleware
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
+    B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
     type Error = Error;
     type Transform = AuthMiddlewareMiddleware<S>;
     type InitError = ();
@@ -30,22 +40,27 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ok(AuthMiddlewareMiddleware {
             service: Rc::new(service),
-            secret: self.secret.clone(),
+            verifier: self.verifier.clone(),
+            state: self.state.clone(),
+            policy: self.policy.clone(),
         })
     }
 }
 
 pub struct AuthMiddlewareMiddleware<S> {
     service: Rc<S>,
-    secret: String,
+    verifier: Arc<JwtVerifier>,
+    state: Arc<ServiceState>,
+    policy: Option<AuthPolicy>,
 }
 
 impl<S, B> Service<ServiceRequest> for AuthMiddlewareMiddleware<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
+    B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
@@ -55,34 +70,30 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let service = self.service.clone();
-        let secret = self.secret.clone();
-
-        // Don't verify for public endpoints
-        let path = req.path().to_string();
-        let public = public_service(&path);
-
-        println!("public {:?}", public);
+        let verifier = self.verifier.clone();
+        let policy = self.policy.clone().or_else(|| {
+            self.state
+                .registry()
+                .auth_policy_for(req.request())
+                .cloned()
+        });
 
         Box::pin(async move {
-            if !public {
-                match verify_jwt_from_header(&req, &secret) {
-                    Ok(claims) => {
-                        req.extensions_mut().insert::<Claims>(claims);
-                    }
-                    Err(_) => {
-                        // Instead of returning an error, insert "guest" claims
-                        let guest_claims = Claims {
-                            sub: "guest".into(),
-                            exp: 0, // Optionally set an expiration if needed
-                            role: "guest".into(),
-                            // add any other default fields required by your Claims struct
-                        };
-                        req.extensions_mut().insert::<Claims>(guest_claims);
-                    }
+            // Verified even on public routes so services can personalise the response
+            let allowed = match verify_jwt_from_header(&req, &verifier).await {
+                Ok(claims) => {
+                    let allowed = policy.map_or(Ok(()), |p| authorize(&p, Ok(&claims)));
+                    req.extensions_mut().insert::<Claims>(claims);
+                    allowed
+                }
+                Err(unauthenticated) => {
+                    policy.map_or(Ok(()), |p| authorize(&p, Err(unauthenticated)))
                 }
+            };
+            if let Err(denied) = allowed {
+                return Ok(req.into_response(denied).map_into_right_body());
             }
-            println!("work public access...");
-            service.call(req).await
+            Ok(service.call(req).await?.map_into_left_body())
         })
     }
 }
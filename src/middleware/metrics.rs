@@ -0,0 +1,86 @@
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    Error,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::{rc::Rc, sync::Arc, time::Instant};
+
+use crate::{
+    metrics::{Metrics, NO_SERVICE},
+    routing::ServiceState,
+};
+
+// Counts and times every client request under the service it was routed to.
+// Wrapped outside the auth and rate limiting middleware so their 401/403/429
+// answers are counted too.
+pub struct RequestMetrics {
+    pub metrics: Arc<Metrics>,
+    pub state: Arc<ServiceState>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware {
+            service: Rc::new(service),
+            metrics: self.metrics.clone(),
+            state: self.state.clone(),
+        })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+    metrics: Arc<Metrics>,
+    state: Arc<ServiceState>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let metrics = self.metrics.clone();
+        let service_name = self
+            .state
+            .registry()
+            .detect_service(req.path())
+            .map_or_else(|| NO_SERVICE.to_string(), |s| s.name.clone());
+        let method = req.method().clone();
+
+        Box::pin(async move {
+            let started = Instant::now();
+            let in_flight = metrics.start_request(&service_name);
+            let result = service.call(req).await;
+            drop(in_flight);
+
+            // Streamed bodies are still being sent; this times the response head
+            let status = match &result {
+                Ok(res) => res.status(),
+                Err(err) => err.as_response_error().status_code(),
+            };
+            metrics.record_request(&service_name, &method, status, started.elapsed());
+            result
+        })
+    }
+}
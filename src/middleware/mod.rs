@@ -1 +1,6 @@
-pub mod jwt;
\ No newline at end of file
+pub mod access_log;
+pub mod jwt;
+pub mod metrics;
+pub mod rate_limit;
+pub mod tls;
+pub mod trace;
@@ -0,0 +1,287 @@
+pub mod store;
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    body::{BoxBody, EitherBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderMap, HeaderName, HeaderValue},
+    Error, HttpMessage as _, HttpResponse,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use serde_json::json;
+use sha2::{Digest as _, Sha256};
+use std::{rc::Rc, sync::Arc, time::Duration};
+use tracing::warn;
+
+use crate::{
+    auth::Claims,
+    config::{RateLimitConfig, RateLimitKey},
+    routing::{headers::client_ip, registry::Registry, ServiceState},
+};
+
+use store::{Decision, RateLimitStore};
+
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+// Enforces the rate limits configured for routes, services and the gateway
+// default. Must run after `JwtMiddleware` so `user` keys can see the claims.
+pub struct RateLimiter {
+    pub state: Arc<ServiceState>,
+    pub store: Arc<dyn RateLimitStore>,
+    // `[server] trusted_proxy_hops`, to find the client IP behind load balancers
+    pub trusted_hops: usize,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+            state: self.state.clone(),
+            store: self.store.clone(),
+            trusted_hops: self.trusted_hops,
+        })
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    state: Arc<ServiceState>,
+    store: Arc<dyn RateLimitStore>,
+    trusted_hops: usize,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        let registry = self.state.registry();
+        let Some((scope, policy)) = registry.rate_limit_for(req.request()) else {
+            return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) });
+        };
+        let policy = policy.clone();
+        let bucket = bucket_key(&req, policy.key, &registry, self.trusted_hops);
+        let key = format!("{}|{}", scope, bucket);
+        let hit = self.store.hit(&key, &policy);
+
+        Box::pin(async move {
+            let decision = match hit.await {
+                Ok(decision) => decision,
+                Err(err) => {
+                    // Better to serve unthrottled than to fail every request
+                    warn!(%key, error = %err, "rate limit store unavailable, allowing request");
+                    return Ok(service.call(req).await?.map_into_left_body());
+                }
+            };
+
+            if !decision.allowed {
+                let mut res = HttpResponse::TooManyRequests();
+                if let Some(wait) = decision.retry_after {
+                    res.insert_header((header::RETRY_AFTER, ceil_secs(wait)));
+                }
+                let mut res = res.json(json!({ "error": "Too many requests" }));
+                insert_headers(res.headers_mut(), &decision, &policy);
+                return Ok(req.into_response(res).map_into_right_body());
+            }
+
+            let mut res = service.call(req).await?;
+            insert_headers(res.headers_mut(), &decision, &policy);
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+// Who the bucket belongs to within its scope
+fn bucket_key(
+    req: &ServiceRequest,
+    key: RateLimitKey,
+    registry: &Registry,
+    trusted_hops: usize,
+) -> String {
+    // Only connections without a socket address (never TCP) share `ip:unknown`
+    let ip = || match client_ip(req.request(), trusted_hops) {
+        Some(ip) => format!("ip:{}", ip),
+        None => "ip:unknown".to_string(),
+    };
+
+    match key {
+        RateLimitKey::Ip => ip(),
+        RateLimitKey::User => req
+            .extensions()
+            .get::<Claims>()
+            .map(|claims| format!("user:{}", claims.sub))
+            .unwrap_or_else(ip),
+        // Unknown keys count by IP, or rotating the header would get a fresh
+        // bucket every time. Buckets (and `/admin/rate-limits`) name the
+        // key's digest, never the key itself.
+        RateLimitKey::ApiKey => req
+            .headers()
+            .get(API_KEY_HEADER)
+            .map(|api_key| format!("{:x}", Sha256::digest(api_key.as_bytes())))
+            .filter(|digest| registry.knows_api_key(digest))
+            .map(|digest| format!("key:{}", digest))
+            .unwrap_or_else(ip),
+        RateLimitKey::Route => "route".to_string(),
+    }
+}
+
+// `RateLimit-*` fields from the IETF ratelimit-headers draft
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision, policy: &RateLimitConfig) {
+    headers.insert(RATELIMIT_LIMIT, HeaderValue::from(decision.limit));
+    headers.insert(RATELIMIT_REMAINING, HeaderValue::from(decision.remaining));
+    headers.insert(
+        RATELIMIT_RESET,
+        HeaderValue::from(ceil_secs(decision.reset)),
+    );
+    if let Ok(value) = HeaderValue::from_str(&format!("{};w={}", policy.limit, policy.window_secs))
+    {
+        headers.insert(RATELIMIT_POLICY, value);
+    }
+}
+
+// Round up so clients never come back too early
+fn ceil_secs(d: Duration) -> u64 {
+    d.as_secs() + u64::from(d.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GatewayConfig;
+    use actix_web::{
+        test::{self, TestRequest},
+        web, App,
+    };
+    use std::{fs, path::PathBuf};
+    use tempfile::NamedTempFile;
+
+    const KEY: &str = "issued-key";
+
+    fn registry() -> Registry {
+        let digest = format!("{:X}", Sha256::digest(KEY));
+        let toml = format!(
+            r#"
+            [rate_limit]
+            api_keys = ["{digest}"]
+            "#
+        );
+        Registry::from_toml(&toml, None).unwrap()
+    }
+
+    fn key_of(req: TestRequest, registry: &Registry) -> String {
+        let req = req
+            .peer_addr("203.0.113.9:4000".parse().unwrap())
+            .to_srv_request();
+        bucket_key(&req, RateLimitKey::ApiKey, registry, 0)
+    }
+
+    #[test]
+    fn api_key_buckets_are_kept_only_for_issued_keys() {
+        let registry = registry();
+
+        let issued = key_of(TestRequest::default().insert_header((API_KEY_HEADER, KEY)), &registry);
+        assert_eq!(issued, format!("key:{:x}", Sha256::digest(KEY)));
+        assert!(!issued.contains(KEY));
+
+        // Made-up or missing keys share the caller's IP bucket
+        for req in [
+            TestRequest::default().insert_header((API_KEY_HEADER, "made-up-1")),
+            TestRequest::default().insert_header((API_KEY_HEADER, "made-up-2")),
+            TestRequest::default(),
+        ] {
+            assert_eq!(key_of(req, &registry), "ip:203.0.113.9");
+        }
+    }
+
+    #[test]
+    fn api_keys_must_be_digests() {
+        let registry = Registry::from_toml(
+            r#"
+            [rate_limit]
+            api_keys = ["issued-key"]
+            "#,
+            None,
+        );
+        assert!(registry.is_err());
+    }
+
+    const TOKEN_BUCKET: &str = r#"
+        [[services]]
+        name = "post"
+        prefix = "/api/v1/posts"
+        upstreams = [{ url = "http://post:8082" }]
+        rate_limit = { algorithm = "token_bucket", key = "route", limit = 2, window_secs = 60 }
+    "#;
+
+    #[actix_web::test]
+    async fn reloads_start_buckets_over_under_the_new_policy() {
+        let file = NamedTempFile::new().unwrap();
+        fs::write(file.path(), TOKEN_BUCKET).unwrap();
+        let config = GatewayConfig::load(file.path()).unwrap();
+        let state = Arc::new(ServiceState::new(PathBuf::from(file.path()), &config).unwrap());
+        let store: Arc<dyn RateLimitStore> = Arc::new(store::MemoryStore::new());
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimiter {
+                    state: state.clone(),
+                    store: store.clone(),
+                    trusted_hops: 0,
+                })
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = || TestRequest::get().uri("/api/v1/posts/1").to_request();
+
+        for _ in 0..2 {
+            assert!(test::call_service(&app, get()).await.status().is_success());
+        }
+        assert_eq!(test::call_service(&app, get()).await.status(), 429);
+
+        // Same scope and key, so the same bucket, now counted as a sliding window
+        fs::write(
+            file.path(),
+            TOKEN_BUCKET
+                .replace("token_bucket", "sliding_window")
+                .replace("limit = 2", "limit = 3"),
+        )
+        .unwrap();
+        state.reload().unwrap();
+
+        for _ in 0..3 {
+            assert!(test::call_service(&app, get()).await.status().is_success());
+        }
+        assert_eq!(test::call_service(&app, get()).await.status(), 429);
+        let buckets = store.buckets("post|", 10).await.unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert!(matches!(
+            buckets[0].counters,
+            store::Counters::SlidingWindow { current: 3, .. }
+        ));
+    }
+}
@@ -0,0 +1,495 @@
+use futures::future::{ready, LocalBoxFuture};
+use serde::Serialize;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    io,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::{RateLimitAlgorithm, RateLimitBackend, RateLimitConfig, RateLimitSettings};
+
+const SHARDS: usize = 16;
+
+// How often a shard drops buckets nobody has touched for a while
+const SWEEP_INTERVAL_MS: u64 = 60_000;
+
+// Outcome of counting one request against a bucket
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // Until the bucket is back to its full quota
+    pub reset: Duration,
+    // Until the next request would be allowed; set when denied
+    pub retry_after: Option<Duration>,
+}
+
+// A bucket as last updated, for the admin API
+#[derive(Serialize)]
+pub struct BucketSnapshot {
+    pub key: String,
+    pub idle_secs: u64,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum Counters {
+    // Without the refill since the last request
+    TokenBucket { tokens: f64 },
+    SlidingWindow { current: u32, previous: u32 },
+}
+
+// Counter storage behind the rate limiter. The in-memory store is per process;
+// a networked store (e.g. Redis running the same algorithms as scripts) lets
+// replicas share counters, hence the async interface.
+pub trait RateLimitStore: Send + Sync {
+    fn hit(
+        &self,
+        key: &str,
+        policy: &RateLimitConfig,
+    ) -> LocalBoxFuture<'static, io::Result<Decision>>;
+
+    // Up to `limit` buckets whose key starts with `prefix`, ordered by key
+    fn buckets(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> LocalBoxFuture<'static, io::Result<Vec<BucketSnapshot>>>;
+}
+
+pub fn from_config(settings: &RateLimitSettings) -> Box<dyn RateLimitStore> {
+    match settings.backend {
+        RateLimitBackend::Memory => Box::new(MemoryStore::new()),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Buckets spread over independently locked shards so unrelated keys don't contend
+pub struct MemoryStore {
+    shards: Vec<Mutex<Shard>>,
+}
+
+#[derive(Default)]
+struct Shard {
+    buckets: HashMap<String, Bucket>,
+    last_sweep_ms: u64,
+}
+
+struct Bucket {
+    state: BucketState,
+    last_seen_ms: u64,
+    // What `state` was counted under
+    policy: RateLimitConfig,
+}
+
+enum BucketState {
+    Tokens {
+        tokens: f64,
+        updated_ms: u64,
+    },
+    Window {
+        index: u64,
+        current: u32,
+        previous: u32,
+    },
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard::default())).collect(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    fn hit_at(&self, key: &str, policy: &RateLimitConfig, now: u64) -> Decision {
+        let window_ms = policy.window_secs * 1000;
+        let mut shard = self.shard(key).lock().unwrap();
+
+        if now.saturating_sub(shard.last_sweep_ms) >= SWEEP_INTERVAL_MS {
+            // A bucket idle for two windows is back to its initial state anyway
+            shard.buckets.retain(|_, b| {
+                now.saturating_sub(b.last_seen_ms) < 2 * b.policy.window_secs * 1000
+            });
+            shard.last_sweep_ms = now;
+        }
+
+        let fresh = || Bucket {
+            state: match policy.algorithm {
+                RateLimitAlgorithm::TokenBucket => BucketState::Tokens {
+                    tokens: policy.burst.unwrap_or(policy.limit) as f64,
+                    updated_ms: now,
+                },
+                RateLimitAlgorithm::SlidingWindow => BucketState::Window {
+                    index: now / window_ms,
+                    current: 0,
+                    previous: 0,
+                },
+            },
+            last_seen_ms: now,
+            policy: policy.clone(),
+        };
+        let bucket = shard.buckets.entry(key.to_string()).or_insert_with(fresh);
+        // A reload changed the policy: counters kept under the old one
+        // (possibly of the other algorithm) mean nothing under the new
+        if bucket.policy != *policy {
+            *bucket = fresh();
+        }
+        bucket.last_seen_ms = now;
+
+        match &mut bucket.state {
+            BucketState::Tokens { tokens, updated_ms } => {
+                token_bucket(tokens, updated_ms, now, policy)
+            }
+            BucketState::Window {
+                index,
+                current,
+                previous,
+            } => sliding_window(index, current, previous, now, policy),
+        }
+    }
+
+    fn buckets_at(&self, prefix: &str, limit: usize, now: u64) -> Vec<BucketSnapshot> {
+        let mut buckets: Vec<BucketSnapshot> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard
+                    .buckets
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, bucket)| BucketSnapshot {
+                        key: key.clone(),
+                        idle_secs: now.saturating_sub(bucket.last_seen_ms) / 1000,
+                        counters: match bucket.state {
+                            BucketState::Tokens { tokens, .. } => Counters::TokenBucket { tokens },
+                            BucketState::Window {
+                                current, previous, ..
+                            } => Counters::SlidingWindow { current, previous },
+                        },
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        buckets.sort_by(|a, b| a.key.cmp(&b.key));
+        buckets.truncate(limit);
+        buckets
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn hit(
+        &self,
+        key: &str,
+        policy: &RateLimitConfig,
+    ) -> LocalBoxFuture<'static, io::Result<Decision>> {
+        Box::pin(ready(Ok(self.hit_at(key, policy, now_ms()))))
+    }
+
+    fn buckets(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> LocalBoxFuture<'static, io::Result<Vec<BucketSnapshot>>> {
+        Box::pin(ready(Ok(self.buckets_at(prefix, limit, now_ms()))))
+    }
+}
+
+// Refill at `limit` per window up to `burst`, spend one token per request
+fn token_bucket(
+    tokens: &mut f64,
+    updated_ms: &mut u64,
+    now: u64,
+    policy: &RateLimitConfig,
+) -> Decision {
+    let capacity = policy.burst.unwrap_or(policy.limit) as f64;
+    let per_ms = policy.limit as f64 / (policy.window_secs * 1000) as f64;
+
+    *tokens = (*tokens + now.saturating_sub(*updated_ms) as f64 * per_ms).min(capacity);
+    *updated_ms = now;
+
+    let allowed = *tokens >= 1.0;
+    if allowed {
+        *tokens -= 1.0;
+    }
+
+    let ms = |missing: f64| Duration::from_millis((missing / per_ms).ceil() as u64);
+    Decision {
+        allowed,
+        limit: capacity as u32,
+        remaining: *tokens as u32,
+        reset: ms(capacity - *tokens),
+        retry_after: (!allowed).then(|| ms(1.0 - *tokens)),
+    }
+}
+
+// Approximates a true sliding window from two fixed windows: the previous
+// window's count is weighted by how much of it still overlaps the last `window`
+fn sliding_window(
+    index: &mut u64,
+    current: &mut u32,
+    previous: &mut u32,
+    now: u64,
+    policy: &RateLimitConfig,
+) -> Decision {
+    let window_ms = policy.window_secs * 1000;
+    let now_index = now / window_ms;
+    if now_index != *index {
+        *previous = if now_index == *index + 1 { *current } else { 0 };
+        *current = 0;
+        *index = now_index;
+    }
+
+    let elapsed = (now % window_ms) as f64 / window_ms as f64;
+    let limit = policy.limit as f64;
+    let weighted = *previous as f64 * (1.0 - elapsed) + *current as f64;
+
+    let allowed = weighted + 1.0 <= limit;
+    if allowed {
+        *current += 1;
+    }
+    let used = weighted + allowed as u32 as f64;
+
+    let until_window_end = window_ms - now % window_ms;
+    let retry_after = (!allowed).then(|| {
+        let wait_ms = if (*current as f64) < limit && *previous > 0 {
+            // Wait until enough of the previous window has slid out
+            let needed = 1.0 - (limit - 1.0 - *current as f64) / *previous as f64;
+            ((needed - elapsed) * window_ms as f64).ceil().max(1.0) as u64
+        } else {
+            // The current window alone is full; it has to start sliding out too
+            let needed = 1.0 - (limit - 1.0) / *current as f64;
+            until_window_end + (needed * window_ms as f64).ceil() as u64
+        };
+        Duration::from_millis(wait_ms)
+    });
+
+    Decision {
+        allowed,
+        limit: policy.limit,
+        remaining: (limit - used).max(0.0) as u32,
+        reset: Duration::from_millis(if *current > 0 {
+            until_window_end + window_ms
+        } else {
+            until_window_end
+        }),
+        retry_after,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Start of a window for every window size used below
+    const T: u64 = 1_000_000;
+
+    fn policy(algorithm: RateLimitAlgorithm, limit: u32, window_secs: u64) -> RateLimitConfig {
+        RateLimitConfig {
+            algorithm,
+            key: Default::default(),
+            limit,
+            window_secs,
+            burst: None,
+        }
+    }
+
+    fn allowed(store: &MemoryStore, key: &str, policy: &RateLimitConfig, now: u64) -> bool {
+        store.hit_at(key, policy, now).allowed
+    }
+
+    // Another key that lands in the same shard as `key`
+    fn shard_mate(store: &MemoryStore, key: &str, n: usize) -> String {
+        (0..)
+            .map(|i| format!("{}-{}", key, i))
+            .filter(|other| std::ptr::eq(store.shard(other), store.shard(key)))
+            .nth(n)
+            .unwrap()
+    }
+
+    #[test]
+    fn token_bucket_denies_once_the_burst_is_spent() {
+        let (store, policy) = (
+            MemoryStore::new(),
+            policy(RateLimitAlgorithm::TokenBucket, 10, 10),
+        );
+
+        for remaining in (0..10).rev() {
+            let decision = store.hit_at("k", &policy, T);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let denied = store.hit_at("k", &policy, T);
+        assert!(!denied.allowed);
+        assert_eq!(denied.limit, 10);
+        assert_eq!(denied.remaining, 0);
+        // One token per second
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(denied.reset, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn token_bucket_refills_at_limit_per_window() {
+        let (store, policy) = (
+            MemoryStore::new(),
+            policy(RateLimitAlgorithm::TokenBucket, 10, 10),
+        );
+        for _ in 0..10 {
+            store.hit_at("k", &policy, T);
+        }
+
+        let half = store.hit_at("k", &policy, T + 500);
+        assert!(!half.allowed);
+        assert_eq!(half.retry_after, Some(Duration::from_millis(500)));
+        // Exactly one token back
+        assert!(allowed(&store, "k", &policy, T + 1000));
+        assert!(!allowed(&store, "k", &policy, T + 1000));
+    }
+
+    #[test]
+    fn token_bucket_refill_is_capped_at_burst() {
+        let policy = RateLimitConfig {
+            burst: Some(3),
+            ..policy(RateLimitAlgorithm::TokenBucket, 1, 1)
+        };
+        let store = MemoryStore::new();
+
+        for _ in 0..3 {
+            assert!(allowed(&store, "k", &policy, T));
+        }
+        assert!(!allowed(&store, "k", &policy, T));
+
+        let later = T + 60_000;
+        let decision = store.hit_at("k", &policy, later);
+        assert_eq!((decision.limit, decision.remaining), (3, 2));
+        assert!(allowed(&store, "k", &policy, later));
+        assert!(allowed(&store, "k", &policy, later));
+        assert!(!allowed(&store, "k", &policy, later));
+    }
+
+    #[test]
+    fn sliding_window_denies_past_the_limit() {
+        let (store, policy) = (
+            MemoryStore::new(),
+            policy(RateLimitAlgorithm::SlidingWindow, 4, 10),
+        );
+
+        for remaining in (0..4).rev() {
+            let decision = store.hit_at("k", &policy, T);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let denied = store.hit_at("k", &policy, T);
+        assert!(!denied.allowed);
+        // Rest of this window, then a quarter of the next for 4 to weigh 3
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(12_500)));
+        assert_eq!(denied.reset, Duration::from_secs(20));
+    }
+
+    #[test]
+    fn sliding_window_weighs_the_previous_window_after_rollover() {
+        let (store, policy) = (
+            MemoryStore::new(),
+            policy(RateLimitAlgorithm::SlidingWindow, 4, 10),
+        );
+        for _ in 0..4 {
+            store.hit_at("k", &policy, T);
+        }
+
+        let next = T + 10_000;
+        let denied = store.hit_at("k", &policy, next);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(2_500)));
+        // 4 x 0.7501 still weighs more than 3
+        assert!(!allowed(&store, "k", &policy, next + 2_499));
+        assert!(allowed(&store, "k", &policy, next + 2_500));
+        assert!(!allowed(&store, "k", &policy, next + 2_500));
+    }
+
+    #[test]
+    fn sliding_window_forgets_windows_older_than_the_previous() {
+        let (store, policy) = (
+            MemoryStore::new(),
+            policy(RateLimitAlgorithm::SlidingWindow, 4, 10),
+        );
+        for _ in 0..4 {
+            store.hit_at("k", &policy, T);
+        }
+
+        for _ in 0..4 {
+            assert!(allowed(&store, "k", &policy, T + 20_000));
+        }
+        assert!(!allowed(&store, "k", &policy, T + 20_000));
+    }
+
+    #[test]
+    fn sweep_drops_buckets_idle_for_two_windows() {
+        let (store, policy) = (
+            MemoryStore::new(),
+            policy(RateLimitAlgorithm::TokenBucket, 1, 10),
+        );
+        let idle = shard_mate(&store, "k", 0);
+        let recent = shard_mate(&store, "k", 1);
+        let sweeper = shard_mate(&store, "k", 2);
+
+        store.hit_at(&idle, &policy, T);
+        store.hit_at(&recent, &policy, T + SWEEP_INTERVAL_MS - 10_000);
+        store.hit_at(&sweeper, &policy, T + SWEEP_INTERVAL_MS);
+
+        let keys: Vec<String> = store
+            .buckets_at("", 10, T + SWEEP_INTERVAL_MS)
+            .into_iter()
+            .map(|b| b.key)
+            .collect();
+        let mut expected = vec![recent, sweeper];
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn buckets_lists_matching_keys_in_order_up_to_the_limit() {
+        let store = MemoryStore::new();
+        let tokens = policy(RateLimitAlgorithm::TokenBucket, 10, 10);
+        let window = policy(RateLimitAlgorithm::SlidingWindow, 10, 10);
+
+        store.hit_at("post|ip:2", &tokens, T);
+        store.hit_at("post|ip:1", &window, T);
+        store.hit_at("post|ip:1", &window, T + 2_000);
+        store.hit_at("user|ip:1", &tokens, T);
+
+        let buckets = store.buckets_at("post|", 10, T + 5_000);
+        let keys: Vec<&str> = buckets.iter().map(|b| b.key.as_str()).collect();
+        assert_eq!(keys, ["post|ip:1", "post|ip:2"]);
+        assert_eq!(buckets[0].idle_secs, 3);
+        assert!(matches!(
+            buckets[0].counters,
+            Counters::SlidingWindow {
+                current: 2,
+                previous: 0
+            }
+        ));
+        assert_eq!(buckets[1].idle_secs, 5);
+        assert!(matches!(buckets[1].counters, Counters::TokenBucket { tokens } if tokens == 9.0));
+
+        let first = store.buckets_at("post|", 1, T);
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].key, "post|ip:1");
+        assert!(store.buckets_at("vote|", 10, T).is_empty());
+    }
+}
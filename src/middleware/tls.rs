@@ -1,17 +1,253 @@
+use actix_web::rt::{self, time};
+use rustls::{
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+        ResolvesServerCert,
+    },
+    sign::{self, CertifiedKey},
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+};
+use std::{
+    fs,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info};
 
-use actix_web::{Error, HttpRequest, HttpResponse, Result};
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use crate::config::{ServerTlsConfig, UpstreamTlsConfig};
 
-pub struct TlsMiddleware;
+// Build the rustls config for the client-facing listener. The certificate is
+// served through a resolver so it can be swapped without dropping connections.
+pub fn server_config(config: &ServerTlsConfig) -> io::Result<(ServerConfig, Arc<CertResolver>)> {
+    let resolver = Arc::new(CertResolver::load(&config.cert, &config.key)?);
+    let builder = ServerConfig::builder().with_safe_defaults();
 
-impl TlsMiddleware {
-    pub fn new() -> Self {
-        // Initialize your SSL settings here if needed
-        TlsMiddleware
+    let builder = match &config.client_ca {
+        Some(ca) => {
+            let roots = load_roots(ca)?;
+            let verifier = if config.require_client_cert {
+                AllowAnyAuthenticatedClient::new(roots).boxed()
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok((builder.with_cert_resolver(resolver.clone()), resolver))
+}
+
+// Serves the current certificate and reloads it from disk on demand
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    fn load(cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        Ok(CertResolver {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(Arc::new(certified_key(cert_path, key_path)?)),
+        })
+    }
+
+    // On error the current certificate keeps being served
+    fn reload(&self) -> io::Result<()> {
+        let key = certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    fn modified_at(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+// Poll the certificate and key files, swapping in the new pair once both parse
+pub fn spawn_cert_watcher(resolver: Arc<CertResolver>, interval_secs: u64) {
+    if interval_secs == 0 {
+        return;
+    }
+
+    rt::spawn(async move {
+        let mut last_modified = resolver.modified_at();
+        let mut ticker = time::interval(Duration::from_secs(interval_secs));
+
+        loop {
+            ticker.tick().await;
+
+            let modified = resolver.modified_at();
+            if modified.is_none() || modified == last_modified {
+                continue;
+            }
+            // Only remember the change once it loaded, so a half-written pair is retried
+            match resolver.reload() {
+                Ok(()) => {
+                    last_modified = modified;
+                    info!(cert = %resolver.cert_path.display(), "TLS certificate reloaded");
+                }
+                Err(err) => {
+                    error!(%err, "TLS certificate reload failed; keeping current certificate")
+                }
+            }
+        }
+    });
+}
+
+// Client side of `[services.tls]` for connections made outside reqwest
+// (WebSocket and gRPC upstreams): the public roots plus `ca`, and the client
+// certificate
+pub fn upstream_client_config(config: Option<&UpstreamTlsConfig>) -> io::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    if let Some(ca) = config.and_then(|c| c.ca.as_ref()) {
+        for cert in load_certs(ca)? {
+            roots
+                .add(&cert)
+                .map_err(|err| invalid(ca, &err.to_string()))?;
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    match config.map(|c| (&c.cert, &c.key)) {
+        Some((Some(cert), Some(key))) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|err| invalid(cert, &err.to_string())),
+        _ => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| invalid(key_path, "unsupported private key type"))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"));
     }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(invalid(path, "no private key found"))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|err| invalid(path, &err.to_string()))?;
+    }
+    Ok(roots)
+}
+
+fn invalid(path: &Path, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), msg),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Writes a fresh self-signed pair for `host` over the resolver's files
+    fn write_pair(dir: &TempDir, host: &str) -> Vec<u8> {
+        let cert = rcgen::generate_simple_self_signed(vec![host.to_string()]).unwrap();
+        let pem = cert.serialize_pem().unwrap();
+        fs::write(dir.path().join("cert.pem"), &pem).unwrap();
+        fs::write(dir.path().join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        rustls_pemfile::certs(&mut pem.as_bytes())
+            .unwrap()
+            .remove(0)
+    }
+
+    fn resolver(dir: &TempDir) -> io::Result<CertResolver> {
+        CertResolver::load(&dir.path().join("cert.pem"), &dir.path().join("key.pem"))
+    }
+
+    fn served(resolver: &CertResolver) -> Vec<u8> {
+        resolver.current.read().unwrap().cert[0].0.clone()
+    }
+
+    #[test]
+    fn reloads_a_replaced_certificate() {
+        let dir = TempDir::new().unwrap();
+        let first = write_pair(&dir, "one.test");
+        let resolver = resolver(&dir).unwrap();
+        assert_eq!(served(&resolver), first);
+
+        let second = write_pair(&dir, "two.test");
+        resolver.reload().unwrap();
+        assert_eq!(served(&resolver), second);
+    }
+
+    #[test]
+    fn keeps_serving_the_current_certificate_when_the_new_one_is_bad() {
+        let dir = TempDir::new().unwrap();
+        let current = write_pair(&dir, "one.test");
+        let resolver = resolver(&dir).unwrap();
+
+        // Half-written certificate, then a key file without a key
+        fs::write(dir.path().join("cert.pem"), "-----BEGIN CERTIFICATE-----\n").unwrap();
+        assert!(resolver.reload().is_err());
+        write_pair(&dir, "two.test");
+        fs::write(dir.path().join("key.pem"), "not a key").unwrap();
+        let err = resolver.reload().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        assert_eq!(served(&resolver), current);
+    }
+
+    #[test]
+    fn refuses_to_start_without_a_usable_pair() {
+        let dir = TempDir::new().unwrap();
+        assert!(resolver(&dir).is_err());
 
-    pub async fn handle_tls(&self, req: &HttpRequest) -> Result<HttpResponse, Error> {
-        // Implement any specific logic for handling mTLS or TLS connections
-        Ok(HttpResponse::Ok().finish())
+        write_pair(&dir, "one.test");
+        fs::write(dir.path().join("cert.pem"), "").unwrap();
+        let err = resolver(&dir).err().unwrap();
+        assert!(err.to_string().contains("no certificates found"), "{}", err);
     }
-}
\ No newline at end of file
+}
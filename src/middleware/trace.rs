@@ -0,0 +1,285 @@
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    Error, HttpMessage as _,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use rand::Rng as _;
+use std::rc::Rc;
+use tracing::{field, info_span, Instrument as _};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+pub const TRACEPARENT_HEADER: HeaderName = HeaderName::from_static("traceparent");
+
+// Client-supplied ids longer than this are replaced rather than logged
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Correlation ids of a request, kept in its extensions for the proxy to
+// forward: our `X-Request-ID` and the W3C trace context of the gateway hop
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub request_id: String,
+    pub trace_id: String,
+    // Id of the gateway's span, the parent of whatever the upstream records
+    pub span_id: String,
+    // The caller's span, when it sent a `traceparent`
+    pub parent_id: Option<String>,
+    pub flags: String,
+}
+
+impl RequestContext {
+    // Keep the caller's request id and trace if they are well formed, so one
+    // id follows the request from the client through every service
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let request_id = headers
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| valid_request_id(id))
+            .map_or_else(|| Uuid::new_v4().to_string(), str::to_string);
+
+        let (trace_id, parent_id, flags) = match headers
+            .get(&TRACEPARENT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_traceparent)
+        {
+            Some((trace_id, parent_id, flags)) => (trace_id, Some(parent_id), flags),
+            None => (random_hex::<16>(), None, "01".to_string()),
+        };
+
+        RequestContext {
+            request_id,
+            trace_id,
+            span_id: random_hex::<8>(),
+            parent_id,
+            flags,
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+// `version-traceid-parentid-flags`, returned as they are. Unknown future
+// versions may append fields, version `ff` is invalid.
+fn parse_traceparent(value: &str) -> Option<(String, String, String)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+
+    let hex = |s: &str, len: usize| {
+        s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let nonzero = |s: &str| s.bytes().any(|b| b != b'0');
+    if !hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    if !hex(trace_id, 32) || !nonzero(trace_id) || !hex(parent_id, 16) || !nonzero(parent_id) {
+        return None;
+    }
+    if !hex(flags, 2) {
+        return None;
+    }
+    Some((
+        trace_id.to_string(),
+        parent_id.to_string(),
+        flags.to_string(),
+    ))
+}
+
+// `N` random bytes as lowercase hex, never all zeros
+fn random_hex<const N: usize>() -> String {
+    loop {
+        let mut bytes = [0u8; N];
+        rand::thread_rng().fill(&mut bytes[..]);
+        if bytes.iter().any(|&b| b != 0) {
+            return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        }
+    }
+}
+
+// Assigns every request its correlation ids, runs it inside a span carrying
+// them and echoes `X-Request-ID` on the response. Wrapped outermost so the
+// span covers the other middleware too.
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestTracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTracingMiddleware {
+            service: Rc::new(service),
+        })
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let context = RequestContext::from_headers(req.headers());
+
+        let span = info_span!(
+            "request",
+            request_id = %context.request_id,
+            trace_id = field::Empty,
+            parent_id = context.parent_id.as_deref().map(field::display),
+            method = %req.method(),
+            path = %req.path(),
+        );
+        // With OTLP export on, the exported span's ids are the ones to propagate
+        #[cfg(feature = "otlp")]
+        let context = crate::telemetry::link_span(&span, context);
+        span.record("trace_id", field::display(&context.trace_id));
+
+        let request_id = HeaderValue::from_str(&context.request_id).ok();
+        req.extensions_mut().insert(context);
+
+        Box::pin(
+            async move {
+                let mut res = service.call(req).await?;
+                if let Some(request_id) = request_id {
+                    res.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+                }
+                Ok(res)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    fn headers(traceparent: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            TRACEPARENT_HEADER,
+            HeaderValue::from_str(traceparent).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn parses_a_valid_traceparent() {
+        let parsed = parse_traceparent(&format!("00-{}-{}-01", TRACE_ID, PARENT_ID));
+        assert_eq!(
+            parsed,
+            Some((TRACE_ID.into(), PARENT_ID.into(), "01".into()))
+        );
+        // Later versions may add fields after the flags
+        let future =
+            parse_traceparent(&format!("cc-{}-{}-00-what-comes-next", TRACE_ID, PARENT_ID));
+        assert_eq!(
+            future,
+            Some((TRACE_ID.into(), PARENT_ID.into(), "00".into()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_traceparents() {
+        let cases = [
+            "",
+            "garbage",
+            &format!("00-{}-{}", TRACE_ID, PARENT_ID),
+            &format!("00-{}-{}-1", TRACE_ID, PARENT_ID),
+            &format!("00-{}-{}-zz", TRACE_ID, PARENT_ID),
+            &format!("00-{}-{}-01", &TRACE_ID[1..], PARENT_ID),
+            &format!("00-{}-{}-01", TRACE_ID, &PARENT_ID[1..]),
+            &format!("00-{}-{}-01", TRACE_ID.to_uppercase(), PARENT_ID),
+            &format!("00_{}_{}_01", TRACE_ID, PARENT_ID),
+            // All-zero ids are invalid
+            &format!("00-{}-{}-01", "0".repeat(32), PARENT_ID),
+            &format!("00-{}-{}-01", TRACE_ID, "0".repeat(16)),
+            // Version ff is forbidden, version 00 has exactly four fields
+            &format!("ff-{}-{}-01", TRACE_ID, PARENT_ID),
+            &format!("0-{}-{}-01", TRACE_ID, PARENT_ID),
+            &format!("00-{}-{}-01-extra", TRACE_ID, PARENT_ID),
+        ];
+
+        for value in cases {
+            assert_eq!(parse_traceparent(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn continues_a_valid_trace() {
+        let context =
+            RequestContext::from_headers(&headers(&format!("00-{}-{}-00", TRACE_ID, PARENT_ID)));
+
+        assert_eq!(context.trace_id, TRACE_ID);
+        assert_eq!(context.parent_id.as_deref(), Some(PARENT_ID));
+        assert_eq!(context.flags, "00");
+        assert_ne!(context.span_id, PARENT_ID);
+        assert_eq!(
+            context.traceparent(),
+            format!("00-{}-{}-00", TRACE_ID, context.span_id)
+        );
+    }
+
+    #[test]
+    fn starts_a_new_trace_instead_of_propagating_a_bad_header() {
+        for value in [
+            format!("00-{}-{}-01", "0".repeat(32), PARENT_ID),
+            format!("ff-{}-{}-01", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01", TRACE_ID, "xyz"),
+        ] {
+            let context = RequestContext::from_headers(&headers(&value));
+
+            assert_ne!(context.trace_id, TRACE_ID, "{}", value);
+            assert_ne!(context.trace_id, "0".repeat(32), "{}", value);
+            assert_eq!(context.trace_id.len(), 32);
+            assert_eq!(context.parent_id, None);
+            assert_eq!(context.flags, "01");
+            assert!(parse_traceparent(&context.traceparent()).is_some());
+        }
+    }
+
+    #[test]
+    fn keeps_only_well_formed_request_ids() {
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("req-123"));
+        assert_eq!(RequestContext::from_headers(&headers).request_id, "req-123");
+
+        for bad in ["", "has space", &"x".repeat(MAX_REQUEST_ID_LEN + 1)] {
+            headers.insert(REQUEST_ID_HEADER, HeaderValue::from_str(bad).unwrap());
+            let request_id = RequestContext::from_headers(&headers).request_id;
+            assert!(Uuid::parse_str(&request_id).is_ok(), "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use actix_web::{
+    dev::{Path, ResourceDef},
+    http::{Method, StatusCode},
+    HttpResponse,
+};
+use serde_json::{json, Map, Value};
+use std::time::Duration;
+
+use crate::config::{
+    check_path_pattern, AggregateConfig, AggregatePartConfig, AuthPolicy, ConfigError,
+    RateLimitConfig, DEFAULT_TOTAL_TIMEOUT_MS, SUBJECT_PLACEHOLDER,
+};
+
+// A composite endpoint answered from several service paths at once
+pub struct Aggregate {
+    pub path: String,
+    pattern: ResourceDef,
+    pub auth: AuthPolicy,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub total: Duration,
+    pub parts: Vec<Part>,
+}
+
+pub struct Part {
+    pub name: String,
+    // Path and query with `{param}` placeholders
+    pub template: String,
+    pub required: bool,
+}
+
+// Why a part has no body in the merged document
+pub struct PartError {
+    pub status: StatusCode,
+    pub error: String,
+}
+
+impl Aggregate {
+    pub fn from_config(config: &AggregateConfig) -> Result<Self, ConfigError> {
+        check_path_pattern(&config.path).map_err(|err| {
+            ConfigError::Invalid(format!("path of aggregate `{}`: {}", config.path, err))
+        })?;
+        Ok(Aggregate {
+            path: config.path.clone(),
+            pattern: ResourceDef::new(config.path.as_str()),
+            auth: config.auth.clone().unwrap_or_default(),
+            rate_limit: config.rate_limit.clone(),
+            total: Duration::from_millis(config.total_ms.unwrap_or(DEFAULT_TOTAL_TIMEOUT_MS)),
+            parts: config.parts.iter().map(Part::from_config).collect(),
+        })
+    }
+
+    // Only GET: parts are fetched with GET whatever the client sent
+    pub fn matches(&self, method: &Method, path: &str) -> bool {
+        method == Method::GET && self.pattern.is_match(path)
+    }
+
+    // Path and query of every part for a request to `path`, or why the part
+    // can't be requested (a `{sub}` part for an anonymous caller)
+    pub fn targets(
+        &self,
+        path: &str,
+        subject: Option<&str>,
+    ) -> Vec<Result<(String, String), PartError>> {
+        let mut captured = Path::new(path);
+        self.pattern.capture_match_info(&mut captured);
+        self.parts
+            .iter()
+            .map(|part| part.target(&captured, subject))
+            .collect()
+    }
+
+    // One document with each part's body under its name (`null` when it
+    // failed) and the failures under `errors`. A failed required part turns
+    // the response into a 502.
+    pub fn merge(&self, outcomes: Vec<Result<Value, PartError>>) -> HttpResponse {
+        let mut document = Map::new();
+        let mut errors = Map::new();
+        let mut failed_required = false;
+        for (part, outcome) in self.parts.iter().zip(outcomes) {
+            let body = match outcome {
+                Ok(body) => body,
+                Err(err) => {
+                    failed_required |= part.required;
+                    errors.insert(
+                        part.name.clone(),
+                        json!({ "status": err.status.as_u16(), "error": err.error }),
+                    );
+                    Value::Null
+                }
+            };
+            document.insert(part.name.clone(), body);
+        }
+        if !errors.is_empty() {
+            document.insert("errors".into(), Value::Object(errors));
+        }
+
+        let status = if failed_required {
+            StatusCode::BAD_GATEWAY
+        } else {
+            StatusCode::OK
+        };
+        HttpResponse::build(status).json(Value::Object(document))
+    }
+}
+
+impl Part {
+    fn from_config(config: &AggregatePartConfig) -> Self {
+        Part {
+            name: config.name.clone(),
+            template: config.path.clone(),
+            required: config.required,
+        }
+    }
+
+    // The path before substitution, enough to tell which service serves the part
+    pub fn path_template(&self) -> &str {
+        self.template.split('?').next().unwrap_or_default()
+    }
+
+    fn target(
+        &self,
+        captured: &Path<&str>,
+        subject: Option<&str>,
+    ) -> Result<(String, String), PartError> {
+        let mut target = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some((before, after)) = rest.split_once('{') {
+            let (name, after) = after.split_once('}').unwrap_or((after, ""));
+            target.push_str(before);
+            let value = if name == SUBJECT_PLACEHOLDER {
+                encode_segment(subject.ok_or_else(|| PartError {
+                    status: StatusCode::UNAUTHORIZED,
+                    error: "Requires a signed-in caller".into(),
+                })?)
+            } else {
+                // Already percent-encoded as the client sent it; validated to exist
+                captured.get(name).unwrap_or_default().to_string()
+            };
+            target.push_str(&value);
+            rest = after;
+        }
+        target.push_str(rest);
+
+        Ok(match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), query.to_string()),
+            None => (target, String::new()),
+        })
+    }
+}
+
+// Percent-encode everything but the unreserved characters of RFC 3986
+fn encode_segment(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::registry::Registry;
+
+    #[test]
+    fn fills_in_path_segments_and_the_subject() {
+        let config: AggregateConfig = toml::from_str(
+            r#"
+            path = "/api/v1/profiles/{user_id}"
+            parts = [
+                { name = "user", path = "/api/v1/user/{user_id}" },
+                { name = "posts", path = "/api/v1/posts?author={user_id}&viewer={sub}" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let aggregate = Aggregate::from_config(&config).unwrap();
+
+        let targets: Vec<_> = aggregate
+            .targets("/api/v1/profiles/a%20b", Some("id/é"))
+            .into_iter()
+            .map(|target| target.ok().unwrap())
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                ("/api/v1/user/a%20b".to_string(), String::new()),
+                (
+                    "/api/v1/posts".to_string(),
+                    "author=a%20b&viewer=id%2F%C3%A9".to_string()
+                ),
+            ]
+        );
+
+        // Only the part that needs the caller fails for an anonymous one
+        let targets = aggregate.targets("/api/v1/profiles/7", None);
+        assert!(targets[0].is_ok());
+        let err = targets[1].as_ref().err().unwrap();
+        assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn malformed_aggregate_paths_are_config_errors() {
+        for path in ["/api/v1/profiles/{user_id", "/api/v1/profiles/{user_id:[}"] {
+            let toml = format!(
+                r#"
+                [[services]]
+                name = "user"
+                prefix = "/api/v1/user"
+                upstreams = [{{ url = "http://user:8081" }}]
+
+                [[aggregates]]
+                path = '{}'
+                parts = [{{ name = "user", path = "/api/v1/user" }}]
+                "#,
+                path
+            );
+            let registry = Registry::from_toml(&toml, None);
+            assert!(matches!(registry, Err(ConfigError::Invalid(_))), "{}", path);
+        }
+    }
+}
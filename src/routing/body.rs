@@ -0,0 +1,578 @@
+use actix_web::{
+    error::PayloadError,
+    http::{
+        header::{self, HeaderMap},
+        Method,
+    },
+    rt::{self, time},
+    web, HttpResponse,
+};
+use futures::{
+    channel::mpsc,
+    future,
+    stream::{self, LocalBoxStream},
+    SinkExt as _, StreamExt as _,
+};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::debug;
+
+use super::{
+    headers::copy_response_headers,
+    registry::InFlight,
+    validation::{payload_too_large, BodyRules},
+};
+
+// Request bodies up to this size are buffered so a retry can replay them.
+// Anything larger, or of unknown length, is streamed to the upstream once.
+pub const MAX_REPLAY_BODY: usize = 64 * 1024;
+
+// Chunks buffered between the client connection and the upstream request
+const CHANNEL_CHUNKS: usize = 8;
+
+// The client's body, with the chunk read to tell whether there is one put back in front
+type ClientBody = LocalBoxStream<'static, Result<web::Bytes, PayloadError>>;
+
+pub enum RequestBody {
+    Buffered(web::Bytes),
+    Streaming {
+        // Taken by the first attempt; a streamed body can't be sent twice
+        body: Option<reqwest::Body>,
+        limit: Option<usize>,
+        // Set once the client sent more than `limit` and the upload was cut off
+        overflowed: Arc<AtomicBool>,
+    },
+}
+
+impl RequestBody {
+    // Buffer small bodies when the request may be retried and bodies that
+    // `rules` validate; stream everything else, cut off past `rules.max_bytes`
+    pub async fn from_payload(
+        mut payload: web::Payload,
+        method: &Method,
+        headers: &HeaderMap,
+        replayable: bool,
+        rules: Option<&BodyRules>,
+    ) -> Result<Self, HttpResponse> {
+        // HTTP/2 requests may have a body without announcing its length, so
+        // whether there is one is up to the payload, not the framing headers
+        let first = loop {
+            match payload.next().await {
+                Some(Ok(chunk)) if chunk.is_empty() => continue,
+                Some(Ok(chunk)) => break chunk,
+                Some(Err(err)) => return Err(read_failed(err)),
+                None => {
+                    if let Some(rules) = rules {
+                        rules.check_missing_body(method)?;
+                    }
+                    return Ok(RequestBody::Buffered(web::Bytes::new()));
+                }
+            }
+        };
+        let payload: ClientBody = stream::once(future::ready(Ok(first)))
+            .chain(payload)
+            .boxed_local();
+
+        if let Some(rules) = rules.filter(|rules| rules.validates_body()) {
+            let limit = rules.buffer_limit();
+            let bytes = match read_limited(payload, limit).await {
+                Ok(Some(bytes)) => bytes,
+                Ok(None) => return Err(payload_too_large(limit)),
+                Err(err) => return Err(read_failed(err)),
+            };
+            rules.check_body(&bytes)?;
+            return Ok(RequestBody::Buffered(bytes));
+        }
+
+        let content_length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        let chunked = headers.contains_key(header::TRANSFER_ENCODING);
+        if replayable && !chunked && content_length.is_some_and(|len| len <= MAX_REPLAY_BODY) {
+            return match read_limited(payload, MAX_REPLAY_BODY).await {
+                Ok(Some(bytes)) => Ok(RequestBody::Buffered(bytes)),
+                Ok(None) => Err(HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                    "error": "Request body larger than its Content-Length"
+                }))),
+                Err(err) => Err(read_failed(err)),
+            };
+        }
+
+        let limit = rules.and_then(|rules| rules.max_bytes);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        Ok(RequestBody::Streaming {
+            body: Some(stream_payload(payload, limit, overflowed.clone())),
+            limit,
+            overflowed,
+        })
+    }
+
+    pub fn is_replayable(&self) -> bool {
+        matches!(self, RequestBody::Buffered(_))
+    }
+
+    // Body for the next attempt
+    pub fn next_attempt(&mut self) -> reqwest::Body {
+        match self {
+            RequestBody::Buffered(bytes) => bytes.clone().into(),
+            RequestBody::Streaming { body, .. } => body.take().unwrap_or_else(|| Vec::new().into()),
+        }
+    }
+
+    // The limit a streamed upload was cut off at, if it was
+    pub fn exceeded_limit(&self) -> Option<usize> {
+        match self {
+            RequestBody::Streaming {
+                limit, overflowed, ..
+            } if overflowed.load(Ordering::Relaxed) => *limit,
+            _ => None,
+        }
+    }
+}
+
+// The whole body, or `None` once it grows past `limit`
+async fn read_limited(
+    mut payload: ClientBody,
+    limit: usize,
+) -> Result<Option<web::Bytes>, PayloadError> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > limit {
+            return Ok(None);
+        }
+    }
+    Ok(Some(body.freeze()))
+}
+
+fn read_failed(err: impl std::fmt::Display) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "error": format!("Failed to read request body: {}", err)
+    }))
+}
+
+// The client payload lives on this worker thread, while reqwest wants a `Send`
+// stream, so a local task pumps chunks through a bounded channel. The bound
+// keeps a slow upstream from making the gateway buffer the whole upload.
+fn stream_payload(
+    mut payload: ClientBody,
+    limit: Option<usize>,
+    overflowed: Arc<AtomicBool>,
+) -> reqwest::Body {
+    let (mut tx, rx) = mpsc::channel::<io::Result<web::Bytes>>(CHANNEL_CHUNKS);
+
+    rt::spawn(async move {
+        let mut received = 0;
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(io::Error::other).and_then(|chunk| {
+                received += chunk.len();
+                if limit.is_some_and(|limit| received > limit) {
+                    // Fails the upstream request; the gateway answers 413
+                    overflowed.store(true, Ordering::Relaxed);
+                    return Err(io::Error::other("request body too large"));
+                }
+                Ok(chunk)
+            });
+            let failed = chunk.is_err();
+            // The upstream request was dropped; stop reading from the client
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    reqwest::Body::wrap_stream(rx)
+}
+
+// Whether the client asks for a Server-Sent Events stream (`EventSource` does)
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/event-stream"))
+}
+
+// Like `stream_response`, for responses that stay open indefinitely: the
+// stream ends once the upstream sent nothing for `idle`
+pub fn stream_events(
+    resp: reqwest::Response,
+    in_flight: Option<InFlight>,
+    idle: Duration,
+) -> HttpResponse {
+    let mut builder = HttpResponse::build(resp.status());
+    copy_response_headers(resp.headers(), &mut builder);
+
+    let chunks = resp.bytes_stream();
+    let body = stream::unfold(
+        (chunks, in_flight),
+        move |(mut chunks, in_flight)| async move {
+            match time::timeout(idle, chunks.next()).await {
+                Ok(Some(chunk)) => Some((chunk, (chunks, in_flight))),
+                Ok(None) => None,
+                Err(_) => {
+                    debug!("closing idle event stream");
+                    None
+                }
+            }
+        },
+    );
+    builder.streaming(body)
+}
+
+// Relay the upstream response as-is: status, end-to-end headers and body bytes
+// are passed through untouched, and the body is streamed rather than buffered.
+// `in_flight` is held until the body has been fully sent so the balancer sees
+// the load.
+pub fn stream_response(resp: reqwest::Response, in_flight: Option<InFlight>) -> HttpResponse {
+    let mut builder = HttpResponse::build(resp.status());
+    copy_response_headers(resp.headers(), &mut builder);
+
+    let content_length = resp
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    let body = resp.bytes_stream().map(move |chunk| {
+        let _in_flight = &in_flight;
+        chunk
+    });
+
+    match content_length {
+        Some(len) => builder.no_chunking(len).streaming(body),
+        None => builder.streaming(body),
+    }
+}
+
+// Read an upstream response of at most `limit` bytes into memory, e.g. to
+// cache it. A larger body is relayed as a stream instead: `Err` carries the
+// response to send, with the part read so far in front of the rest.
+pub async fn buffer_response(
+    resp: reqwest::Response,
+    in_flight: Option<InFlight>,
+    limit: usize,
+) -> Result<(reqwest::StatusCode, reqwest::header::HeaderMap, web::Bytes), HttpResponse> {
+    let too_large = resp.content_length().is_some_and(|len| len > limit as u64);
+    if too_large {
+        return Err(stream_response(resp, in_flight));
+    }
+
+    let status = resp.status();
+    let headers = resp.headers().clone();
+    let mut body = resp.bytes_stream();
+    let mut buffered = web::BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                return Err(HttpResponse::BadGateway().json(serde_json::json!({
+                    "error": format!("Failed to read upstream response: {}", err)
+                })))
+            }
+        };
+        buffered.extend_from_slice(&chunk);
+        if buffered.len() > limit {
+            let head = stream::once(async move { Ok(buffered.freeze()) });
+            let body = head.chain(body).map(move |chunk| {
+                let _in_flight = &in_flight;
+                chunk
+            });
+            let mut builder = HttpResponse::build(status);
+            copy_response_headers(&headers, &mut builder);
+            return Err(builder.streaming(body));
+        }
+    }
+
+    Ok((status, headers, buffered.freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BodyConfig;
+    use actix_web::{
+        body, http::StatusCode, test::TestRequest, App, FromRequest as _, HttpRequest, HttpServer,
+    };
+    use std::{fs, path::PathBuf};
+    use tempfile::NamedTempFile;
+
+    // Not valid UTF-8, so any text conversion on the way would show
+    const BINARY: &[u8] = &[0xff, 0x00, 0xfe, 0x80, 0x0a, 0xc3, 0x28];
+
+    // `POST /echo` answers with the body it received, `GET /binary` with
+    // `BINARY`, and `GET /chunked` with a body of unannounced length
+    fn upstream() -> String {
+        let server = HttpServer::new(|| {
+            App::new()
+                .app_data(web::PayloadConfig::new(16 * 1024 * 1024))
+                .route(
+                    "/echo",
+                    web::post().to(|body: web::Bytes| async move { HttpResponse::Ok().body(body) }),
+                )
+                .route(
+                    "/binary",
+                    web::get().to(|| async {
+                        HttpResponse::PartialContent()
+                            .content_type("application/octet-stream")
+                            .insert_header(("x-checksum", "7"))
+                            .body(BINARY)
+                    }),
+                )
+                .route(
+                    "/chunked",
+                    web::get().to(|| async {
+                        let chunks =
+                            [BINARY, BINARY].map(|c| Ok::<_, io::Error>(web::Bytes::from(c)));
+                        HttpResponse::Ok().streaming(stream::iter(chunks))
+                    }),
+                )
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        rt::spawn(server.run());
+        format!("http://{}", addr)
+    }
+
+    async fn forward(
+        req: TestRequest,
+        rules: Option<&BodyRules>,
+    ) -> (RequestBody, reqwest::Result<web::Bytes>) {
+        let (req, mut payload) = req.to_http_parts();
+        let payload = web::Payload::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+        let mut body = RequestBody::from_payload(payload, req.method(), req.headers(), true, rules)
+            .await
+            .unwrap_or_else(|res| panic!("rejected with {}", res.status()));
+        let sent = reqwest::Client::new()
+            .post(format!("{}/echo", upstream()))
+            .body(body.next_attempt())
+            .send()
+            .await;
+        let echoed = match sent {
+            Ok(resp) => resp.bytes().await,
+            Err(err) => Err(err),
+        };
+        (body, echoed)
+    }
+
+    async fn get(path: &str) -> reqwest::Response {
+        reqwest::get(format!("{}{}", upstream(), path))
+            .await
+            .unwrap()
+    }
+
+    const SCHEMA: &str = r#"{
+        "type": "object",
+        "required": ["title"],
+        "properties": { "title": { "type": "string" } }
+    }"#;
+
+    // The schema is compiled while the rules are built, so its file can go right after
+    fn rules() -> BodyRules {
+        let schema = NamedTempFile::new().unwrap();
+        fs::write(schema.path(), SCHEMA).unwrap();
+        let config = BodyConfig {
+            max_bytes: Some(32),
+            content_types: vec!["application/json".into()],
+            schema: Some(PathBuf::from(schema.path())),
+        };
+        BodyRules::from_config(&config, "test").unwrap()
+    }
+
+    async fn read(req: TestRequest, rules: &BodyRules) -> Result<RequestBody, HttpResponse> {
+        let (req, mut payload) = req.to_http_parts();
+        rules.check_head(req.headers())?;
+        let payload = web::Payload::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+        RequestBody::from_payload(payload, req.method(), req.headers(), true, Some(rules)).await
+    }
+
+    fn json(body: &'static str) -> TestRequest {
+        TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .insert_header((header::CONTENT_LENGTH, body.len()))
+            .set_payload(body)
+    }
+
+    fn status(outcome: Result<RequestBody, HttpResponse>) -> StatusCode {
+        outcome.err().map_or(StatusCode::OK, |res| res.status())
+    }
+
+    #[actix_web::test]
+    async fn checks_size_type_and_schema() {
+        let rules = rules();
+        let cases = [
+            (json(r#"{"title": "hello"}"#), StatusCode::OK),
+            // Content-Length over max_bytes, then a body longer than it claims
+            (
+                json(r#"{"title": "a much longer title than allowed"}"#),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (
+                TestRequest::post()
+                    .insert_header((header::CONTENT_TYPE, "application/json"))
+                    .insert_header((header::TRANSFER_ENCODING, "chunked"))
+                    .set_payload(r#"{"title": "a much longer title than allowed"}"#),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (
+                json(r#"{"title": "hello"}"#).insert_header((header::CONTENT_TYPE, "text/plain")),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            (json(r#"{"title": 7}"#), StatusCode::BAD_REQUEST),
+            (json("not json"), StatusCode::BAD_REQUEST),
+        ];
+        for (req, expected) in cases {
+            assert_eq!(status(read(req, &rules).await), expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn only_bodyless_methods_skip_the_schema() {
+        let rules = rules();
+        for req in [
+            TestRequest::get(),
+            TestRequest::default().method(Method::HEAD),
+            TestRequest::delete(),
+            TestRequest::default().method(Method::OPTIONS),
+        ] {
+            match read(req, &rules).await {
+                Ok(RequestBody::Buffered(bytes)) => assert!(bytes.is_empty()),
+                Ok(_) => panic!("streamed an empty body"),
+                Err(res) => panic!("rejected with {}", res.status()),
+            }
+        }
+
+        // Writes without a body, with or without a Content-Length
+        for req in [
+            TestRequest::post().insert_header((header::CONTENT_LENGTH, 0)),
+            TestRequest::post(),
+            TestRequest::put(),
+            TestRequest::patch(),
+        ] {
+            assert_eq!(status(read(req, &rules).await), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[actix_web::test]
+    async fn bodies_without_framing_headers_are_forwarded() {
+        // As over HTTP/2: neither Content-Length nor Transfer-Encoding
+        let (body, echoed) = forward(TestRequest::post().set_payload("hello"), None).await;
+        assert!(!body.is_replayable());
+        assert_eq!(echoed.unwrap(), "hello");
+
+        let rules = rules();
+        let req = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload(r#"{"title": "hi"}"#);
+        let (body, echoed) = forward(req, Some(&rules)).await;
+        assert!(body.is_replayable());
+        assert_eq!(echoed.unwrap(), r#"{"title": "hi"}"#);
+    }
+
+    #[actix_web::test]
+    async fn streams_bodies_larger_than_the_replay_buffer() {
+        let upload: Vec<u8> = (0..MAX_REPLAY_BODY * 4).map(|i| i as u8).collect();
+        let req = TestRequest::put()
+            .insert_header((header::CONTENT_LENGTH, upload.len()))
+            .set_payload(upload.clone());
+        let (body, echoed) = forward(req, None).await;
+        assert!(!body.is_replayable());
+        assert_eq!(echoed.unwrap(), upload);
+        assert_eq!(body.exceeded_limit(), None);
+    }
+
+    #[actix_web::test]
+    async fn streamed_bodies_over_the_limit_are_cut_off() {
+        let config = BodyConfig {
+            max_bytes: Some(1024),
+            content_types: Vec::new(),
+            schema: None,
+        };
+        let rules = BodyRules::from_config(&config, "test").unwrap();
+        let req = TestRequest::post()
+            .insert_header((header::TRANSFER_ENCODING, "chunked"))
+            .set_payload(vec![b'a'; 4096]);
+
+        let (body, echoed) = forward(req, Some(&rules)).await;
+        assert!(echoed.is_err());
+        // What the proxy answers with instead of the upstream error
+        assert_eq!(body.exceeded_limit(), Some(1024));
+        assert_eq!(
+            payload_too_large(1024).status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[actix_web::test]
+    async fn relays_binary_responses_byte_for_byte() {
+        let res = stream_response(get("/binary").await, None);
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/octet-stream"
+        );
+        assert_eq!(res.headers().get("x-checksum").unwrap(), "7");
+        assert_eq!(body::to_bytes(res.into_body()).await.unwrap(), BINARY);
+    }
+
+    #[actix_web::test]
+    async fn keeps_the_upstream_framing() {
+        // A gateway relaying `upstream` paths, to see the framing on the wire
+        let upstream = upstream();
+        let relay = HttpServer::new(move || {
+            let upstream = upstream.clone();
+            App::new().default_service(web::to(move |req: HttpRequest| {
+                let url = format!("{}{}", upstream, req.path());
+                async move { stream_response(reqwest::get(url).await.unwrap(), None) }
+            }))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = relay.addrs()[0];
+        rt::spawn(relay.run());
+        let relayed = |path: &str| reqwest::get(format!("http://{}{}", addr, path));
+
+        let sized = relayed("/binary").await.unwrap();
+        assert_eq!(sized.content_length(), Some(BINARY.len() as u64));
+        assert!(sized.headers().get(header::TRANSFER_ENCODING).is_none());
+        assert_eq!(sized.bytes().await.unwrap(), BINARY);
+
+        let chunked = relayed("/chunked").await.unwrap();
+        assert_eq!(chunked.headers()[header::TRANSFER_ENCODING], "chunked");
+        assert_eq!(chunked.bytes().await.unwrap(), [BINARY, BINARY].concat());
+    }
+
+    #[actix_web::test]
+    async fn buffers_up_to_the_limit_then_streams() {
+        let (status, _, bytes) = buffer_response(get("/binary").await, None, 64)
+            .await
+            .unwrap();
+        assert_eq!(status, reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(bytes, BINARY);
+
+        // Over the limit, the part already read still goes out first
+        let res = buffer_response(get("/chunked").await, None, 4)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            body::to_bytes(res.into_body()).await.unwrap(),
+            [BINARY, BINARY].concat()
+        );
+    }
+}
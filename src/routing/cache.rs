@@ -0,0 +1,646 @@
+use actix_web::{
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        Method, StatusCode,
+    },
+    web::Bytes,
+    HttpRequest, HttpResponse,
+};
+use futures::{
+    channel::oneshot,
+    future::{FutureExt as _, Shared},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::{CacheConfig, CacheSettings};
+
+use super::headers::response_headers;
+
+// How the gateway's cache answered: `HIT`, `STALE` (refresh under way),
+// `REVALIDATED` (upstream confirmed the stored copy) or `MISS`
+pub const CACHE_STATUS_HEADER: HeaderName = HeaderName::from_static("x-cache");
+pub const HIT: &str = "HIT";
+pub const STALE: &str = "STALE";
+pub const REVALIDATED: &str = "REVALIDATED";
+pub const MISS: &str = "MISS";
+
+// Resolves once the request fetching a key is done with it
+pub type Pending = Shared<oneshot::Receiver<()>>;
+
+// In-memory cache for the anonymous GET responses of routes with a `cache`
+// policy. Entries are keyed by path and query, with one variant per set of
+// values of the request headers named in the response's `Vary`.
+pub struct ResponseCache {
+    max_bytes: usize,
+    max_entry_bytes: usize,
+    store: Mutex<Store>,
+    // Keys being fetched from upstream; concurrent misses wait for that fetch
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+}
+
+#[derive(Default)]
+struct Store {
+    slots: HashMap<String, Slot>,
+    // Last use of each key, oldest first
+    lru: BTreeMap<u64, String>,
+    clock: u64,
+    bytes: usize,
+}
+
+struct Slot {
+    variants: Vec<Arc<CachedResponse>>,
+    used: u64,
+}
+
+// Held by the request fetching a key; waiting requests wake up when it is dropped
+pub struct Flight {
+    key: String,
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+    _done: oneshot::Sender<()>,
+}
+
+pub struct CachedResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+    // Request header values named by the response's `Vary`
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    stored: Instant,
+    fresh_for: Duration,
+    stale_for: Duration,
+    size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    // Past its TTL, but may be served while it is refreshed
+    Stale,
+    // Only good for revalidating with a conditional request
+    Expired,
+}
+
+// The `Cache-Control` directives the cache acts on
+#[derive(Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    stale_while_revalidate: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse<'a>(values: impl Iterator<Item = &'a HeaderValue>) -> Self {
+        let mut cc = CacheControl::default();
+        let directives = values
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let secs = value.and_then(|v| v.parse().ok());
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "max-age" => cc.max_age = secs,
+                "s-maxage" => cc.s_maxage = secs,
+                "stale-while-revalidate" => cc.stale_while_revalidate = secs,
+                _ => {}
+            }
+        }
+        cc
+    }
+}
+
+// Only anonymous GETs are cached, so one user's response never reaches another.
+// `Cache-Control: no-store` from the client bypasses the cache.
+pub fn is_cacheable_request(req: &HttpRequest, authenticated: bool) -> bool {
+    req.method() == Method::GET
+        && !authenticated
+        && !req.headers().contains_key(header::AUTHORIZATION)
+        && !CacheControl::parse(req.headers().get_all(header::CACHE_CONTROL)).no_store
+}
+
+// The client asked for a copy confirmed by the upstream (`no-cache` or `max-age=0`)
+pub fn wants_revalidation(req: &HttpRequest) -> bool {
+    let cc = CacheControl::parse(req.headers().get_all(header::CACHE_CONTROL));
+    cc.no_cache || cc.max_age == Some(0)
+}
+
+pub fn cache_key(req: &HttpRequest) -> String {
+    match req.query_string() {
+        "" => req.path().to_string(),
+        query => format!("{}?{}", req.path(), query),
+    }
+}
+
+impl ResponseCache {
+    pub fn new(settings: &CacheSettings) -> Self {
+        ResponseCache {
+            max_bytes: settings.max_bytes,
+            max_entry_bytes: settings.max_entry_bytes,
+            store: Mutex::new(Store::default()),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn max_entry_bytes(&self) -> usize {
+        self.max_entry_bytes
+    }
+
+    // The variant of `key` stored for the request's `Vary` header values
+    pub fn lookup(&self, key: &str, req: &HttpRequest) -> Option<Arc<CachedResponse>> {
+        let mut store = self.store.lock().unwrap();
+        let entry = store
+            .slots
+            .get(key)?
+            .variants
+            .iter()
+            .find(|entry| entry.matches(req.headers()))?
+            .clone();
+        store.touch(key);
+        Some(entry)
+    }
+
+    // Store `entry`, replacing the variant with the same `Vary` values and
+    // evicting least recently used keys until the cache fits `max_bytes`
+    pub fn insert(&self, key: &str, entry: Arc<CachedResponse>) {
+        if entry.size > self.max_entry_bytes {
+            return;
+        }
+
+        let mut store = self.store.lock().unwrap();
+        let store = &mut *store;
+        let slot = store.slots.entry(key.to_string()).or_insert_with(|| Slot {
+            variants: Vec::new(),
+            used: 0,
+        });
+        if let Some(i) = slot.variants.iter().position(|v| v.vary == entry.vary) {
+            store.bytes -= slot.variants.swap_remove(i).size;
+        }
+        store.bytes += entry.size;
+        slot.variants.push(entry);
+        store.touch(key);
+
+        while store.bytes > self.max_bytes {
+            let Some((_, oldest)) = store.lru.pop_first() else {
+                break;
+            };
+            if let Some(slot) = store.slots.remove(&oldest) {
+                store.bytes -= slot.bytes();
+            }
+        }
+    }
+
+    // Drop every key starting with `prefix`, e.g. `/api/v1/posts`; returns how many
+    pub fn purge(&self, prefix: &str) -> usize {
+        let mut store = self.store.lock().unwrap();
+        let keys: Vec<String> = store
+            .slots
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in &keys {
+            store.remove(key);
+        }
+        keys.len()
+    }
+
+    // Claim the upstream fetch of `key`, or get the one already under way
+    pub fn begin(&self, key: &str) -> Result<Flight, Pending> {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(fetch) = pending.get(key) {
+            return Err(fetch.clone());
+        }
+        let (done, fetch) = oneshot::channel();
+        pending.insert(key.to_string(), fetch.shared());
+        Ok(Flight {
+            key: key.to_string(),
+            pending: self.pending.clone(),
+            _done: done,
+        })
+    }
+}
+
+impl Store {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(slot) = self.slots.get_mut(key) {
+            self.lru.remove(&slot.used);
+            slot.used = self.clock;
+            self.lru.insert(self.clock, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(slot) = self.slots.remove(key) {
+            self.lru.remove(&slot.used);
+            self.bytes -= slot.bytes();
+        }
+    }
+}
+
+impl Slot {
+    fn bytes(&self) -> usize {
+        self.variants.iter().map(|entry| entry.size).sum()
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl CachedResponse {
+    // `None` unless the response may be stored: a 200 without `no-store`,
+    // `no-cache`, `private`, `Set-Cookie` or `Vary: *`
+    pub fn new(
+        status: StatusCode,
+        headers: &reqwest::header::HeaderMap,
+        body: Bytes,
+        req: &HttpRequest,
+        policy: &CacheConfig,
+    ) -> Option<Self> {
+        let cc = CacheControl::parse(headers.get_all(header::CACHE_CONTROL).iter());
+        if status != StatusCode::OK
+            || cc.no_store
+            || cc.no_cache
+            || cc.private
+            || headers.contains_key(header::SET_COOKIE)
+        {
+            return None;
+        }
+
+        let mut vary = Vec::new();
+        let names = headers
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim);
+        for name in names {
+            if name == "*" {
+                return None;
+            }
+            if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+                let value = req.headers().get(&name).cloned();
+                vary.push((name, value));
+            }
+        }
+
+        let stored_headers: Vec<_> = response_headers(headers)
+            .into_iter()
+            .filter(|(name, _)| *name != header::AGE)
+            .collect();
+        let size = body.len()
+            + stored_headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>();
+        let mut entry = CachedResponse {
+            status,
+            headers: stored_headers,
+            body,
+            vary,
+            etag: headers.get(header::ETAG).cloned(),
+            last_modified: headers.get(header::LAST_MODIFIED).cloned(),
+            stored: Instant::now(),
+            fresh_for: Duration::ZERO,
+            stale_for: Duration::ZERO,
+            size,
+        };
+        entry.set_lifetime(&cc, policy);
+
+        let useful =
+            !entry.fresh_for.is_zero() || !entry.stale_for.is_zero() || entry.has_validator();
+        useful.then_some(entry)
+    }
+
+    // Copy of this entry confirmed by a `304 Not Modified` from the upstream
+    pub fn revalidated(&self, headers: &reqwest::header::HeaderMap, policy: &CacheConfig) -> Self {
+        let mut entry = CachedResponse {
+            status: self.status,
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            vary: self.vary.clone(),
+            etag: headers.get(header::ETAG).or(self.etag.as_ref()).cloned(),
+            last_modified: self.last_modified.clone(),
+            stored: Instant::now(),
+            fresh_for: Duration::ZERO,
+            stale_for: Duration::ZERO,
+            size: self.size,
+        };
+        entry.set_lifetime(
+            &CacheControl::parse(headers.get_all(header::CACHE_CONTROL).iter()),
+            policy,
+        );
+        entry
+    }
+
+    // `s-maxage` over `max-age` over the route's TTL
+    fn set_lifetime(&mut self, cc: &CacheControl, policy: &CacheConfig) {
+        let fresh = cc.s_maxage.or(cc.max_age).unwrap_or(policy.ttl_secs);
+        let stale = cc
+            .stale_while_revalidate
+            .unwrap_or(policy.stale_while_revalidate_secs);
+        self.fresh_for = Duration::from_secs(fresh);
+        self.stale_for = Duration::from_secs(stale);
+    }
+
+    pub fn freshness(&self) -> Freshness {
+        self.freshness_at(Instant::now())
+    }
+
+    fn freshness_at(&self, now: Instant) -> Freshness {
+        let age = now.saturating_duration_since(self.stored);
+        if age < self.fresh_for {
+            Freshness::Fresh
+        } else if age < self.fresh_for + self.stale_for {
+            Freshness::Stale
+        } else {
+            Freshness::Expired
+        }
+    }
+
+    pub fn has_validator(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+
+    // Conditional request headers for revalidating this entry upstream
+    pub fn validators(&self) -> impl Iterator<Item = (HeaderName, HeaderValue)> {
+        [
+            (header::IF_NONE_MATCH, self.etag.clone()),
+            (header::IF_MODIFIED_SINCE, self.last_modified.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.map(|value| (name, value)))
+    }
+
+    fn matches(&self, req: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| req.get(name) == value.as_ref())
+    }
+
+    // The stored response, or a 304 when the client's `If-None-Match` names its ETag
+    pub fn respond(&self, req: &HttpRequest, cache_status: &'static str) -> HttpResponse {
+        let not_modified = match (req.headers().get(header::IF_NONE_MATCH), &self.etag) {
+            (Some(wanted), Some(etag)) => etag_matches(wanted, etag),
+            _ => false,
+        };
+
+        let mut builder = if not_modified {
+            HttpResponse::NotModified()
+        } else {
+            HttpResponse::build(self.status)
+        };
+        for header in &self.headers {
+            builder.append_header(header.clone());
+        }
+        builder
+            .insert_header((header::AGE, self.stored.elapsed().as_secs()))
+            .insert_header((CACHE_STATUS_HEADER, cache_status));
+
+        if not_modified {
+            builder.finish()
+        } else {
+            builder.body(self.body.clone())
+        }
+    }
+}
+
+// Weak comparison (RFC 9110 section 13.1.2) against an `If-None-Match` list
+fn etag_matches(wanted: &HeaderValue, etag: &HeaderValue) -> bool {
+    let strip = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let (Ok(wanted), Ok(etag)) = (wanted.to_str(), etag.to_str()) else {
+        return false;
+    };
+    let etag = strip(etag);
+    wanted.trim() == "*" || wanted.split(',').any(|tag| strip(tag) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const POLICY: CacheConfig = CacheConfig {
+        ttl_secs: 10,
+        stale_while_revalidate_secs: 5,
+    };
+
+    fn get(uri: &str, headers: &[(&'static str, &'static str)]) -> HttpRequest {
+        let mut req = TestRequest::get().uri(uri);
+        for header in headers {
+            req = req.insert_header(*header);
+        }
+        req.to_http_request()
+    }
+
+    fn upstream_headers(headers: &[(&'static str, &'static str)]) -> reqwest::header::HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    reqwest::header::HeaderName::from_static(name),
+                    reqwest::header::HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    fn response(
+        req: &HttpRequest,
+        headers: &[(&'static str, &'static str)],
+        body: &str,
+    ) -> Option<CachedResponse> {
+        CachedResponse::new(
+            StatusCode::OK,
+            &upstream_headers(headers),
+            Bytes::copy_from_slice(body.as_bytes()),
+            req,
+            &POLICY,
+        )
+    }
+
+    fn cache(max_bytes: usize) -> ResponseCache {
+        ResponseCache::new(&CacheSettings {
+            max_bytes,
+            max_entry_bytes: 64,
+        })
+    }
+
+    // Store a plain 10-byte response under the request's key
+    fn store(cache: &ResponseCache, uri: &str) {
+        let req = get(uri, &[]);
+        let entry = response(&req, &[], "0123456789").unwrap();
+        cache.insert(&cache_key(&req), Arc::new(entry));
+    }
+
+    fn cached(cache: &ResponseCache, uri: &str) -> bool {
+        let req = get(uri, &[]);
+        cache.lookup(&cache_key(&req), &req).is_some()
+    }
+
+    #[test]
+    fn keys_on_path_and_query() {
+        assert_eq!(
+            cache_key(&get("/api/v1/posts/all", &[])),
+            "/api/v1/posts/all"
+        );
+        assert_eq!(
+            cache_key(&get("/api/v1/posts/all?page=2&size=10", &[])),
+            "/api/v1/posts/all?page=2&size=10"
+        );
+    }
+
+    #[test]
+    fn stores_one_variant_per_vary_values() {
+        let cache = cache(1000);
+        let gzip = get("/posts", &[("accept-encoding", "gzip")]);
+        let br = get("/posts", &[("accept-encoding", "br")]);
+        let vary = [("vary", "Accept-Encoding")];
+
+        cache.insert("/posts", Arc::new(response(&gzip, &vary, "gzip").unwrap()));
+        assert!(cache.lookup("/posts", &gzip).is_some());
+        assert!(cache.lookup("/posts", &br).is_none());
+        assert!(cache.lookup("/posts", &get("/posts", &[])).is_none());
+
+        cache.insert("/posts", Arc::new(response(&br, &vary, "br").unwrap()));
+        assert_eq!(cache.lookup("/posts", &br).unwrap().body, "br");
+        assert_eq!(cache.lookup("/posts", &gzip).unwrap().body, "gzip");
+
+        // Same `Vary` values replace the variant rather than adding one
+        let bytes = cache.store.lock().unwrap().bytes;
+        cache.insert("/posts", Arc::new(response(&br, &vary, "bR").unwrap()));
+        assert_eq!(cache.lookup("/posts", &br).unwrap().body, "bR");
+        assert_eq!(cache.store.lock().unwrap().bytes, bytes);
+    }
+
+    #[test]
+    fn refuses_responses_that_must_not_be_shared() {
+        let req = get("/posts", &[]);
+        for headers in [
+            &[("vary", "*")][..],
+            &[("cache-control", "no-store")],
+            &[("cache-control", "private, max-age=60")],
+            &[("cache-control", "no-cache")],
+            &[("set-cookie", "session=1")],
+        ] {
+            assert!(response(&req, headers, "body").is_none(), "{:?}", headers);
+        }
+        let not_found = CachedResponse::new(
+            StatusCode::NOT_FOUND,
+            &upstream_headers(&[]),
+            Bytes::new(),
+            &req,
+            &POLICY,
+        );
+        assert!(not_found.is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_keys_first() {
+        let cache = cache(30);
+        store(&cache, "/a");
+        store(&cache, "/b");
+        store(&cache, "/c");
+        // Using `/a` leaves `/b` the least recently used, then `/c`
+        assert!(cached(&cache, "/a"));
+
+        store(&cache, "/d");
+        assert!(!cached(&cache, "/b"));
+        store(&cache, "/e");
+        assert!(!cached(&cache, "/c"));
+
+        assert!(cached(&cache, "/a") && cached(&cache, "/d") && cached(&cache, "/e"));
+        assert_eq!(cache.store.lock().unwrap().bytes, 30);
+    }
+
+    #[test]
+    fn skips_entries_larger_than_max_entry_bytes() {
+        let cache = cache(1000);
+        let req = get("/big", &[]);
+        let entry = response(&req, &[], &"x".repeat(65)).unwrap();
+        cache.insert("/big", Arc::new(entry));
+        assert!(!cached(&cache, "/big"));
+    }
+
+    #[test]
+    fn stale_window_follows_the_ttl() {
+        let entry = response(&get("/posts", &[]), &[], "body").unwrap();
+        let at = |secs| entry.stored + Duration::from_secs(secs);
+
+        assert_eq!(entry.freshness_at(at(0)), Freshness::Fresh);
+        assert_eq!(entry.freshness_at(at(9)), Freshness::Fresh);
+        assert_eq!(entry.freshness_at(at(10)), Freshness::Stale);
+        assert_eq!(entry.freshness_at(at(14)), Freshness::Stale);
+        assert_eq!(entry.freshness_at(at(15)), Freshness::Expired);
+    }
+
+    #[test]
+    fn upstream_cache_control_overrides_the_route_policy() {
+        let req = get("/posts", &[]);
+        let cases = [
+            ("max-age=60", 60, 5),
+            ("max-age=60, s-maxage=30", 30, 5),
+            ("max-age=0, stale-while-revalidate=20", 0, 20),
+        ];
+        for (cache_control, fresh, stale) in cases {
+            let entry = response(&req, &[("cache-control", cache_control)], "body").unwrap();
+            assert_eq!(
+                entry.fresh_for,
+                Duration::from_secs(fresh),
+                "{}",
+                cache_control
+            );
+            assert_eq!(
+                entry.stale_for,
+                Duration::from_secs(stale),
+                "{}",
+                cache_control
+            );
+        }
+    }
+
+    #[test]
+    fn purge_drops_keys_under_the_prefix() {
+        let cache = cache(1000);
+        store(&cache, "/api/v1/posts/1");
+        store(&cache, "/api/v1/posts/all?page=2");
+        store(&cache, "/api/v1/user");
+
+        assert_eq!(cache.purge("/api/v1/posts"), 2);
+        assert!(!cached(&cache, "/api/v1/posts/1"));
+        assert!(!cached(&cache, "/api/v1/posts/all?page=2"));
+        assert!(cached(&cache, "/api/v1/user"));
+
+        let store = cache.store.lock().unwrap();
+        assert_eq!((store.bytes, store.lru.len()), (10, 1));
+    }
+
+    #[test]
+    fn concurrent_misses_wait_for_the_first_fetch() {
+        let cache = cache(1000);
+        let flight = cache.begin("/posts").ok().unwrap();
+        let waiting = cache.begin("/posts").err().unwrap();
+        assert!(cache.begin("/other").is_ok());
+
+        drop(flight);
+        assert!(futures::executor::block_on(waiting).is_err());
+        assert!(cache.begin("/posts").is_ok());
+    }
+}
@@ -0,0 +1,425 @@
+use serde::Serialize;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::CircuitBreakerConfig;
+
+// Retry-After hint when a half-open breaker already has its trial requests out
+const HALF_OPEN_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+// Per-upstream circuit breaker.
+//
+// Closed: requests flow; the breaker opens after `consecutive_failures` failures
+// in a row, or when the failure rate over the current window reaches `failure_rate`
+// (once at least `min_requests` have been seen).
+// Open: requests fail fast until `open_secs` have passed.
+// Half-open: up to `half_open_requests` trial requests go through; if they all
+// succeed the breaker closes, any failure re-opens it.
+pub struct CircuitBreaker {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    window_started: Instant,
+    window_requests: u32,
+    window_failures: u32,
+    opened_at: Option<Instant>,
+    half_open_started: u32,
+    half_open_successes: u32,
+}
+
+#[derive(Serialize)]
+pub struct BreakerSnapshot {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    pub window_requests: u32,
+    pub window_failures: u32,
+    pub retry_after_secs: Option<u64>,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        CircuitBreaker {
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                window_started: Instant::now(),
+                window_requests: 0,
+                window_failures: 0,
+                opened_at: None,
+                half_open_started: 0,
+                half_open_successes: 0,
+            }),
+        }
+    }
+
+    // Whether a request could be admitted right now, without claiming a slot.
+    // Used by the balancer to skip upstreams whose breaker is open.
+    pub fn is_available(&self, config: &CircuitBreakerConfig) -> bool {
+        self.is_available_at(config, Instant::now())
+    }
+
+    fn is_available_at(&self, config: &CircuitBreakerConfig, now: Instant) -> bool {
+        if !config.enabled {
+            return true;
+        }
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => inner.retry_after(config, now).is_none(),
+            BreakerState::HalfOpen => inner.half_open_started < config.half_open_requests,
+        }
+    }
+
+    // Admit a request, or return how long the caller should wait before retrying
+    pub fn try_acquire(&self, config: &CircuitBreakerConfig) -> Result<(), Duration> {
+        self.try_acquire_at(config, Instant::now())
+    }
+
+    fn try_acquire_at(&self, config: &CircuitBreakerConfig, now: Instant) -> Result<(), Duration> {
+        if !config.enabled {
+            return Ok(());
+        }
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state == BreakerState::Open {
+            if let Some(wait) = inner.retry_after(config, now) {
+                return Err(wait);
+            }
+            inner.half_open(now);
+        }
+
+        if inner.state == BreakerState::HalfOpen {
+            if inner.half_open_started >= config.half_open_requests {
+                // A trial request that never reported back (e.g. the client went away)
+                // must not wedge the breaker; start a fresh trial after another cooldown
+                let stale = inner.opened_at.is_none_or(|at| {
+                    now.duration_since(at) >= Duration::from_secs(config.open_secs)
+                });
+                if !stale {
+                    return Err(HALF_OPEN_RETRY_AFTER);
+                }
+                inner.half_open(now);
+            }
+            inner.half_open_started += 1;
+        }
+
+        Ok(())
+    }
+
+    // Record the outcome of an admitted request; returns the new state if it changed
+    pub fn record(&self, success: bool, config: &CircuitBreakerConfig) -> Option<BreakerState> {
+        self.record_at(success, config, Instant::now())
+    }
+
+    fn record_at(
+        &self,
+        success: bool,
+        config: &CircuitBreakerConfig,
+        now: Instant,
+    ) -> Option<BreakerState> {
+        if !config.enabled {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            BreakerState::Closed => {
+                if now.duration_since(inner.window_started)
+                    >= Duration::from_secs(config.window_secs)
+                {
+                    inner.window_started = now;
+                    inner.window_requests = 0;
+                    inner.window_failures = 0;
+                }
+                inner.window_requests += 1;
+
+                if success {
+                    inner.consecutive_failures = 0;
+                    return None;
+                }
+
+                inner.window_failures += 1;
+                inner.consecutive_failures += 1;
+
+                let rate_tripped = inner.window_requests >= config.min_requests
+                    && inner.window_failures as f64
+                        >= config.failure_rate * inner.window_requests as f64;
+                if inner.consecutive_failures >= config.consecutive_failures || rate_tripped {
+                    inner.open(now);
+                    return Some(BreakerState::Open);
+                }
+                None
+            }
+            BreakerState::HalfOpen if success => {
+                inner.half_open_successes += 1;
+                if inner.half_open_successes >= config.half_open_requests {
+                    inner.close(now);
+                    return Some(BreakerState::Closed);
+                }
+                None
+            }
+            BreakerState::HalfOpen => {
+                inner.open(now);
+                Some(BreakerState::Open)
+            }
+            // Late result from a request admitted before the breaker opened
+            BreakerState::Open => None,
+        }
+    }
+
+    pub fn retry_after(&self, config: &CircuitBreakerConfig) -> Option<Duration> {
+        self.inner
+            .lock()
+            .unwrap()
+            .retry_after(config, Instant::now())
+    }
+
+    pub fn snapshot(&self, config: &CircuitBreakerConfig) -> BreakerSnapshot {
+        let inner = self.inner.lock().unwrap();
+        BreakerSnapshot {
+            state: inner.state,
+            consecutive_failures: inner.consecutive_failures,
+            window_requests: inner.window_requests,
+            window_failures: inner.window_failures,
+            retry_after_secs: inner
+                .retry_after(config, Instant::now())
+                .map(|d| d.as_secs().max(1)),
+        }
+    }
+}
+
+impl Inner {
+    // Remaining open time, if the breaker is open and still cooling down
+    fn retry_after(&self, config: &CircuitBreakerConfig, now: Instant) -> Option<Duration> {
+        if self.state != BreakerState::Open {
+            return None;
+        }
+        let cooldown = Duration::from_secs(config.open_secs);
+        let elapsed = self
+            .opened_at
+            .map(|at| now.saturating_duration_since(at))
+            .unwrap_or(cooldown);
+        cooldown.checked_sub(elapsed).filter(|d| !d.is_zero())
+    }
+
+    fn open(&mut self, now: Instant) {
+        self.state = BreakerState::Open;
+        self.opened_at = Some(now);
+    }
+
+    fn half_open(&mut self, now: Instant) {
+        self.state = BreakerState::HalfOpen;
+        self.opened_at = Some(now);
+        self.half_open_started = 0;
+        self.half_open_successes = 0;
+    }
+
+    fn close(&mut self, now: Instant) {
+        self.state = BreakerState::Closed;
+        self.consecutive_failures = 0;
+        self.window_started = now;
+        self.window_requests = 0;
+        self.window_failures = 0;
+        self.opened_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            consecutive_failures: 3,
+            failure_rate: 0.5,
+            min_requests: 4,
+            window_secs: 10,
+            open_secs: 10,
+            half_open_requests: 1,
+            ..CircuitBreakerConfig::default()
+        }
+    }
+
+    fn state(breaker: &CircuitBreaker) -> BreakerState {
+        breaker.inner.lock().unwrap().state
+    }
+
+    // A breaker that opened at `start`
+    fn opened(config: &CircuitBreakerConfig, start: Instant) -> CircuitBreaker {
+        let breaker = CircuitBreaker::new();
+        for _ in 0..config.consecutive_failures {
+            breaker.record_at(false, config, start);
+        }
+        assert_eq!(state(&breaker), BreakerState::Open);
+        breaker
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let config = CircuitBreakerConfig {
+            failure_rate: 1.0,
+            ..config()
+        };
+        let (breaker, now) = (CircuitBreaker::new(), Instant::now());
+
+        assert_eq!(breaker.record_at(false, &config, now), None);
+        assert_eq!(breaker.record_at(false, &config, now), None);
+        // A success breaks the run
+        assert_eq!(breaker.record_at(true, &config, now), None);
+        assert_eq!(breaker.record_at(false, &config, now), None);
+        assert_eq!(breaker.record_at(false, &config, now), None);
+        assert!(breaker.is_available_at(&config, now));
+        assert_eq!(
+            breaker.record_at(false, &config, now),
+            Some(BreakerState::Open)
+        );
+        assert!(!breaker.is_available_at(&config, now));
+        assert_eq!(
+            breaker.try_acquire_at(&config, now),
+            Err(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn opens_on_failure_rate_once_min_requests_are_seen() {
+        let config = CircuitBreakerConfig {
+            consecutive_failures: 100,
+            ..config()
+        };
+        let (breaker, now) = (CircuitBreaker::new(), Instant::now());
+
+        for success in [false, true, false] {
+            assert_eq!(breaker.record_at(success, &config, now), None);
+        }
+        // 4 requests, 2 failed: exactly the configured rate
+        assert_eq!(breaker.record_at(true, &config, now), None);
+        assert_eq!(
+            breaker.record_at(false, &config, now),
+            Some(BreakerState::Open)
+        );
+    }
+
+    #[test]
+    fn failure_rate_window_rolls_over() {
+        let config = CircuitBreakerConfig {
+            consecutive_failures: 100,
+            ..config()
+        };
+        let (breaker, start) = (CircuitBreaker::new(), Instant::now());
+
+        breaker.record_at(false, &config, start);
+        breaker.record_at(false, &config, start);
+        // The two failures above fall out of the window
+        let later = start + Duration::from_secs(config.window_secs);
+        for success in [true, true, true, false] {
+            assert_eq!(breaker.record_at(success, &config, later), None);
+        }
+        assert_eq!(state(&breaker), BreakerState::Closed);
+    }
+
+    #[test]
+    fn fails_fast_until_the_cooldown_has_passed() {
+        let (config, start) = (config(), Instant::now());
+        let breaker = opened(&config, start);
+
+        let almost = start + Duration::from_secs(9);
+        assert!(!breaker.is_available_at(&config, almost));
+        assert_eq!(
+            breaker.try_acquire_at(&config, almost),
+            Err(Duration::from_secs(1))
+        );
+        assert_eq!(state(&breaker), BreakerState::Open);
+
+        let cooled = start + Duration::from_secs(10);
+        assert!(breaker.is_available_at(&config, cooled));
+        assert_eq!(breaker.try_acquire_at(&config, cooled), Ok(()));
+        assert_eq!(state(&breaker), BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn half_open_admits_one_trial_and_closes_on_success() {
+        let (config, start) = (config(), Instant::now());
+        let breaker = opened(&config, start);
+        let cooled = start + Duration::from_secs(10);
+
+        assert_eq!(breaker.try_acquire_at(&config, cooled), Ok(()));
+        assert!(!breaker.is_available_at(&config, cooled));
+        assert_eq!(
+            breaker.try_acquire_at(&config, cooled),
+            Err(HALF_OPEN_RETRY_AFTER)
+        );
+
+        assert_eq!(
+            breaker.record_at(true, &config, cooled),
+            Some(BreakerState::Closed)
+        );
+        assert!(breaker.is_available_at(&config, cooled));
+        assert_eq!(breaker.try_acquire_at(&config, cooled), Ok(()));
+        // Closing starts a clean slate
+        assert_eq!(breaker.record_at(false, &config, cooled), None);
+    }
+
+    #[test]
+    fn half_open_failure_reopens_for_another_cooldown() {
+        let (config, start) = (config(), Instant::now());
+        let breaker = opened(&config, start);
+        let cooled = start + Duration::from_secs(10);
+
+        assert_eq!(breaker.try_acquire_at(&config, cooled), Ok(()));
+        assert_eq!(
+            breaker.record_at(false, &config, cooled),
+            Some(BreakerState::Open)
+        );
+        assert_eq!(
+            breaker.try_acquire_at(&config, cooled),
+            Err(Duration::from_secs(10))
+        );
+        assert_eq!(
+            breaker.try_acquire_at(&config, cooled + Duration::from_secs(10)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn unreported_trial_is_replaced_after_a_cooldown() {
+        let (config, start) = (config(), Instant::now());
+        let breaker = opened(&config, start);
+        let cooled = start + Duration::from_secs(10);
+
+        assert_eq!(breaker.try_acquire_at(&config, cooled), Ok(()));
+        let stale = cooled + Duration::from_secs(config.open_secs);
+        assert_eq!(breaker.try_acquire_at(&config, stale), Ok(()));
+        assert_eq!(
+            breaker.try_acquire_at(&config, stale),
+            Err(HALF_OPEN_RETRY_AFTER)
+        );
+    }
+
+    #[test]
+    fn disabled_breaker_never_opens() {
+        let config = CircuitBreakerConfig {
+            enabled: false,
+            ..config()
+        };
+        let (breaker, now) = (CircuitBreaker::new(), Instant::now());
+
+        for _ in 0..10 {
+            assert_eq!(breaker.record_at(false, &config, now), None);
+        }
+        assert!(breaker.is_available_at(&config, now));
+        assert_eq!(breaker.try_acquire_at(&config, now), Ok(()));
+    }
+}
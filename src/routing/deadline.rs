@@ -0,0 +1,104 @@
+use actix_web::http::header::{HeaderMap, HeaderValue};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Absolute deadline (Unix epoch milliseconds) after which the gateway gives up
+// on a request. Services read it to abandon work nobody is waiting for.
+pub const DEADLINE_HEADER: &str = "X-Request-Deadline";
+
+#[derive(Clone)]
+pub struct Deadline {
+    at: Instant,
+    epoch_ms: u64,
+}
+
+fn now_epoch_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl Deadline {
+    // `total` from now, or earlier if the caller already sent a tighter deadline
+    pub fn new(total: Duration, incoming: &HeaderMap) -> Self {
+        let now_ms = now_epoch_ms();
+        let own = now_ms.saturating_add(total.as_millis() as u64);
+        let epoch_ms = incoming
+            .get(DEADLINE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .map_or(own, |theirs| theirs.min(own));
+
+        Deadline {
+            at: Instant::now() + Duration::from_millis(epoch_ms.saturating_sub(now_ms)),
+            epoch_ms,
+        }
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    pub fn header_value(&self) -> HeaderValue {
+        HeaderValue::from(self.epoch_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderName;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn incoming(deadline: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-request-deadline"),
+            HeaderValue::from_str(deadline).unwrap(),
+        );
+        headers
+    }
+
+    fn epoch_ms(deadline: &Deadline) -> u64 {
+        deadline.header_value().to_str().unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn own_deadline_is_total_from_now() {
+        let before = now_epoch_ms();
+        let deadline = Deadline::new(10 * SECOND, &HeaderMap::new());
+        let after = now_epoch_ms();
+
+        assert!((before + 10_000..=after + 10_000).contains(&epoch_ms(&deadline)));
+        assert!(deadline.remaining() <= 10 * SECOND);
+        assert!(deadline.remaining() > 9 * SECOND);
+    }
+
+    #[test]
+    fn a_tighter_incoming_deadline_is_passed_on() {
+        let theirs = now_epoch_ms() + 2_000;
+        let deadline = Deadline::new(10 * SECOND, &incoming(&theirs.to_string()));
+        assert_eq!(epoch_ms(&deadline), theirs);
+        assert!(deadline.remaining() <= 2 * SECOND);
+        assert!(deadline.remaining() > SECOND);
+    }
+
+    #[test]
+    fn looser_or_malformed_incoming_deadlines_are_clamped_to_our_own() {
+        let far = (now_epoch_ms() + 3_600_000).to_string();
+        for theirs in [far.as_str(), "soon", "-5", ""] {
+            let deadline = Deadline::new(SECOND, &incoming(theirs));
+            assert!(epoch_ms(&deadline) <= now_epoch_ms() + 1_000, "{}", theirs);
+            assert!(deadline.remaining() <= SECOND, "{}", theirs);
+        }
+    }
+
+    #[test]
+    fn a_passed_deadline_leaves_no_time() {
+        let theirs = now_epoch_ms() - 5_000;
+        let deadline = Deadline::new(10 * SECOND, &incoming(&theirs.to_string()));
+        assert_eq!(deadline.remaining(), Duration::ZERO);
+        assert_eq!(epoch_ms(&deadline), theirs);
+    }
+}
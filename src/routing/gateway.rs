@@ -1,92 +1,985 @@
-use actix_web::{web, HttpMessage as _, HttpRequest, HttpResponse, Responder};
+use actix_web::{
+    http::{
+        header::{self, HeaderValue},
+        Method, StatusCode,
+    },
+    rt::{self, time},
+    web, HttpMessage as _, HttpRequest, HttpResponse, Responder,
+};
+use futures::future;
 use reqwest::header::HeaderMap;
 use serde_json::json;
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_tungstenite::tungstenite;
+use tracing::{debug, error, warn};
 
 use crate::{
-    auth::Claims,
-    routing::ServiceState,
-    utils::{build_uri, detect_service},
+    auth::{
+        forwarded_query, policy::authorize, Claims, InternalSigner, INTERNAL_ASSERTION_HEADER,
+    },
+    config::{CacheConfig, Protocol},
+    metrics::Metrics,
+    middleware::{
+        access_log::ServedBy,
+        trace::{RequestContext, REQUEST_ID_HEADER, TRACEPARENT_HEADER},
+    },
+    routing::{
+        aggregate::{Aggregate, PartError},
+        body::{buffer_response, is_event_stream, stream_events, stream_response, RequestBody},
+        cache::{self, CachedResponse, Freshness, ResponseCache},
+        deadline::{Deadline, DEADLINE_HEADER},
+        grpc,
+        headers::{copy_response_headers, upstream_request_headers},
+        registry::{InFlight, Registry, Service, Timeouts, Upstream},
+        retry::{backoff, is_retryable_method, is_retryable_outcome},
+        validation::{payload_too_large, BodyRules},
+        websocket, ServiceState,
+    },
+    utils::build_uri,
 };
 
+// Largest body of a single aggregate part
+const AGGREGATE_PART_MAX_BYTES: usize = 1024 * 1024;
+
+// Plain identity headers services used to trust; never passed on from clients
+const USER_ID_HEADER: &str = "X-User-ID";
+const USER_ROLE_HEADER: &str = "X-User-Role";
+
 pub async fn forward_request(
     req: HttpRequest,
-    body: web::Bytes,
+    payload: web::Payload,
     state: web::Data<Arc<ServiceState>>,
+    signer: web::Data<InternalSigner>,
+    metrics: web::Data<Metrics>,
+    cache: web::Data<ResponseCache>,
 ) -> impl Responder {
     let claims = req.extensions().get::<Claims>().cloned();
+    let context = req
+        .extensions()
+        .get::<RequestContext>()
+        .cloned()
+        .unwrap_or_else(|| RequestContext::from_headers(req.headers()));
     let path = req.path();
 
-    let service_name = match detect_service(path) {
+    // Pin the registry for the lifetime of this request so a reload can't swap it mid-flight
+    let registry = state.registry();
+    let identity = Identity {
+        claims: claims.as_ref(),
+        signer: &signer,
+        request_id: &context.request_id,
+    };
+    let headers = upstream_headers(&req, &context);
+    if let Some(aggregate) = registry.aggregate_for(&req) {
+        return forward_aggregate(aggregate, &registry, &req, headers, &identity, &metrics).await;
+    }
+    let service = match registry.detect_service(path) {
         Some(svc) => svc,
         None => return HttpResponse::NotFound().json(json!({ "error": "Service not found" })),
     };
+    let service_name = service.name.as_str();
+
+    let route = service.route_for(&req);
+    let forward = Forward::new(service, &req, headers, &metrics, req.headers());
+    let forward = match forward.asserting(&identity) {
+        Ok(forward) => forward,
+        Err(resp) => return resp,
+    };
+
+    if websocket::is_upgrade(&req) {
+        return forward_websocket(forward, payload).await;
+    }
+    if service.protocol_for(route) == Protocol::Grpc {
+        return forward_grpc(forward, payload, service.body_rules_for(route)).await;
+    }
+
+    if let Some(policy) = route.and_then(|r| r.cache.as_ref()) {
+        if !forward.streaming && cache::is_cacheable_request(&req, claims.is_some()) {
+            return forward_cached(forward, policy, registry.clone(), &metrics, &cache).await;
+        }
+    }
+
+    let rules = service.body_rules_for(route);
+    if let Some(Err(resp)) = rules.map(|rules| rules.check_head(req.headers())) {
+        return resp;
+    }
+    let replayable = service.retry.max_retries > 0 && is_retryable_method(req.method(), req.headers());
+    let body = RequestBody::from_payload(payload, req.method(), req.headers(), replayable, rules);
+    let mut body = match body.await {
+        Ok(body) => body,
+        Err(resp) => return resp,
+    };
+    let attempt = match forward.send(&mut body).await {
+        Ok(attempt) => attempt,
+        Err(resp) => return resp,
+    };
+
+    let res = match (attempt.response, body.exceeded_limit()) {
+        (Ok(resp), _) if forward.streaming => {
+            stream_events(resp, attempt.in_flight, forward.timeouts.idle)
+        }
+        (Ok(resp), _) => stream_response(resp, attempt.in_flight),
+        (Err(_), Some(limit)) => payload_too_large(limit),
+        (Err(err), None) => upstream_error(err, service_name),
+    };
+    with_served_by(res, attempt.served_by)
+}
+
+// Who the request is made for, stated to each upstream service in an assertion
+// addressed to that service alone
+struct Identity<'a> {
+    claims: Option<&'a Claims>,
+    signer: &'a InternalSigner,
+    request_id: &'a str,
+}
+
+impl Identity<'_> {
+    // `headers` plus the assertion for `service`, valid for at least `valid_for`
+    fn assert_to(
+        &self,
+        service: &str,
+        mut headers: HeaderMap,
+        valid_for: Duration,
+    ) -> Result<HeaderMap, HttpResponse> {
+        let assertion = match self
+            .signer
+            .mint(self.claims, self.request_id, service, valid_for)
+        {
+            Ok(token) => token,
+            Err(err) => {
+                error!("failed to sign internal assertion: {}", err);
+                return Err(HttpResponse::InternalServerError()
+                    .json(json!({ "error": "Failed to authorize request" })));
+            }
+        };
+        // Base64url segments, always a valid header value
+        if let Ok(assertion) = HeaderValue::from_str(&assertion) {
+            headers.insert(INTERNAL_ASSERTION_HEADER, assertion);
+        }
+        Ok(headers)
+    }
+}
+
+// Headers for the upstream: the client's own and the trace context. The
+// identity assertion is added per service by `Forward::asserting`.
+fn upstream_headers(req: &HttpRequest, context: &RequestContext) -> HeaderMap {
+    let mut headers = upstream_request_headers(req);
+
+    // Identity headers only ever come from the gateway, never from the client
+    for name in [INTERNAL_ASSERTION_HEADER, USER_ID_HEADER, USER_ROLE_HEADER] {
+        headers.remove(name);
+    }
+
+    // The upstream continues the trace as a child of the gateway's span
+    for (name, value) in [
+        (REQUEST_ID_HEADER, context.request_id.clone()),
+        (TRACEPARENT_HEADER, context.traceparent()),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+// A client request on its way to the service's upstreams, with everything
+// needed to send it again on retries and background cache refreshes
+struct Forward<'a> {
+    service: &'a Service,
+    req: &'a HttpRequest,
+    // As the client sent it, or the path of an aggregate's part
+    client_path: String,
+    // After the service's rewrite rules
+    path: String,
+    query: String,
+    headers: HeaderMap,
+    timeouts: Timeouts,
+    deadline: Deadline,
+    // An event stream: the deadline only bounds the wait for its head
+    streaming: bool,
+    metrics: &'a Metrics,
+}
+
+// Outcome of the attempt whose response goes back to the client
+struct Attempt {
+    response: Result<reqwest::Response, UpstreamError>,
+    in_flight: Option<InFlight>,
+    // Upstream that produced `response`
+    served_by: Option<String>,
+}
+
+impl<'a> Forward<'a> {
+    // `incoming` carries any deadline set by the caller
+    fn new(
+        service: &'a Service,
+        req: &'a HttpRequest,
+        headers: HeaderMap,
+        metrics: &'a Metrics,
+        incoming: &header::HeaderMap,
+    ) -> Self {
+        let query = forwarded_query(req);
+        Self::to(service, req, req.path(), &query, headers, metrics, incoming)
+    }
+
+    // The request sent to `path` and `query` instead of the client's target
+    fn to(
+        service: &'a Service,
+        req: &'a HttpRequest,
+        path: &str,
+        query: &str,
+        mut headers: HeaderMap,
+        metrics: &'a Metrics,
+        incoming: &header::HeaderMap,
+    ) -> Self {
+        let timeouts = service.timeouts_for(service.route_matching(req.method(), path));
+        let deadline = Deadline::new(timeouts.total, incoming);
+        let streaming = is_event_stream(req.headers());
+        // Long-lived connections have no deadline for the service to honour
+        if !streaming && !websocket::is_upgrade(req) {
+            headers.insert(DEADLINE_HEADER, deadline.header_value());
+        }
+
+        Forward {
+            service,
+            req,
+            client_path: path.to_string(),
+            path: service.upstream_path(path),
+            query: query.to_string(),
+            headers,
+            timeouts,
+            deadline,
+            streaming,
+            metrics,
+        }
+    }
+
+    // With the identity assertion for the service, minted once and sent on
+    // every attempt, so it has to outlive the deadline
+    fn asserting(mut self, identity: &Identity<'_>) -> Result<Self, HttpResponse> {
+        let headers = std::mem::take(&mut self.headers);
+        let valid_for = self.deadline.remaining();
+        self.headers = identity.assert_to(&self.service.name, headers, valid_for)?;
+        Ok(self)
+    }
+
+    // Send the request, retrying on other upstreams where allowed. `Err` is a
+    // response for when no upstream could be tried at all.
+    async fn send(&self, body: &mut RequestBody) -> Result<Attempt, HttpResponse> {
+        let (service, req) = (self.service, self.req);
+        let service_name = service.name.as_str();
+        let balance_key = service.balance_key(req.headers(), &self.client_path);
+        let retry = &service.retry;
+        let can_retry = retry.max_retries > 0
+            && is_retryable_method(req.method(), req.headers())
+            && body.is_replayable();
+        service.retry_budget.record_request();
+
+        let mut tried = Vec::new();
+        let mut attempt = 0;
+        // Outcome of the previous attempt, returned as-is if no upstream is left to retry on
+        let mut last = None;
+        loop {
+            let backend = match service.next_backend(balance_key.as_deref(), &tried) {
+                Some(upstream) => upstream,
+                None => match last.take() {
+                    Some(previous) => return Ok(previous),
+                    None => return Err(service_unavailable(service_name, service.retry_after())),
+                },
+            };
+
+            // Another request may have claimed the last half-open trial slot since we picked
+            if let Err(wait) = backend.breaker.try_acquire(&service.circuit_breaker) {
+                match last.take() {
+                    Some(previous) => return Ok(previous),
+                    None => return Err(service_unavailable(service_name, Some(wait))),
+                }
+            }
+
+            let in_flight = backend.track();
+            let uri = build_uri(&backend.url, &self.path, &self.query);
+
+            debug!(service = service_name, upstream = %backend.url, path = %self.path, attempt, "forwarding request");
+
+            let remaining = self.deadline.remaining();
+            let started = Instant::now();
+            let mut request = service
+                .client
+                .request(req.method().clone(), &uri)
+                .headers(self.headers.clone())
+                .body(body.next_attempt());
+            if !self.streaming {
+                request = request.timeout(remaining);
+            }
+            let send = request.send();
+            let response = match time::timeout(self.timeouts.read.min(remaining), send).await {
+                Ok(result) => result.map_err(UpstreamError::from),
+                Err(_) => Err(UpstreamError::Timeout),
+            };
+            self.metrics.record_upstream(
+                service_name,
+                &backend.url,
+                req.method(),
+                &response,
+                started.elapsed(),
+            );
+
+            // An upload cut off at the route's limit is the client's fault, not the upstream's
+            if body.exceeded_limit().is_some() {
+                return Ok(Attempt {
+                    response,
+                    in_flight: Some(in_flight),
+                    served_by: Some(backend.url.clone()),
+                });
+            }
+
+            let success = matches!(&response, Ok(resp) if !resp.status().is_server_error());
+            if let Some(state) = backend.breaker.record(success, &service.circuit_breaker) {
+                warn!(service = service_name, upstream = %backend.url, ?state, "circuit breaker changed state");
+            }
+
+            let delay = backoff(attempt, retry);
+            if can_retry
+                && attempt < retry.max_retries
+                && is_retryable_outcome(&response, retry)
+                && delay < self.deadline.remaining()
+                && service.retry_budget.try_spend(retry)
+            {
+                attempt += 1;
+                debug!(service = service_name, upstream = %backend.url, attempt, "retrying on another upstream");
+                drop(in_flight);
+                last = Some(Attempt {
+                    response,
+                    in_flight: None,
+                    served_by: Some(backend.url.clone()),
+                });
+                tried.push(backend);
+                time::sleep(delay).await;
+                continue;
+            }
+
+            return Ok(Attempt {
+                response,
+                in_flight: Some(in_flight),
+                served_by: Some(backend.url.clone()),
+            });
+        }
+    }
+}
+
+// Upgrade the client connection and relay it to one upstream. The upstream
+// handshake comes first so that its refusal (401, 404, ...) reaches the client
+// as an ordinary response; upgrades are never retried.
+async fn forward_websocket(forward: Forward<'_>, payload: web::Payload) -> HttpResponse {
+    let (service, req) = (forward.service, forward.req);
+    let service_name = service.name.as_str();
+    let (mut res, session, client) = match actix_ws::handle(req, payload) {
+        Ok(handshake) => handshake,
+        Err(err) => return err.error_response(),
+    };
+
+    let backend = match single_backend(service, req) {
+        Ok(upstream) => upstream,
+        Err(wait) => return service_unavailable(service_name, wait),
+    };
+    let in_flight = backend.track();
+    let url = build_uri(
+        &websocket::upstream_url(&backend.url),
+        &forward.path,
+        &forward.query,
+    );
 
-    if claims.is_none() && service_name != "auth" {
-        return HttpResponse::Unauthorized().json(json!({ "error": "Missing or invalid token" }));
+    debug!(service = service_name, upstream = %backend.url, path = %forward.path, "opening WebSocket");
+
+    let connect = websocket::connect(&url, &forward.headers, service.websocket_tls.clone());
+    let wait = forward.timeouts.read.min(forward.deadline.remaining());
+    let outcome = time::timeout(wait, connect).await;
+    let success = match &outcome {
+        Ok(Ok(_)) => true,
+        Ok(Err(tungstenite::Error::Http(resp))) => !resp.status().is_server_error(),
+        _ => false,
+    };
+    if let Some(state) = backend.breaker.record(success, &service.circuit_breaker) {
+        warn!(service = service_name, upstream = %backend.url, ?state, "circuit breaker changed state");
     }
 
-    let backend_url = match state.get_next_backend(service_name) {
-        Some(url) => url,
-        None => {
-            return HttpResponse::InternalServerError().json(json!({
-                "error": format!("Backend not available for service: {service_name}")
-            }))
+    let res = match outcome {
+        Ok(Ok((socket, protocol))) => {
+            if let Some(protocol) = protocol {
+                res.headers_mut()
+                    .insert(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+            }
+            let client = client.aggregate_continuations();
+            rt::spawn(websocket::relay(
+                session,
+                client,
+                socket,
+                forward.timeouts.idle,
+                in_flight,
+            ));
+            res
+        }
+        Ok(Err(tungstenite::Error::Http(resp))) => websocket::rejected(resp),
+        Ok(Err(err)) => HttpResponse::BadGateway().json(json!({
+            "error": format!("Gateway error: {}", err)
+        })),
+        Err(_) => gateway_timeout(service_name),
+    };
+    with_served_by(res, Some(backend.url.clone()))
+}
+
+// gRPC-Web call translated to gRPC over HTTP/2. gRPC-Web clients only make
+// unary and server-streaming calls, so the one request message is buffered.
+async fn forward_grpc(
+    forward: Forward<'_>,
+    payload: web::Payload,
+    rules: Option<&BodyRules>,
+) -> HttpResponse {
+    let (service, req) = (forward.service, forward.req);
+    let service_name = service.name.as_str();
+    let Some(call) = grpc::WebCall::from_headers(req.headers()) else {
+        return HttpResponse::UnsupportedMediaType().json(json!({
+            "error": "Expected a gRPC-Web request (application/grpc-web)"
+        }));
+    };
+    // Built whenever a route of the service speaks gRPC
+    let Some(client) = &service.grpc else {
+        return call.error(grpc::INTERNAL, "gRPC is not enabled for this service");
+    };
+
+    let limit = rules
+        .and_then(|rules| rules.max_bytes)
+        .unwrap_or(grpc::DEFAULT_MAX_MESSAGE_BYTES);
+    let body = match payload.to_bytes_limited(limit).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(_)) => return call.error(grpc::INTERNAL, "failed to read request"),
+        Err(_) => {
+            let message = format!("request larger than {} bytes", limit);
+            return call.error(grpc::RESOURCE_EXHAUSTED, &message);
         }
     };
+    let body = match call.decode(body) {
+        Ok(body) => body,
+        Err(resp) => return resp,
+    };
 
-    let uri = build_uri(backend_url, path, req.query_string());
+    let backend = match single_backend(service, req) {
+        Ok(upstream) => upstream,
+        Err(_) => return call.error(grpc::UNAVAILABLE, "no upstream available"),
+    };
+    let in_flight = backend.track();
+    let uri = build_uri(&backend.url, &forward.path, &forward.query);
+    let request = match call.upstream_request(&uri, &forward.headers, &forward.deadline, body) {
+        Ok(request) => request,
+        Err(err) => return call.error(grpc::INTERNAL, &err.to_string()),
+    };
 
-    #[cfg(debug_assertions)]
-    println!("Forwarding to URI: {}", uri);
+    debug!(service = service_name, upstream = %backend.url, path = %forward.path, "forwarding gRPC call");
 
-    let client = &state.http_client;
-    let mut builder = client.request(req.method().clone(), &uri);
+    let wait = forward.timeouts.read.min(forward.deadline.remaining());
+    let outcome = time::timeout(wait, client.request(request)).await;
+    let success = matches!(&outcome, Ok(Ok(resp)) if !grpc::is_failure(resp));
+    if let Some(state) = backend.breaker.record(success, &service.circuit_breaker) {
+        warn!(service = service_name, upstream = %backend.url, ?state, "circuit breaker changed state");
+    }
 
-    // Forward headers
-    let mut headers = HeaderMap::new();
-    for (key, value) in req.headers().iter() {
-        headers.insert(key.clone(), value.clone());
+    let res = match outcome {
+        Ok(Ok(resp)) => call.response(resp, forward.deadline, in_flight),
+        Ok(Err(err)) => call.error(grpc::UNAVAILABLE, &format!("Gateway error: {}", err)),
+        Err(_) => call.error(grpc::DEADLINE_EXCEEDED, "upstream timed out"),
+    };
+    with_served_by(res, Some(backend.url.clone()))
+}
+
+// Fetch every part of a composite endpoint in parallel and merge the bodies.
+// Parts go through their services like any request (route timeouts,
+// balancing, breakers, retries) and their own access policy, but never the
+// response cache.
+async fn forward_aggregate(
+    aggregate: &Aggregate,
+    registry: &Registry,
+    req: &HttpRequest,
+    mut headers: HeaderMap,
+    identity: &Identity<'_>,
+    metrics: &Metrics,
+) -> HttpResponse {
+    // The parts' bodies are parsed here, not relayed
+    for name in [
+        header::ACCEPT_ENCODING,
+        header::IF_NONE_MATCH,
+        header::IF_MODIFIED_SINCE,
+        header::RANGE,
+    ] {
+        headers.remove(name);
     }
-    headers.insert("Content-Type", "application/json".parse().unwrap());
+    headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
 
-    if let Some(claims) = claims {
-        headers.insert("X-Service-Key", "key_accommodation".parse().unwrap());
-        headers.insert("X-User-ID", claims.sub.parse().unwrap());
-        headers.insert("X-User-Role", claims.role.parse().unwrap());
+    // Handed to the parts as if the client had sent it, so none outlives the aggregate
+    let deadline = Deadline::new(aggregate.total, req.headers());
+    let mut incoming = header::HeaderMap::new();
+    if let Ok(name) = header::HeaderName::try_from(DEADLINE_HEADER) {
+        incoming.insert(name, deadline.header_value());
     }
 
-    headers.insert("x-jwt-secret", "123456789".parse().unwrap());
-    builder = builder.headers(headers);
+    let subject = identity.claims.map(|claims| claims.sub.as_str());
+    let parts = aggregate
+        .targets(req.path(), subject)
+        .into_iter()
+        .map(|target| {
+            let (headers, incoming, deadline) = (headers.clone(), &incoming, &deadline);
+            async move {
+                let (path, query) = target?;
+                let fetch = fetch_part(
+                    registry, req, &path, &query, headers, identity, metrics, incoming,
+                );
+                match time::timeout(deadline.remaining(), fetch).await {
+                    Ok(outcome) => outcome,
+                    Err(_) => Err(PartError {
+                        status: StatusCode::GATEWAY_TIMEOUT,
+                        error: "Upstream timed out".into(),
+                    }),
+                }
+            }
+        });
+    let outcomes = future::join_all(parts).await;
+    aggregate.merge(outcomes)
+}
 
-    let response = builder.body(body).send().await;
+// One part of an aggregate as a JSON value
+#[allow(clippy::too_many_arguments)]
+async fn fetch_part(
+    registry: &Registry,
+    req: &HttpRequest,
+    path: &str,
+    query: &str,
+    headers: HeaderMap,
+    identity: &Identity<'_>,
+    metrics: &Metrics,
+    incoming: &header::HeaderMap,
+) -> Result<serde_json::Value, PartError> {
+    let failed = |status: StatusCode, error: String| PartError { status, error };
+    // Validated against the registry when it was built
+    let Some(service) = registry.detect_service(path) else {
+        return Err(failed(StatusCode::NOT_FOUND, "Service not found".into()));
+    };
+    if let Some(policy) = registry.auth_policy(&Method::GET, path) {
+        let claims = identity.claims.ok_or_else(|| HttpResponse::Unauthorized().finish());
+        if let Err(denied) = authorize(policy, claims) {
+            let status = denied.status();
+            return Err(failed(status, reason(status)));
+        }
+    }
 
-    match response {
-        Ok(resp) => {
-            let status = resp.status();
-            let content_type = resp
-                .headers()
-                .get("Content-Type")
-                .and_then(|v| v.to_str().ok())
-                .unwrap_or("");
+    let forward = Forward::to(service, req, path, query, headers, metrics, incoming)
+        .asserting(identity)
+        .map_err(|resp| failed(resp.status(), reason(resp.status())))?;
+    let mut body = RequestBody::Buffered(web::Bytes::new());
+    let attempt = match forward.send(&mut body).await {
+        Ok(attempt) => attempt,
+        Err(resp) => return Err(failed(resp.status(), reason(resp.status()))),
+    };
+    let resp = match attempt.response {
+        Ok(resp) => resp,
+        Err(err) => {
+            let status = upstream_error(err, &service.name).status();
+            return Err(failed(status, reason(status)));
+        }
+    };
+    let limit = AGGREGATE_PART_MAX_BYTES;
+    let (status, _, body) = buffer_response(resp, attempt.in_flight, limit)
+        .await
+        .map_err(|_| {
+            let error = format!("Response unreadable or larger than {} bytes", limit);
+            failed(StatusCode::BAD_GATEWAY, error)
+        })?;
+    let status = StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let body = serde_json::from_slice::<serde_json::Value>(&body);
+    match body {
+        Ok(body) if status.is_success() => Ok(body),
+        Ok(body) => {
+            // Services answer errors as `{"error": "..."}`
+            let error = body["error"]
+                .as_str()
+                .map_or_else(|| reason(status), str::to_string);
+            Err(failed(status, error))
+        }
+        Err(_) if status.is_success() => Err(failed(
+            StatusCode::BAD_GATEWAY,
+            "Response is not JSON".into(),
+        )),
+        Err(_) => Err(failed(status, reason(status))),
+    }
+}
 
-            if content_type.contains("application/json") {
-                match resp.json::<serde_json::Value>().await {
-                    Ok(json) => HttpResponse::build(status).json(json),
-                    Err(_) => HttpResponse::InternalServerError().json(json!({
-                        "error": "Invalid JSON"
-                    })),
+fn reason(status: StatusCode) -> String {
+    status.canonical_reason().unwrap_or("Error").to_string()
+}
+
+// The upstream for a request that is never retried, or how long until an
+// open breaker lets one through
+fn single_backend(service: &Service, req: &HttpRequest) -> Result<Arc<Upstream>, Option<Duration>> {
+    let balance_key = service.balance_key(req.headers(), req.path());
+    let backend = service
+        .next_backend(balance_key.as_deref(), &[])
+        .ok_or_else(|| service.retry_after())?;
+    backend
+        .breaker
+        .try_acquire(&service.circuit_breaker)
+        .map_err(Some)?;
+    Ok(backend)
+}
+
+// Answer a cacheable request from the cache where possible. A stale entry is
+// served while a background request refreshes it, and concurrent misses for
+// the same key wait for a single upstream fetch.
+async fn forward_cached(
+    forward: Forward<'_>,
+    policy: &CacheConfig,
+    registry: Arc<Registry>,
+    metrics: &web::Data<Metrics>,
+    cache: &web::Data<ResponseCache>,
+) -> HttpResponse {
+    let req = forward.req;
+    let key = cache::cache_key(req);
+    let revalidate = cache::wants_revalidation(req);
+    let cached = cache.lookup(&key, req);
+
+    if let Some(entry) = cached.as_ref().filter(|_| !revalidate) {
+        match entry.freshness() {
+            Freshness::Fresh => return entry.respond(req, cache::HIT),
+            Freshness::Stale => {
+                if let Ok(flight) = cache.begin(&key) {
+                    let (req, headers) = (req.clone(), forward.headers.clone());
+                    let (metrics, cache) = (metrics.clone(), cache.clone());
+                    let (policy, previous, key) = (policy.clone(), entry.clone(), key.clone());
+                    rt::spawn(async move {
+                        let _flight = flight;
+                        let Some(service) = registry.detect_service(req.path()) else {
+                            return;
+                        };
+                        // Nobody is waiting on the refresh, so the client's deadline doesn't apply
+                        let forward =
+                            Forward::new(service, &req, headers, &metrics, &header::HeaderMap::new());
+                        refresh(forward, &cache, &key, &policy, Some(previous)).await;
+                    });
                 }
-            } else {
-                let text = resp
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| "<invalid body>".into());
-                HttpResponse::build(status).body(text)
+                return entry.respond(req, cache::STALE);
             }
+            Freshness::Expired => {}
+        }
+    }
+
+    let _flight = match cache.begin(&key) {
+        Ok(flight) => Some(flight),
+        Err(pending) => {
+            let _ = time::timeout(forward.deadline.remaining(), pending).await;
+            let fetched = cache
+                .lookup(&key, req)
+                .filter(|entry| !revalidate && entry.freshness() == Freshness::Fresh);
+            if let Some(entry) = fetched {
+                return entry.respond(req, cache::HIT);
+            }
+            // That response couldn't be shared; fetch our own
+            None
+        }
+    };
+    refresh(forward, cache, &key, policy, cached).await
+}
+
+// Fetch a cacheable request upstream and store the response if allowed. An
+// entry with validators is revalidated with a conditional request instead.
+async fn refresh(
+    mut forward: Forward<'_>,
+    cache: &ResponseCache,
+    key: &str,
+    policy: &CacheConfig,
+    previous: Option<Arc<CachedResponse>>,
+) -> HttpResponse {
+    let previous = previous.filter(|entry| entry.has_validator());
+    // The client's own validators are checked against the stored entry
+    forward.headers.remove(header::IF_NONE_MATCH);
+    forward.headers.remove(header::IF_MODIFIED_SINCE);
+    for (name, value) in previous.iter().flat_map(|entry| entry.validators()) {
+        forward.headers.insert(name, value);
+    }
+
+    let attempt = match forward.send(&mut RequestBody::Buffered(web::Bytes::new())).await {
+        Ok(attempt) => attempt,
+        Err(resp) => return resp,
+    };
+    let resp = match attempt.response {
+        Ok(resp) => resp,
+        Err(err) => {
+            let res = upstream_error(err, &forward.service.name);
+            return with_served_by(res, attempt.served_by);
         }
-        Err(err) => HttpResponse::InternalServerError().json(json!({
+    };
+
+    let req = forward.req;
+    let mut res = match previous {
+        Some(entry) if resp.status() == StatusCode::NOT_MODIFIED => {
+            let entry = Arc::new(entry.revalidated(resp.headers(), policy));
+            cache.insert(key, entry.clone());
+            entry.respond(req, cache::REVALIDATED)
+        }
+        _ => match buffer_response(resp, attempt.in_flight, cache.max_entry_bytes()).await {
+            Ok((status, headers, body)) => {
+                match CachedResponse::new(status, &headers, body.clone(), req, policy) {
+                    Some(entry) => {
+                        let entry = Arc::new(entry);
+                        cache.insert(key, entry.clone());
+                        entry.respond(req, cache::MISS)
+                    }
+                    None => {
+                        let mut builder = HttpResponse::build(status);
+                        copy_response_headers(&headers, &mut builder);
+                        builder.body(body)
+                    }
+                }
+            }
+            Err(res) => res,
+        },
+    };
+    if !res.headers().contains_key(cache::CACHE_STATUS_HEADER) {
+        res.headers_mut().insert(
+            cache::CACHE_STATUS_HEADER,
+            HeaderValue::from_static(cache::MISS),
+        );
+    }
+    with_served_by(res, attempt.served_by)
+}
+
+fn upstream_error(err: UpstreamError, service_name: &str) -> HttpResponse {
+    match err {
+        UpstreamError::Timeout => gateway_timeout(service_name),
+        // Connect timeouts stay retryable, but still surface as a timeout
+        UpstreamError::Request(err) if err.is_timeout() => gateway_timeout(service_name),
+        // Refused or reset connections: the upstream failed, not the gateway
+        UpstreamError::Request(err) => HttpResponse::BadGateway().json(json!({
             "error": format!("Gateway error: {}", err)
         })),
     }
 }
+
+fn with_served_by(mut res: HttpResponse, served_by: Option<String>) -> HttpResponse {
+    if let Some(url) = served_by {
+        res.extensions_mut().insert(ServedBy(url));
+    }
+    res
+}
+
+fn gateway_timeout(service_name: &str) -> HttpResponse {
+    HttpResponse::GatewayTimeout().json(json!({
+        "error": format!("Upstream timed out for service: {service_name}")
+    }))
+}
+
+// Why an attempt against an upstream produced no response
+pub enum UpstreamError {
+    // Read timeout or the request deadline expired after the request was sent
+    Timeout,
+    Request(reqwest::Error),
+}
+
+impl From<reqwest::Error> for UpstreamError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() && !err.is_connect() {
+            UpstreamError::Timeout
+        } else {
+            UpstreamError::Request(err)
+        }
+    }
+}
+
+fn service_unavailable(service_name: &str, retry_after: Option<Duration>) -> HttpResponse {
+    let mut builder = HttpResponse::ServiceUnavailable();
+    if let Some(wait) = retry_after {
+        // Round up so clients never retry before the breaker is ready
+        let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        builder.insert_header((header::RETRY_AFTER, secs.to_string()));
+    }
+    builder.json(json!({
+        "error": format!("Backend not available for service: {service_name}")
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body, test::TestRequest, App, HttpServer};
+    use serde_json::Value;
+
+    // Serves `/api/v1/user` at once, `/api/v1/posts` after two seconds and
+    // fails `/api/v1/follow/...` with 503
+    fn upstream() -> String {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/api/v1/user",
+                    web::get().to(|| async { HttpResponse::Ok().json(json!({ "id": "42" })) }),
+                )
+                .route(
+                    "/api/v1/posts",
+                    web::get().to(|| async {
+                        time::sleep(Duration::from_secs(2)).await;
+                        HttpResponse::Ok().json(json!([]))
+                    }),
+                )
+                .route(
+                    "/api/v1/follow/counts/{sub}",
+                    web::get().to(|| async {
+                        HttpResponse::ServiceUnavailable()
+                            .json(json!({ "error": "Database unavailable" }))
+                    }),
+                )
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        rt::spawn(server.run());
+        format!("http://{}", addr)
+    }
+
+    fn registry(url: &str, counts_required: bool) -> Registry {
+        let toml = format!(
+            r#"
+            [[services]]
+            name = "user"
+            prefix = "/api/v1/user"
+            upstreams = [{{ url = "{url}" }}]
+
+            [[services]]
+            name = "post"
+            prefix = "/api/v1/posts"
+            upstreams = [{{ url = "{url}" }}]
+
+            [[services]]
+            name = "follow"
+            prefix = "/api/v1/follow"
+            upstreams = [{{ url = "{url}" }}]
+
+            [[aggregates]]
+            path = "/api/v1/profile"
+            total_ms = 500
+            parts = [
+                {{ name = "user", path = "/api/v1/user", required = true }},
+                {{ name = "counts", path = "/api/v1/follow/counts/{{sub}}", required = {counts_required} }},
+                {{ name = "posts", path = "/api/v1/posts?author={{sub}}" }},
+            ]
+            "#
+        );
+        Registry::from_toml(&toml, None).unwrap()
+    }
+
+    async fn profile(registry: &Registry) -> (StatusCode, Value, Duration) {
+        let req = TestRequest::get().uri("/api/v1/profile").to_http_request();
+        let claims = Claims {
+            sub: "42".into(),
+            role: "user".into(),
+            exp: usize::MAX,
+            scope: None,
+            scp: None,
+        };
+        let identity = Identity {
+            claims: Some(&claims),
+            signer: &InternalSigner::new("test-secret"),
+            request_id: "test",
+        };
+        let aggregate = registry.aggregate_for(&req).unwrap();
+        let started = Instant::now();
+        let res = forward_aggregate(
+            aggregate,
+            registry,
+            &req,
+            HeaderMap::new(),
+            &identity,
+            &Metrics::new(),
+        )
+        .await;
+        let elapsed = started.elapsed();
+        let status = res.status();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap(), elapsed)
+    }
+
+    #[actix_web::test]
+    async fn optional_parts_that_fail_or_time_out_are_reported() {
+        let registry = registry(&upstream(), false);
+        let (status, body, elapsed) = profile(&registry).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["user"], json!({ "id": "42" }));
+        assert_eq!(body["counts"], Value::Null);
+        assert_eq!(body["posts"], Value::Null);
+        assert_eq!(
+            body["errors"]["counts"],
+            json!({ "status": 503, "error": "Database unavailable" })
+        );
+        assert_eq!(body["errors"]["posts"]["status"], 504);
+        assert!(body["errors"].get("user").is_none());
+        // The total deadline, not the slow part, bounds the response
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+    }
+
+    #[actix_web::test]
+    async fn a_failed_required_part_fails_the_aggregate() {
+        let registry = registry(&upstream(), true);
+        let (status, body, _) = profile(&registry).await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["user"], json!({ "id": "42" }));
+        assert_eq!(body["counts"], Value::Null);
+        assert_eq!(body["errors"]["counts"]["status"], 503);
+        assert_eq!(body["errors"]["posts"]["status"], 504);
+    }
+
+    #[actix_web::test]
+    async fn assertions_outlive_the_retries_the_deadline_allows() {
+        let registry = Registry::from_toml(
+            r#"
+            [[services]]
+            name = "post"
+            prefix = "/api/v1/posts"
+            upstreams = [{ url = "http://post:8082" }]
+            timeouts = { total_ms = 120000 }
+            retry = { max_retries = 3 }
+
+            [[services]]
+            name = "user"
+            prefix = "/api/v1/user"
+            upstreams = [{ url = "http://user:8081" }]
+            "#,
+            None,
+        )
+        .unwrap();
+        let identity = Identity {
+            claims: None,
+            signer: &InternalSigner::new("test-secret"),
+            request_id: "test",
+        };
+        let metrics = Metrics::new();
+        let lifetime = |path: &str| {
+            let req = TestRequest::get().uri(path).to_http_request();
+            let service = registry.detect_service(path).unwrap();
+            let forward = Forward::new(service, &req, HeaderMap::new(), &metrics, req.headers())
+                .asserting(&identity)
+                .unwrap();
+            let assertion = forward.headers[INTERNAL_ASSERTION_HEADER].to_str().unwrap();
+
+            let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+            validation.set_audience(&[&service.name]);
+            let key = jsonwebtoken::DecodingKey::from_secret(b"test-secret");
+            let claims = jsonwebtoken::decode::<Value>(assertion, &key, &validation)
+                .unwrap()
+                .claims;
+            claims["exp"].as_u64().unwrap() - claims["iat"].as_u64().unwrap()
+        };
+
+        assert_eq!(lifetime("/api/v1/posts/1"), 120);
+        // Shorter deadlines keep the usual lifetime
+        assert_eq!(lifetime("/api/v1/user"), 30);
+    }
+}
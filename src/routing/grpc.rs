@@ -0,0 +1,404 @@
+use actix_web::{
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        StatusCode,
+    },
+    rt::time,
+    web, HttpResponse,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use futures::stream;
+use hyper::{body::HttpBody as _, client::HttpConnector, Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::{io, time::Duration};
+
+use crate::{config::UpstreamTlsConfig, middleware::tls};
+
+use super::{deadline::Deadline, headers::copy_response_headers, registry::InFlight};
+
+// HTTP/2-only client for a service's gRPC upstreams: h2c for `http://`, ALPN h2 for `https://`
+pub type GrpcClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+// Largest request message buffered unless the route's `body.max_bytes` says
+// otherwise; the usual default limit of gRPC servers
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 4 * 1024 * 1024;
+
+// Request headers of gRPC-Web clients, allowed in CORS preflights
+pub const CLIENT_HEADERS: [&str; 3] = ["x-grpc-web", "x-user-agent", "grpc-timeout"];
+
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+// Flag of the gRPC-Web frame that carries the trailers at the end of the body
+const TRAILER_FRAME: u8 = 0x80;
+
+// Status codes used by the gateway itself (https://grpc.io/docs/guides/status-codes/)
+pub const UNKNOWN: u16 = 2;
+pub const DEADLINE_EXCEEDED: u16 = 4;
+pub const RESOURCE_EXHAUSTED: u16 = 8;
+pub const INTERNAL: u16 = 13;
+pub const UNAVAILABLE: u16 = 14;
+
+pub fn client(
+    tls_config: Option<&UpstreamTlsConfig>,
+    connect_timeout: Duration,
+) -> io::Result<GrpcClient> {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(connect_timeout));
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(tls::upstream_client_config(tls_config)?)
+        .https_or_http()
+        .enable_http2()
+        .wrap_connector(http);
+    Ok(Client::builder().http2_only(true).build(connector))
+}
+
+// HTTP status for a gRPC status, so failed calls look like failures to HTTP
+// tooling as well (https://cloud.google.com/apis/design/errors#handling_errors)
+pub fn http_status(code: u16) -> StatusCode {
+    match code {
+        0 => StatusCode::OK,
+        1 => StatusCode::from_u16(499).unwrap_or(StatusCode::BAD_REQUEST),
+        3 | 9 | 11 => StatusCode::BAD_REQUEST,
+        4 => StatusCode::GATEWAY_TIMEOUT,
+        5 => StatusCode::NOT_FOUND,
+        6 | 10 => StatusCode::CONFLICT,
+        7 => StatusCode::FORBIDDEN,
+        8 => StatusCode::TOO_MANY_REQUESTS,
+        12 => StatusCode::NOT_IMPLEMENTED,
+        14 => StatusCode::SERVICE_UNAVAILABLE,
+        16 => StatusCode::UNAUTHORIZED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// gRPC status for an upstream that answered with a plain HTTP error
+// (https://github.com/grpc/grpc/blob/master/doc/http-grpc-status-mapping.md)
+fn from_http(status: StatusCode) -> u16 {
+    match status.as_u16() {
+        400 => INTERNAL,
+        401 => 16,
+        403 => 7,
+        404 => 12,
+        429 | 502 | 503 | 504 => UNAVAILABLE,
+        _ => UNKNOWN,
+    }
+}
+
+// Whether the upstream itself is in trouble, for its circuit breaker
+pub fn is_failure(resp: &hyper::Response<Body>) -> bool {
+    resp.status().is_server_error()
+        || status_of(resp.headers()).is_some_and(|code| code == UNAVAILABLE)
+}
+
+fn status_of(headers: &hyper::HeaderMap) -> Option<u16> {
+    headers
+        .get(GRPC_STATUS)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Binary,
+    // `application/grpc-web-text`: the body is base64
+    Text,
+}
+
+// A gRPC-Web call from a browser client, and how to answer it
+pub struct WebCall {
+    encoding: Encoding,
+    // Echoed back on the response, e.g. `application/grpc-web+proto`
+    content_type: HeaderValue,
+    // Message format, such as `+proto`, kept on the upstream's content type
+    format: String,
+}
+
+impl WebCall {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(header::CONTENT_TYPE)?;
+        let media_type = content_type.to_str().ok()?.split(';').next()?.trim();
+        let media_type = media_type.to_ascii_lowercase();
+        let (encoding, format) =
+            if let Some(format) = media_type.strip_prefix("application/grpc-web-text") {
+                (Encoding::Text, format)
+            } else {
+                (
+                    Encoding::Binary,
+                    media_type.strip_prefix("application/grpc-web")?,
+                )
+            };
+        if !format.is_empty() && !format.starts_with('+') {
+            return None;
+        }
+
+        Some(WebCall {
+            encoding,
+            content_type: content_type.clone(),
+            format: format.to_string(),
+        })
+    }
+
+    // The request's framed message(s), decoded from base64 for text calls
+    pub fn decode(&self, body: web::Bytes) -> Result<web::Bytes, HttpResponse> {
+        match self.encoding {
+            Encoding::Binary => Ok(body),
+            Encoding::Text => {
+                let text: Vec<u8> = body
+                    .into_iter()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .collect();
+                STANDARD
+                    .decode(text)
+                    .map(web::Bytes::from)
+                    .map_err(|_| self.error(INTERNAL, "request body is not valid base64"))
+            }
+        }
+    }
+
+    // The gRPC request for `uri`. `deadline` caps any `grpc-timeout` of the client.
+    pub fn upstream_request(
+        &self,
+        uri: &str,
+        headers: &reqwest::header::HeaderMap,
+        deadline: &Deadline,
+        body: web::Bytes,
+    ) -> Result<hyper::Request<Body>, hyper::http::Error> {
+        let timeout = headers
+            .get(GRPC_TIMEOUT)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_timeout)
+            .map_or(deadline.remaining(), |theirs| {
+                theirs.min(deadline.remaining())
+            });
+
+        let mut request = hyper::Request::post(uri);
+        for (name, value) in headers {
+            let web_only = [header::CONTENT_TYPE, header::CONTENT_LENGTH, header::ACCEPT];
+            if web_only.contains(name) || name == "x-grpc-web" || name == GRPC_TIMEOUT {
+                continue;
+            }
+            request = request.header(name, value);
+        }
+        request
+            .header(
+                header::CONTENT_TYPE,
+                format!("application/grpc{}", self.format),
+            )
+            .header(header::TE, "trailers")
+            .header(GRPC_TIMEOUT, format!("{}m", timeout.as_millis().max(1)))
+            .body(Body::from(body))
+    }
+
+    // Translate the upstream's gRPC response. Messages are relayed as they
+    // arrive and the trailers become the body's final frame; a stream that
+    // outlives `deadline` ends with DEADLINE_EXCEEDED.
+    pub fn response(
+        &self,
+        resp: hyper::Response<Body>,
+        deadline: Deadline,
+        in_flight: InFlight,
+    ) -> HttpResponse {
+        if resp.status() != StatusCode::OK {
+            let message = format!("upstream answered HTTP {}", resp.status().as_u16());
+            return self.error(from_http(resp.status()), &message);
+        }
+
+        let mut builder = HttpResponse::Ok();
+        copy_response_headers(resp.headers(), &mut builder);
+        builder.insert_header((header::CONTENT_TYPE, self.content_type.clone()));
+
+        // Trailers-only response: the status is already in the headers
+        if let Some(code) = status_of(resp.headers()) {
+            builder.status(http_status(code));
+            return builder.finish();
+        }
+
+        let encoding = self.encoding;
+        let state = Some((resp.into_body(), Vec::new(), in_flight));
+        let body = stream::unfold(state, move |state| {
+            let deadline = deadline.clone();
+            async move {
+                let (mut upstream, mut pending, in_flight) = state?;
+                let (chunk, last) = match time::timeout(deadline.remaining(), upstream.data()).await
+                {
+                    Ok(Some(Ok(chunk))) => (chunk.to_vec(), false),
+                    Ok(None) => {
+                        let trailers = match upstream.trailers().await {
+                            Ok(Some(trailers)) if status_of(&trailers).is_some() => trailers,
+                            _ => status_headers(INTERNAL, "upstream sent no grpc-status"),
+                        };
+                        (trailer_frame(&trailers), true)
+                    }
+                    Ok(Some(Err(_))) => (
+                        trailer_frame(&status_headers(UNAVAILABLE, "upstream stream failed")),
+                        true,
+                    ),
+                    Err(_) => (
+                        trailer_frame(&status_headers(DEADLINE_EXCEEDED, "deadline exceeded")),
+                        true,
+                    ),
+                };
+
+                let out = match encoding {
+                    Encoding::Binary => chunk,
+                    // Encode whole 3-byte groups only, so padding can only appear at the end
+                    Encoding::Text => {
+                        pending.extend(chunk);
+                        let whole = if last {
+                            pending.len()
+                        } else {
+                            pending.len() / 3 * 3
+                        };
+                        let rest = pending.split_off(whole);
+                        let encoded = STANDARD.encode(&pending).into_bytes();
+                        pending = rest;
+                        encoded
+                    }
+                };
+                let next = (!last).then_some((upstream, pending, in_flight));
+                Some((Ok::<_, io::Error>(web::Bytes::from(out)), next))
+            }
+        });
+        builder.streaming(body)
+    }
+
+    // Trailers-only gRPC-Web response for a call that failed at the gateway
+    pub fn error(&self, code: u16, message: &str) -> HttpResponse {
+        let mut builder = HttpResponse::build(http_status(code));
+        builder.insert_header((header::CONTENT_TYPE, self.content_type.clone()));
+        for (name, value) in &status_headers(code, message) {
+            builder.insert_header((name.clone(), value.clone()));
+        }
+        builder.finish()
+    }
+}
+
+fn status_headers(code: u16, message: &str) -> hyper::HeaderMap {
+    let mut headers = hyper::HeaderMap::new();
+    headers.insert(
+        HeaderName::from_static(GRPC_STATUS),
+        HeaderValue::from(code),
+    );
+    if let Ok(message) = HeaderValue::from_str(&percent_encode(message)) {
+        headers.insert(HeaderName::from_static(GRPC_MESSAGE), message);
+    }
+    headers
+}
+
+// `grpc-message` is percent-encoded UTF-8
+fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|b| match b {
+            b' '..=b'~' if b != b'%' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Trailers as a gRPC-Web frame: flag, big-endian length, then HTTP/1-style header lines
+fn trailer_frame(trailers: &hyper::HeaderMap) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in trailers {
+        block.extend_from_slice(name.as_str().as_bytes());
+        block.extend_from_slice(b":");
+        block.extend_from_slice(value.as_bytes());
+        block.extend_from_slice(b"\r\n");
+    }
+    let mut frame = Vec::with_capacity(5 + block.len());
+    frame.push(TRAILER_FRAME);
+    frame.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    frame.extend(block);
+    frame
+}
+
+// `grpc-timeout`: up to 8 digits and a unit (H, M, S, m, u, n)
+fn parse_timeout(value: &str) -> Option<Duration> {
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    if amount.is_empty() || amount.len() > 8 || !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_grpc_timeouts_in_every_unit() {
+        let cases = [
+            ("2H", Some(Duration::from_secs(7200))),
+            ("3M", Some(Duration::from_secs(180))),
+            ("15S", Some(Duration::from_secs(15))),
+            ("250m", Some(Duration::from_millis(250))),
+            ("750u", Some(Duration::from_micros(750))),
+            ("999n", Some(Duration::from_nanos(999))),
+            ("0m", Some(Duration::ZERO)),
+            ("99999999S", Some(Duration::from_secs(99_999_999))),
+            // At most 8 digits
+            ("123456789S", None),
+            ("", None),
+            ("S", None),
+            ("10", None),
+            ("10s", None),
+            ("10x", None),
+            ("1.5S", None),
+            ("-1S", None),
+            ("+1S", None),
+            (" 1S", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_timeout(value), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn encodes_trailers_as_a_grpc_web_frame() {
+        let frame = trailer_frame(&status_headers(0, "ok"));
+
+        let block = b"grpc-status:0\r\ngrpc-message:ok\r\n";
+        let mut expected = vec![0x80, 0, 0, 0, block.len() as u8];
+        expected.extend_from_slice(block);
+        assert_eq!(frame, expected);
+    }
+
+    #[test]
+    fn frame_length_is_big_endian() {
+        let mut trailers = hyper::HeaderMap::new();
+        trailers.insert(
+            "x-padding",
+            HeaderValue::from_str(&"a".repeat(300)).unwrap(),
+        );
+        let frame = trailer_frame(&trailers);
+
+        // "x-padding:" + 300 bytes + CRLF = 312 = 0x0138
+        assert_eq!(frame[..5], [0x80, 0x00, 0x00, 0x01, 0x38]);
+        assert_eq!(frame.len(), 5 + 312);
+        assert!(frame.ends_with(b"a\r\n"));
+    }
+
+    #[test]
+    fn empty_trailers_make_an_empty_frame() {
+        assert_eq!(trailer_frame(&hyper::HeaderMap::new()), [0x80, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn percent_encodes_grpc_messages() {
+        let headers = status_headers(DEADLINE_EXCEEDED, "100% done, ça va");
+        assert_eq!(headers[GRPC_STATUS], "4");
+        assert_eq!(headers[GRPC_MESSAGE], "100%25 done, %C3%A7a va");
+    }
+}
@@ -0,0 +1,313 @@
+use actix_web::{
+    http::header::{self, HeaderName, HeaderValue},
+    HttpRequest, HttpResponseBuilder,
+};
+use std::net::{IpAddr, SocketAddr};
+
+// Hop-by-hop headers from RFC 7230 section 6.1, plus the widely used
+// `Proxy-Connection`. They describe a single connection and are never forwarded.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+// End-to-end headers of a message: hop-by-hop headers and any header listed in
+// its `Connection` header are dropped. Generic so it works for both the
+// client's request headers and reqwest's response headers.
+fn end_to_end<'a, H>(headers: H) -> impl Iterator<Item = (&'a HeaderName, &'a HeaderValue)>
+where
+    H: IntoIterator<Item = (&'a HeaderName, &'a HeaderValue)> + Copy,
+{
+    let listed: Vec<HeaderName> = headers
+        .into_iter()
+        .filter(|(name, _)| *name == header::CONNECTION)
+        .filter_map(|(_, v)| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok())
+        .collect();
+
+    headers
+        .into_iter()
+        .filter(move |(name, _)| !HOP_BY_HOP.contains(&name.as_str()) && !listed.contains(name))
+}
+
+// The client's address. Each of the `trusted_hops` proxies in front of the
+// gateway appended the address it saw to `X-Forwarded-For`, so the client is
+// that many entries left of the connection's peer. Entries further left came
+// from the client itself and are ignored.
+pub fn client_ip(req: &HttpRequest, trusted_hops: usize) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if trusted_hops == 0 {
+        return peer;
+    }
+
+    let mut chain: Vec<Option<IpAddr>> = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|entry| {
+            let entry = entry.trim();
+            entry
+                .parse()
+                .or_else(|_| entry.parse::<SocketAddr>().map(|addr| addr.ip()))
+                .ok()
+        })
+        .collect();
+    chain.push(peer);
+
+    // Fewer entries than trusted hops: the leftmost is the closest to the client
+    let index = chain.len().saturating_sub(trusted_hops + 1);
+    chain[index].or(peer)
+}
+
+// Headers to send upstream: end-to-end headers of the client request (content
+// types included) plus the forwarding headers describing the client hop.
+// `Host` is left to the HTTP client so the upstream sees its own authority.
+pub fn upstream_request_headers(req: &HttpRequest) -> reqwest::header::HeaderMap {
+    let incoming = req.headers();
+    let mut headers = reqwest::header::HeaderMap::new();
+    for (name, value) in end_to_end(incoming) {
+        if name != header::HOST {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+
+    let proto = if req.app_config().secure() {
+        "https"
+    } else {
+        "http"
+    };
+    let host = incoming
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| req.uri().authority().map(|a| a.to_string()));
+    let client_ip = req.peer_addr().map(|addr| addr.ip());
+
+    // Append to any chain set by proxies in front of us
+    if let Some(ip) = client_ip {
+        let chain = joined(incoming, X_FORWARDED_FOR)
+            .map_or_else(|| ip.to_string(), |prev| format!("{}, {}", prev, ip));
+        insert(&mut headers, X_FORWARDED_FOR, &chain);
+    }
+    insert(&mut headers, X_FORWARDED_PROTO, proto);
+    if let Some(host) = &host {
+        insert(&mut headers, X_FORWARDED_HOST, host);
+    }
+
+    // RFC 7239: IPv6 addresses and anything with a colon must be quoted
+    let mut element = Vec::new();
+    if let Some(ip) = client_ip {
+        element.push(match ip {
+            std::net::IpAddr::V4(v4) => format!("for={}", v4),
+            std::net::IpAddr::V6(v6) => format!("for=\"[{}]\"", v6),
+        });
+    }
+    element.push(format!("proto={}", proto));
+    if let Some(host) = &host {
+        element.push(format!("host=\"{}\"", host.replace('"', "")));
+    }
+    let forwarded = joined(incoming, header::FORWARDED.as_str()).map_or_else(
+        || element.join(";"),
+        |prev| format!("{}, {}", prev, element.join(";")),
+    );
+    insert(&mut headers, header::FORWARDED.as_str(), &forwarded);
+
+    headers
+}
+
+// Every line of a list header as one value, as a repeated header means the
+// same as its values joined with commas (RFC 7230 section 3.2.2)
+fn joined(headers: &header::HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+fn insert(headers: &mut reqwest::header::HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+// The upstream's end-to-end response headers (cookies, caching, `Location`,
+// ...). `Content-Length` is dropped too: the body is re-framed for the client
+// connection.
+pub fn response_headers(upstream: &reqwest::header::HeaderMap) -> Vec<(HeaderName, HeaderValue)> {
+    end_to_end(upstream)
+        .filter(|(name, _)| *name != header::CONTENT_LENGTH)
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+// Copy the upstream's end-to-end response headers onto the client response
+pub fn copy_response_headers(
+    upstream: &reqwest::header::HeaderMap,
+    builder: &mut HttpResponseBuilder,
+) {
+    for header in response_headers(upstream) {
+        builder.append_header(header);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn request(forwarded_for: &[&str]) -> HttpRequest {
+        let mut req = TestRequest::default().peer_addr("10.0.0.2:4000".parse().unwrap());
+        for value in forwarded_for {
+            req = req.append_header((X_FORWARDED_FOR, *value));
+        }
+        req.to_http_request()
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn client_ip_picks_the_entry_added_by_the_outermost_trusted_proxy() {
+        let cases: &[(&[&str], usize, Option<IpAddr>)] = &[
+            // No trusted proxies: the header is the client's word
+            (&["198.51.100.7"], 0, ip("10.0.0.2")),
+            (&[], 1, ip("10.0.0.2")),
+            (&["198.51.100.7"], 1, ip("198.51.100.7")),
+            // The client prepended its own entry
+            (&["1.1.1.1, 198.51.100.7"], 1, ip("198.51.100.7")),
+            (&["1.1.1.1, 198.51.100.7, 10.0.0.1"], 2, ip("198.51.100.7")),
+            // Repeated headers form one chain
+            (&["1.1.1.1", "198.51.100.7"], 1, ip("198.51.100.7")),
+            // Fewer entries than hops
+            (&["198.51.100.7"], 3, ip("198.51.100.7")),
+            (&["[2001:db8::1]:443"], 1, ip("2001:db8::1")),
+            (&["198.51.100.7:5000"], 1, ip("198.51.100.7")),
+            (&["2001:db8::1"], 1, ip("2001:db8::1")),
+            // Garbage where an address should be: the peer at least exists
+            (&["unknown"], 1, ip("10.0.0.2")),
+        ];
+
+        for (forwarded_for, hops, expected) in cases {
+            assert_eq!(
+                client_ip(&request(forwarded_for), *hops),
+                *expected,
+                "{:?} with {} trusted hops",
+                forwarded_for,
+                hops
+            );
+        }
+    }
+
+    // Hop-by-hop headers, one named by `Connection`, and end-to-end ones
+    const MESSAGE: [(&str, &str); 11] = [
+        ("connection", "close, X-Trace-Hop"),
+        ("keep-alive", "timeout=5"),
+        ("proxy-connection", "keep-alive"),
+        ("proxy-authorization", "Basic c2VjcmV0"),
+        ("te", "trailers"),
+        ("trailer", "Expires"),
+        ("transfer-encoding", "chunked"),
+        ("upgrade", "h2c"),
+        ("x-trace-hop", "1"),
+        ("accept", "application/json"),
+        ("set-cookie", "a=1"),
+    ];
+
+    fn names<'a>(headers: impl IntoIterator<Item = &'a HeaderName>) -> Vec<&'a str> {
+        let mut names: Vec<_> = headers.into_iter().map(HeaderName::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn requests_lose_hop_by_hop_and_connection_listed_headers() {
+        let mut req = TestRequest::default()
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header((header::HOST, "gateway.test"));
+        for header in MESSAGE {
+            req = req.append_header(header);
+        }
+        let forwarded = upstream_request_headers(&req.to_http_request());
+
+        assert_eq!(
+            names(forwarded.keys()),
+            [
+                "accept",
+                "forwarded",
+                "set-cookie",
+                "x-forwarded-for",
+                "x-forwarded-host",
+                "x-forwarded-proto",
+            ]
+        );
+    }
+
+    #[test]
+    fn responses_lose_hop_by_hop_and_connection_listed_headers() {
+        let mut upstream = reqwest::header::HeaderMap::new();
+        for (name, value) in MESSAGE {
+            upstream.append(name, HeaderValue::from_static(value));
+        }
+        upstream.append("set-cookie", HeaderValue::from_static("b=2"));
+        upstream.insert("content-length", HeaderValue::from_static("12"));
+
+        let kept = response_headers(&upstream);
+        assert_eq!(
+            names(kept.iter().map(|(name, _)| name)),
+            ["accept", "set-cookie", "set-cookie"]
+        );
+    }
+
+    #[test]
+    fn repeated_forwarding_headers_keep_every_hop() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header((header::HOST, "gateway.test"))
+            .append_header((X_FORWARDED_FOR, "1.1.1.1, 198.51.100.7"))
+            .append_header((X_FORWARDED_FOR, "10.0.0.1"))
+            .append_header((header::FORWARDED, "for=1.1.1.1"))
+            .append_header((header::FORWARDED, "for=198.51.100.7;proto=https"))
+            .to_http_request();
+        let forwarded = upstream_request_headers(&req);
+
+        assert_eq!(
+            forwarded
+                .get_all(X_FORWARDED_FOR)
+                .iter()
+                .collect::<Vec<_>>(),
+            ["1.1.1.1, 198.51.100.7, 10.0.0.1, 10.0.0.2"]
+        );
+        assert_eq!(
+            forwarded
+                .get_all(header::FORWARDED)
+                .iter()
+                .collect::<Vec<_>>(),
+            [
+                r#"for=1.1.1.1, for=198.51.100.7;proto=https, for=10.0.0.2;proto=http;host="gateway.test""#
+            ]
+        );
+        // The chain the upstream receives names the same client as ours
+        assert_eq!(client_ip(&req, 2), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn client_ip_is_none_without_a_peer_address() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(client_ip(&req, 0), None);
+    }
+}
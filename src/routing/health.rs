@@ -0,0 +1,237 @@
+use actix_web::rt::{self, time};
+use reqwest::Client;
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::config::HealthCheckConfig;
+
+use super::{registry::Upstream, ServiceState};
+
+// How often the checker wakes up to see which upstreams are due for a probe
+const CHECK_TICK: Duration = Duration::from_secs(1);
+
+// Up/down state of an upstream as seen by the active health checker.
+// Upstreams start healthy so traffic flows before the first probe lands.
+pub struct UpstreamHealth {
+    healthy: AtomicBool,
+    consecutive_successes: AtomicU32,
+    consecutive_failures: AtomicU32,
+    last_probe: Mutex<Option<Instant>>,
+}
+
+#[derive(Serialize)]
+pub struct HealthSnapshot {
+    pub healthy: bool,
+    pub consecutive_successes: u32,
+    pub consecutive_failures: u32,
+    pub last_probe_secs_ago: Option<u64>,
+}
+
+impl UpstreamHealth {
+    pub fn new() -> Self {
+        UpstreamHealth {
+            healthy: AtomicBool::new(true),
+            consecutive_successes: AtomicU32::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            last_probe: Mutex::new(None),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> HealthSnapshot {
+        HealthSnapshot {
+            healthy: self.is_healthy(),
+            consecutive_successes: self.consecutive_successes.load(Ordering::Relaxed),
+            consecutive_failures: self.consecutive_failures.load(Ordering::Relaxed),
+            last_probe_secs_ago: self
+                .last_probe
+                .lock()
+                .unwrap()
+                .map(|at| at.elapsed().as_secs()),
+        }
+    }
+
+    // Back to the initial state, for upstreams that are no longer probed
+    pub fn reset(&self) {
+        self.healthy.store(true, Ordering::Relaxed);
+        self.consecutive_successes.store(0, Ordering::Relaxed);
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        *self.last_probe.lock().unwrap() = None;
+    }
+
+    // Returns true (and stamps the probe time) if the upstream is due for a probe
+    fn claim_probe(&self, interval: Duration) -> bool {
+        let mut last_probe = self.last_probe.lock().unwrap();
+        match *last_probe {
+            Some(at) if at.elapsed() < interval => false,
+            _ => {
+                *last_probe = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    // Record a probe result; returns the new state if it flipped
+    pub fn record(&self, passed: bool, check: &HealthCheckConfig) -> Option<bool> {
+        if passed {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            let successes = self.consecutive_successes.fetch_add(1, Ordering::Relaxed) + 1;
+            if !self.is_healthy() && successes >= check.healthy_threshold {
+                self.healthy.store(true, Ordering::Relaxed);
+                return Some(true);
+            }
+        } else {
+            self.consecutive_successes.store(0, Ordering::Relaxed);
+            let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if self.is_healthy() && failures >= check.unhealthy_threshold {
+                self.healthy.store(false, Ordering::Relaxed);
+                return Some(false);
+            }
+        }
+        None
+    }
+}
+
+// Start the background task that probes every upstream of every service with a `health_check`
+pub fn spawn_health_checker(state: Arc<ServiceState>) {
+    rt::spawn(async move {
+        let mut ticker = time::interval(CHECK_TICK);
+
+        loop {
+            ticker.tick().await;
+
+            // Re-read the registry each tick so reloaded services are picked up
+            let registry = state.registry();
+            for service in registry.services() {
+                let Some(check) = &service.health_check else {
+                    continue;
+                };
+                let interval = Duration::from_secs(check.interval_secs);
+
+                for upstream in &service.upstreams {
+                    if upstream.health.claim_probe(interval) {
+                        // Probe with the service's client so its upstream TLS settings apply
+                        rt::spawn(probe(
+                            service.client.clone(),
+                            service.name.clone(),
+                            upstream.clone(),
+                            check.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+    });
+}
+
+async fn probe(client: Client, service: String, upstream: Arc<Upstream>, check: HealthCheckConfig) {
+    let url = format!("{}{}", upstream.url, check.path);
+    let result = client
+        .get(&url)
+        .timeout(Duration::from_millis(check.timeout_ms))
+        .send()
+        .await;
+
+    let passed = matches!(&result, Ok(resp) if !resp.status().is_server_error());
+
+    match upstream.health.record(passed, &check) {
+        Some(true) => info!(service, upstream = %upstream.url, "upstream is healthy again"),
+        Some(false) => match result {
+            Ok(resp) => {
+                warn!(service, upstream = %upstream.url, status = %resp.status(), "upstream marked down")
+            }
+            Err(err) => warn!(service, upstream = %upstream.url, %err, "upstream marked down"),
+        },
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::GatewayConfig, routing::registry::Registry};
+    use std::{fs, path::PathBuf};
+    use tempfile::NamedTempFile;
+
+    const CHECKED: &str = r#"
+        [[services]]
+        name = "post"
+        prefix = "/api/v1/posts"
+        upstreams = [{ url = "http://127.0.0.1:9" }]
+        health_check = { unhealthy_threshold = 2 }
+    "#;
+
+    #[test]
+    fn ejects_after_unhealthy_threshold_and_readmits_after_healthy_threshold() {
+        let check: HealthCheckConfig = toml::from_str("healthy_threshold = 2").unwrap();
+        let health = UpstreamHealth::new();
+        assert!(health.is_healthy());
+
+        for _ in 1..check.unhealthy_threshold {
+            assert_eq!(health.record(false, &check), None);
+        }
+        assert_eq!(health.record(false, &check), Some(false));
+        assert!(!health.is_healthy());
+
+        assert_eq!(health.record(true, &check), None);
+        assert_eq!(health.record(true, &check), Some(true));
+        assert!(health.is_healthy());
+    }
+
+    #[test]
+    fn removing_the_health_check_readmits_ejected_upstreams_once_reloaded() {
+        let file = NamedTempFile::new().unwrap();
+        fs::write(file.path(), CHECKED).unwrap();
+        let config = GatewayConfig::load(file.path()).unwrap();
+        let state = ServiceState::new(PathBuf::from(file.path()), &config).unwrap();
+
+        let checked = state.registry();
+        let service = &checked.services()[0];
+        let check = service.health_check.clone().unwrap();
+        let upstream = &service.upstreams[0];
+        upstream.health.record(false, &check);
+        upstream.health.record(false, &check);
+        assert!(service.next_backend(None, &[]).is_none());
+
+        // A reload that fails after the upstreams were reused changes nothing
+        let unchecked = CHECKED.replace("health_check = { unhealthy_threshold = 2 }", "");
+        let broken = format!(
+            "{}\n[[aggregates]]\npath = \"/profile\"\nparts = [{{ name = \"x\", path = \"/nowhere\" }}]\n",
+            unchecked
+        );
+        fs::write(file.path(), broken).unwrap();
+        assert!(state.reload().is_err());
+        assert!(!upstream.health.is_healthy());
+
+        fs::write(file.path(), unchecked).unwrap();
+        state.reload().unwrap();
+        let reloaded = state.registry();
+        let service = &reloaded.services()[0];
+        assert!(Arc::ptr_eq(&service.upstreams[0], upstream));
+        assert!(Arc::ptr_eq(&service.retry_budget, &checked.services()[0].retry_budget));
+        assert!(upstream.health.is_healthy());
+        assert!(service.next_backend(None, &[]).is_some());
+    }
+
+    #[test]
+    fn keeping_the_health_check_keeps_ejected_upstreams_out() {
+        let checked = Registry::from_toml(CHECKED, None).unwrap();
+        let service = &checked.services()[0];
+        let check = service.health_check.clone().unwrap();
+        service.upstreams[0].health.record(false, &check);
+        service.upstreams[0].health.record(false, &check);
+
+        let reloaded = Registry::from_toml(CHECKED, Some(&checked)).unwrap();
+        assert!(reloaded.services()[0].next_backend(None, &[]).is_none());
+    }
+}
@@ -1,9 +1,426 @@
-// Define backends for different services
-pub const AUTH_BACKENDS: [&str; 1] = ["http://localhost:8081"];
-pub const USER_BACKENDS: [&str; 1] = ["http://localhost:8080"];
-pub const FOLLOW_BACKENDS: [&str; 1] = ["http://localhost:8085"];
-pub const POST_BACKENDS: [&str; 1] = ["http://localhost:8082"];
-pub const COMMENT_BACKENDS: [&str; 1] = ["http://localhost:8083"];
-pub const VOTE_BACKENDS: [&str; 1] = ["http://localhost:8084"];
-pub const PROPERTY_BACKENDS: [&str; 1] = ["http://localhost:8081"];
-pub const ORDER_BACKENDS: [&str; 2] = ["http://localhost:8085", "http://localhost:8086"];
+use actix_web::{dev::ResourceDef, http::header::HeaderMap};
+use rand::Rng;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use crate::config::{check_path_pattern, LoadBalancerConfig, Strategy};
+
+use super::registry::Upstream;
+
+// Virtual nodes per unit of weight on the consistent-hash ring
+const RING_REPLICAS: u32 = 64;
+
+// Picks one upstream index out of a service's upstream list.
+// `eligible` filters out upstreams that must not receive traffic (e.g. marked down).
+pub trait LoadBalancer: Send + Sync {
+    fn pick(
+        &self,
+        upstreams: &[Arc<Upstream>],
+        key: Option<&str>,
+        eligible: &dyn Fn(usize) -> bool,
+    ) -> Option<usize>;
+}
+
+pub fn from_config(
+    config: &LoadBalancerConfig,
+    upstreams: &[(String, u32)],
+) -> Box<dyn LoadBalancer> {
+    let weights: Vec<u32> = upstreams.iter().map(|(_, weight)| *weight).collect();
+
+    match config.strategy {
+        Strategy::RoundRobin => Box::new(RoundRobin::new()),
+        Strategy::WeightedRoundRobin => Box::new(WeightedRoundRobin::new(&weights)),
+        Strategy::LeastOutstanding => Box::new(LeastOutstanding::new()),
+        Strategy::RandomTwoChoices => Box::new(RandomTwoChoices),
+        Strategy::ConsistentHash => Box::new(ConsistentHash::new(upstreams)),
+    }
+}
+
+// Plain rotation, ignoring weights
+pub struct RoundRobin {
+    counter: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        RoundRobin {
+            counter: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl LoadBalancer for RoundRobin {
+    fn pick(
+        &self,
+        upstreams: &[Arc<Upstream>],
+        _key: Option<&str>,
+        eligible: &dyn Fn(usize) -> bool,
+    ) -> Option<usize> {
+        let len = upstreams.len();
+        let start = self.counter.fetch_add(1, Ordering::SeqCst);
+        (0..len).map(|i| (start + i) % len).find(|&i| eligible(i))
+    }
+}
+
+// Smooth weighted round-robin (as in nginx): spreads heavier upstreams evenly
+// through the cycle instead of sending them bursts of consecutive requests
+pub struct WeightedRoundRobin {
+    weights: Vec<i64>,
+    current: Mutex<Vec<i64>>,
+}
+
+impl WeightedRoundRobin {
+    pub fn new(weights: &[u32]) -> Self {
+        WeightedRoundRobin {
+            weights: weights.iter().map(|w| *w as i64).collect(),
+            current: Mutex::new(vec![0; weights.len()]),
+        }
+    }
+}
+
+impl LoadBalancer for WeightedRoundRobin {
+    fn pick(
+        &self,
+        _upstreams: &[Arc<Upstream>],
+        _key: Option<&str>,
+        eligible: &dyn Fn(usize) -> bool,
+    ) -> Option<usize> {
+        let mut current = self.current.lock().unwrap();
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+
+        for (i, weight) in self.weights.iter().enumerate() {
+            if !eligible(i) {
+                continue;
+            }
+            current[i] += weight;
+            total += weight;
+            if best.is_none_or(|b| current[i] > current[b]) {
+                best = Some(i);
+            }
+        }
+
+        let best = best?;
+        current[best] -= total;
+        Some(best)
+    }
+}
+
+// Upstream with the fewest requests currently in flight through this gateway.
+// Ties rotate so idle upstreams share load evenly.
+pub struct LeastOutstanding {
+    counter: AtomicUsize,
+}
+
+impl LeastOutstanding {
+    pub fn new() -> Self {
+        LeastOutstanding {
+            counter: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl LoadBalancer for LeastOutstanding {
+    fn pick(
+        &self,
+        upstreams: &[Arc<Upstream>],
+        _key: Option<&str>,
+        eligible: &dyn Fn(usize) -> bool,
+    ) -> Option<usize> {
+        let len = upstreams.len();
+        let start = self.counter.fetch_add(1, Ordering::SeqCst);
+
+        (0..len)
+            .map(|i| (start + i) % len)
+            .filter(|&i| eligible(i))
+            .min_by_key(|&i| upstreams[i].in_flight())
+    }
+}
+
+// "Power of two random choices": sample two upstreams, keep the less loaded one
+pub struct RandomTwoChoices;
+
+impl LoadBalancer for RandomTwoChoices {
+    fn pick(
+        &self,
+        upstreams: &[Arc<Upstream>],
+        _key: Option<&str>,
+        eligible: &dyn Fn(usize) -> bool,
+    ) -> Option<usize> {
+        let candidates: Vec<usize> = (0..upstreams.len()).filter(|&i| eligible(i)).collect();
+        let mut rng = rand::thread_rng();
+
+        match candidates.len() {
+            0 => None,
+            1 => Some(candidates[0]),
+            len => {
+                let a = rng.gen_range(0..len);
+                let b = (a + rng.gen_range(1..len)) % len;
+                let (a, b) = (candidates[a], candidates[b]);
+                if upstreams[b].in_flight() < upstreams[a].in_flight() {
+                    Some(b)
+                } else {
+                    Some(a)
+                }
+            }
+        }
+    }
+}
+
+// Hash ring keyed on upstream URL, so the same key keeps landing on the same
+// upstream across gateway replicas and when other upstreams come and go
+pub struct ConsistentHash {
+    ring: Vec<(u64, usize)>,
+}
+
+impl ConsistentHash {
+    pub fn new(upstreams: &[(String, u32)]) -> Self {
+        let mut ring: Vec<(u64, usize)> = upstreams
+            .iter()
+            .enumerate()
+            .flat_map(|(index, (url, weight))| {
+                (0..RING_REPLICAS * weight).map(move |replica| {
+                    (ring_hash(format!("{}#{}", url, replica).as_bytes()), index)
+                })
+            })
+            .collect();
+        ring.sort_unstable();
+
+        ConsistentHash { ring }
+    }
+}
+
+impl LoadBalancer for ConsistentHash {
+    fn pick(
+        &self,
+        _upstreams: &[Arc<Upstream>],
+        key: Option<&str>,
+        eligible: &dyn Fn(usize) -> bool,
+    ) -> Option<usize> {
+        let hash = ring_hash(key.unwrap_or_default().as_bytes());
+        let start = self.ring.partition_point(|(point, _)| *point < hash);
+
+        // Walk clockwise until we hit an upstream that can take the request
+        (0..self.ring.len())
+            .map(|i| self.ring[(start + i) % self.ring.len()].1)
+            .find(|&index| eligible(index))
+    }
+}
+
+// FNV-1a with a murmur3 finalizer so short, similar keys still spread over the ring.
+// Stable across processes and Rust versions, unlike `DefaultHasher`.
+fn ring_hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+// Where the consistent-hash key comes from: `header:<name>` or `path:<pattern>`
+pub enum HashKey {
+    Header(String),
+    Path(ResourceDef),
+}
+
+impl HashKey {
+    pub fn parse(spec: &str) -> Option<Self> {
+        if let Some(name) = spec.strip_prefix("header:") {
+            Some(HashKey::Header(name.to_string()))
+        } else {
+            // Checked by the config validation; never panic on one that wasn't
+            spec.strip_prefix("path:")
+                .filter(|pattern| check_path_pattern(pattern).is_ok())
+                .map(|pattern| HashKey::Path(ResourceDef::new(pattern)))
+        }
+    }
+
+    // Extract the key from a request's headers and path, falling back to the path
+    pub fn extract(&self, headers: &HeaderMap, path: &str) -> String {
+        let key = match self {
+            HashKey::Header(name) => headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            HashKey::Path(def) => {
+                let mut captured = actix_web::dev::Path::new(path);
+                def.capture_match_info(&mut captured).then(|| {
+                    captured
+                        .iter()
+                        .map(|(_, value)| value)
+                        .collect::<Vec<_>>()
+                        .join("/")
+                })
+            }
+        };
+
+        key.unwrap_or_else(|| path.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::registry::Registry;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn registry(strategy: &str, upstreams: usize) -> Registry {
+        let upstreams: Vec<_> = (0..upstreams)
+            .map(|i| format!("{{ url = \"http://post-{}:8082\" }}", i))
+            .collect();
+        let toml = format!(
+            r#"
+            [[services]]
+            name = "post"
+            prefix = "/api/v1/posts"
+            upstreams = [{}]
+            load_balancer = {{ strategy = "{}" }}
+            "#,
+            upstreams.join(", "),
+            strategy
+        );
+        Registry::from_toml(&toml, None).unwrap()
+    }
+
+    fn upstreams(count: usize) -> Vec<Arc<Upstream>> {
+        registry("round_robin", count).services()[0]
+            .upstreams
+            .clone()
+    }
+
+    fn picks(balancer: &dyn LoadBalancer, upstreams: &[Arc<Upstream>], n: usize) -> Vec<usize> {
+        (0..n)
+            .map(|_| balancer.pick(upstreams, None, &|_| true).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn weighted_round_robin_interleaves_the_heavy_upstream() {
+        let balancer = WeightedRoundRobin::new(&[5, 1, 1]);
+        let upstreams = upstreams(3);
+        let cycle = [0, 0, 1, 0, 2, 0, 0];
+        assert_eq!(picks(&balancer, &upstreams, 7), cycle);
+        assert_eq!(picks(&balancer, &upstreams, 7), cycle);
+
+        // Ineligible upstreams neither get picked nor accumulate credit
+        let only_light = |i: usize| i != 0;
+        let picked: Vec<_> = (0..4)
+            .map(|_| balancer.pick(&upstreams, None, &only_light).unwrap())
+            .collect();
+        assert_eq!(picked, [1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn skips_ejected_open_and_drained_upstreams() {
+        let registry = registry("round_robin", 4);
+        let service = &registry.services()[0];
+        let check = toml::from_str("unhealthy_threshold = 1").unwrap();
+        service.upstreams[0].health.record(false, &check);
+        for _ in 0..service.circuit_breaker.consecutive_failures {
+            service.upstreams[1]
+                .breaker
+                .record(false, &service.circuit_breaker);
+        }
+        service.upstreams[2].set_drained(true);
+
+        for _ in 0..8 {
+            let picked = service.next_backend(None, &[]).unwrap();
+            assert!(Arc::ptr_eq(&picked, &service.upstreams[3]));
+        }
+        // Already tried, but the only one left
+        let tried = [service.upstreams[3].clone()];
+        let picked = service.next_backend(None, &tried).unwrap();
+        assert!(Arc::ptr_eq(&picked, &service.upstreams[3]));
+
+        service.upstreams[3].set_drained(true);
+        assert!(service.next_backend(None, &[]).is_none());
+    }
+
+    #[test]
+    fn least_outstanding_prefers_idle_upstreams_and_rotates_ties() {
+        let balancer = LeastOutstanding::new();
+        let upstreams = upstreams(3);
+        assert_eq!(picks(&balancer, &upstreams, 6), [0, 1, 2, 0, 1, 2]);
+
+        let _busy = upstreams[1].track();
+        assert_eq!(picks(&balancer, &upstreams, 4), [0, 2, 2, 0]);
+    }
+
+    #[test]
+    fn two_choices_with_one_candidate_picks_it() {
+        let upstreams = upstreams(3);
+        let _busy = upstreams[2].track();
+        for _ in 0..20 {
+            assert_eq!(
+                RandomTwoChoices.pick(&upstreams, None, &|i| i == 2),
+                Some(2)
+            );
+        }
+        assert_eq!(RandomTwoChoices.pick(&upstreams, None, &|_| false), None);
+
+        // Of two, the less loaded one always wins
+        let picked = RandomTwoChoices.pick(&upstreams, None, &|i| i != 0);
+        assert_eq!(picked, Some(1));
+    }
+
+    #[test]
+    fn consistent_hash_only_moves_the_keys_of_an_ejected_upstream() {
+        let upstreams = upstreams(3);
+        let weighted: Vec<_> = upstreams.iter().map(|u| (u.url.clone(), 1)).collect();
+        let ring = ConsistentHash::new(&weighted);
+        let keys: Vec<String> = (0..300).map(|i| format!("user-{}", i)).collect();
+        let pick = |key: &str, eligible: &dyn Fn(usize) -> bool| {
+            ring.pick(&upstreams, Some(key), eligible).unwrap()
+        };
+
+        let before: Vec<_> = keys.iter().map(|key| pick(key, &|_| true)).collect();
+        assert!((0..3).all(|i| before.contains(&i)));
+        // Same ring from the same URLs, as on another gateway replica
+        let replica = ConsistentHash::new(&weighted);
+        for (key, upstream) in keys.iter().zip(&before) {
+            assert_eq!(
+                replica.pick(&upstreams, Some(key), &|_| true),
+                Some(*upstream)
+            );
+        }
+
+        for (key, upstream) in keys.iter().zip(&before) {
+            let after = pick(key, &|i| i != 1);
+            if *upstream == 1 {
+                assert_ne!(after, 1, "{}", key);
+            } else {
+                assert_eq!(after, *upstream, "{}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn hash_keys_fall_back_to_the_path() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-tenant"),
+            HeaderValue::from_static("acme"),
+        );
+        let by_header = HashKey::parse("header:x-tenant").unwrap();
+        let by_other_header = HashKey::parse("header:x-user").unwrap();
+        let by_path = HashKey::parse("path:/api/v1/posts/{id}").unwrap();
+
+        let cases = [
+            (&by_header, "/api/v1/posts/7", "acme"),
+            (&by_other_header, "/api/v1/posts/7", "/api/v1/posts/7"),
+            (&by_path, "/api/v1/posts/7", "7"),
+            (&by_path, "/api/v1/posts", "/api/v1/posts"),
+        ];
+        for (key, path, expected) in cases {
+            assert_eq!(key.extract(&headers, path), expected, "{}", path);
+        }
+
+        assert!(HashKey::parse("path:/api/v1/posts/{id").is_none());
+        assert!(HashKey::parse("cookie:session").is_none());
+    }
+}
@@ -1,81 +1,68 @@
-use reqwest::Client;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
 
+use crate::config::{ConfigError, GatewayConfig};
+use registry::Registry;
+
+pub mod aggregate;
+pub mod body;
+pub mod cache;
+pub mod circuit_breaker;
+pub mod deadline;
 pub mod gateway;
+pub mod grpc;
+pub mod headers;
+pub mod health;
 pub mod load_balancer;
+pub mod registry;
+pub mod reload;
+pub mod retry;
+pub mod rewrite;
+pub mod route;
+pub mod validation;
+pub mod websocket;
 
-// Atomic counter for round-robin load balancing per service
+// Shared state for the proxy: the swappable service registry
 pub struct ServiceState {
-    pub http_client: Client,
-    pub auth_counter: AtomicUsize,
-    pub user_counter: AtomicUsize,
-    pub follow_counter: AtomicUsize,
-    pub comment_counter: AtomicUsize,
-    pub vote_counter: AtomicUsize,
-    pub post_counter: AtomicUsize,
-    pub property_counter: AtomicUsize,
-    pub order_counter: AtomicUsize,
+    config_path: PathBuf,
+    registry: RwLock<Arc<Registry>>,
+    // Serializes reloads so two of them don't build from the same registry
+    reloading: Mutex<()>,
 }
 
 impl ServiceState {
-    pub fn new() -> Self {
-        ServiceState {
-            http_client: Client::new(),
-            auth_counter: AtomicUsize::new(0),
-            user_counter: AtomicUsize::new(0),
-            follow_counter: AtomicUsize::new(0),
-            comment_counter: AtomicUsize::new(0),
-            vote_counter: AtomicUsize::new(0),
-            post_counter: AtomicUsize::new(0),
-            property_counter: AtomicUsize::new(0),
-            order_counter: AtomicUsize::new(0),
-        }
+    pub fn new(config_path: PathBuf, config: &GatewayConfig) -> Result<Self, ConfigError> {
+        Ok(ServiceState {
+            config_path,
+            registry: RwLock::new(Arc::new(Registry::from_config(config, None)?)),
+            reloading: Mutex::new(()),
+        })
+    }
+
+    // Snapshot of the current registry; requests keep using it even if a reload swaps it out
+    pub fn registry(&self) -> Arc<Registry> {
+        self.registry.read().unwrap().clone()
+    }
+
+    // Re-read the config file and atomically swap in the new registry.
+    // On error the current registry stays in place, upstream state included.
+    // The new registry (HTTP clients, certificates) is built without holding
+    // the lock requests take.
+    pub fn reload(&self) -> Result<usize, ConfigError> {
+        let _reloading = self.reloading.lock().unwrap();
+        let config = GatewayConfig::load(&self.config_path)?;
+        let current = self.registry();
+        let registry = Arc::new(Registry::from_config(&config, Some(&current))?);
+        let services = registry.service_count();
+
+        *self.registry.write().unwrap() = registry.clone();
+        registry.reset_unchecked_health();
+        Ok(services)
     }
 
-    // Select the next backend for each service type using round-robin
-    pub fn get_next_backend(&self, service: &str) -> Option<&'static str> {
-        match service {
-            "auth" => {
-                let index = self.auth_counter.fetch_add(1, Ordering::SeqCst)
-                    % load_balancer::AUTH_BACKENDS.len();
-                Some(load_balancer::AUTH_BACKENDS[index])
-            }
-            "user" => {
-                let index = self.user_counter.fetch_add(1, Ordering::SeqCst)
-                    % load_balancer::USER_BACKENDS.len();
-                Some(load_balancer::USER_BACKENDS[index])
-            }
-            "follow" => {
-                let index = self.follow_counter.fetch_add(1, Ordering::SeqCst)
-                    % load_balancer::FOLLOW_BACKENDS.len();
-                Some(load_balancer::FOLLOW_BACKENDS[index])
-            }
-            "post" => {
-                let index = self.post_counter.fetch_add(1, Ordering::SeqCst)
-                    % load_balancer::POST_BACKENDS.len();
-                Some(load_balancer::POST_BACKENDS[index])
-            }
-            "comment" => {
-                let index = self.comment_counter.fetch_add(1, Ordering::SeqCst)
-                    % load_balancer::COMMENT_BACKENDS.len();
-                Some(load_balancer::COMMENT_BACKENDS[index])
-            }
-            "vote" => {
-                let index = self.vote_counter.fetch_add(1, Ordering::SeqCst)
-                    % load_balancer::VOTE_BACKENDS.len();
-                Some(load_balancer::VOTE_BACKENDS[index])
-            }
-            "property" => {
-                let index = self.property_counter.fetch_add(1, Ordering::SeqCst)
-                    % load_balancer::PROPERTY_BACKENDS.len();
-                Some(load_balancer::PROPERTY_BACKENDS[index])
-            }
-            "order" => {
-                let index = self.order_counter.fetch_add(1, Ordering::SeqCst)
-                    % load_balancer::ORDER_BACKENDS.len();
-                Some(load_balancer::ORDER_BACKENDS[index])
-            }
-            _ => None,
-        }
+    pub fn config_path(&self) -> &PathBuf {
+        &self.config_path
     }
 }
@@ -0,0 +1,541 @@
+use actix_web::{
+    http::{header::HeaderMap, Method},
+    HttpRequest,
+};
+use reqwest::Client;
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    config::{
+        AuthPolicy, CircuitBreakerConfig, ConfigError, GatewayConfig, HealthCheckConfig, Protocol,
+        RateLimitConfig, RetryConfig, ServiceConfig, Strategy, TimeoutConfig, UpstreamTlsConfig,
+        DEFAULT_CONNECT_TIMEOUT_MS, DEFAULT_IDLE_TIMEOUT_MS, DEFAULT_READ_TIMEOUT_MS,
+        DEFAULT_TOTAL_TIMEOUT_MS,
+    },
+    middleware::tls,
+};
+
+use super::{
+    aggregate::Aggregate,
+    circuit_breaker::CircuitBreaker,
+    grpc::{self, GrpcClient},
+    health::UpstreamHealth,
+    load_balancer::{self, HashKey, LoadBalancer},
+    retry::RetryBudget,
+    rewrite::Rewrite,
+    route::Route,
+    validation::BodyRules,
+};
+
+// Routing table built from the `[[services]]` entries of the gateway config
+pub struct Registry {
+    services: Vec<Service>,
+    // Rate limit for services and routes that don't set their own
+    rate_limit: Option<RateLimitConfig>,
+    // SHA-256 digests of the API keys `api_key` rate limits count separately
+    api_keys: HashSet<String>,
+    aggregates: Vec<Aggregate>,
+}
+
+pub struct Service {
+    pub name: String,
+    pub prefix: String,
+    pub aliases: Vec<String>,
+    pub upstreams: Vec<Arc<Upstream>>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub retry: RetryConfig,
+    // Kept across reloads, like the upstreams' state
+    pub retry_budget: Arc<RetryBudget>,
+    pub timeouts: TimeoutConfig,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub auth: AuthPolicy,
+    pub body: Option<BodyRules>,
+    pub routes: Vec<Route>,
+    rewrites: Vec<Rewrite>,
+    // Per service so connect timeout and TLS settings can differ between services
+    pub client: Client,
+    // TLS settings for `wss://` WebSocket upstreams; the public roots when unset
+    pub websocket_tls: Option<Arc<rustls::ClientConfig>>,
+    pub protocol: Protocol,
+    // Only for services with gRPC routes
+    pub grpc: Option<GrpcClient>,
+    pub strategy: Strategy,
+    balancer: Box<dyn LoadBalancer>,
+    hash_key: Option<HashKey>,
+}
+
+// Effective timeouts for one request after route and service overrides
+pub struct Timeouts {
+    pub read: Duration,
+    pub total: Duration,
+    pub idle: Duration,
+}
+
+// A single backend instance. Shared between registry generations so that
+// runtime state such as health survives a config reload.
+pub struct Upstream {
+    pub url: String,
+    pub health: UpstreamHealth,
+    pub breaker: CircuitBreaker,
+    in_flight: AtomicUsize,
+    // Taken out of rotation through the admin API; requests in flight finish
+    drained: AtomicBool,
+}
+
+// Counts a request against its upstream until dropped
+pub struct InFlight(Arc<Upstream>);
+
+impl Upstream {
+    fn new(url: &str) -> Self {
+        Upstream {
+            url: url.to_string(),
+            health: UpstreamHealth::new(),
+            breaker: CircuitBreaker::new(),
+            in_flight: AtomicUsize::new(0),
+            drained: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_drained(&self) -> bool {
+        self.drained.load(Ordering::Relaxed)
+    }
+
+    pub fn set_drained(&self, drained: bool) {
+        self.drained.store(drained, Ordering::Relaxed);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn track(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Service {
+    fn from_config(
+        config: &ServiceConfig,
+        previous: Option<&Service>,
+    ) -> Result<Self, ConfigError> {
+        let upstreams: Vec<Arc<Upstream>> = config
+            .upstreams
+            .iter()
+            .map(|u| {
+                previous
+                    .and_then(|prev| prev.upstreams.iter().find(|p| p.url == u.url))
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(Upstream::new(&u.url)))
+            })
+            .collect();
+        let weighted: Vec<(String, u32)> = config
+            .upstreams
+            .iter()
+            .map(|u| (u.url.clone(), u.weight))
+            .collect();
+
+        let connect_ms = config
+            .timeouts
+            .connect_ms
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS);
+        let mut client = Client::builder()
+            .connect_timeout(Duration::from_millis(connect_ms))
+            // Redirects are the client's business; relay them with their `Location`
+            .redirect(reqwest::redirect::Policy::none());
+        if config.http2 {
+            client = client.http2_prior_knowledge();
+        }
+        if let Some(tls) = &config.tls {
+            client = with_upstream_tls(client, tls).map_err(|err| {
+                ConfigError::Invalid(format!("tls of service `{}`: {}", config.name, err))
+            })?;
+        }
+        let client = client.build().map_err(|err| {
+            ConfigError::Invalid(format!("HTTP client of service `{}`: {}", config.name, err))
+        })?;
+        let websocket_tls = config
+            .tls
+            .as_ref()
+            .map(|tls| tls::upstream_client_config(Some(tls)).map(Arc::new))
+            .transpose()
+            .map_err(|err| {
+                ConfigError::Invalid(format!("tls of service `{}`: {}", config.name, err))
+            })?;
+        let speaks_grpc = config.protocol == Protocol::Grpc
+            || config
+                .routes
+                .iter()
+                .any(|route| route.protocol == Some(Protocol::Grpc));
+        let grpc = speaks_grpc
+            .then(|| grpc::client(config.tls.as_ref(), Duration::from_millis(connect_ms)))
+            .transpose()
+            .map_err(|err| {
+                ConfigError::Invalid(format!("gRPC client of service `{}`: {}", config.name, err))
+            })?;
+
+        Ok(Service {
+            name: config.name.clone(),
+            prefix: config.prefix.trim_end_matches('/').to_string(),
+            aliases: config
+                .aliases
+                .iter()
+                .map(|alias| alias.trim_end_matches('/').to_string())
+                .collect(),
+            upstreams,
+            health_check: config.health_check.clone(),
+            circuit_breaker: config.circuit_breaker.clone(),
+            retry: config.retry.clone(),
+            retry_budget: previous
+                .map(|prev| prev.retry_budget.clone())
+                .unwrap_or_else(|| Arc::new(RetryBudget::new())),
+            timeouts: config.timeouts.clone(),
+            rate_limit: config.rate_limit.clone(),
+            auth: config.auth.clone().unwrap_or_default(),
+            body: config
+                .body
+                .as_ref()
+                .map(|body| BodyRules::from_config(body, &format!("service `{}`", config.name)))
+                .transpose()?,
+            routes: config
+                .routes
+                .iter()
+                .map(Route::from_config)
+                .collect::<Result<_, _>>()?,
+            rewrites: config
+                .rewrite
+                .iter()
+                .map(|rule| Rewrite::from_config(rule, &config.name))
+                .collect::<Result<_, _>>()?,
+            client,
+            websocket_tls,
+            protocol: config.protocol,
+            grpc,
+            strategy: config.load_balancer.strategy,
+            balancer: load_balancer::from_config(&config.load_balancer, &weighted),
+            hash_key: match config.load_balancer.strategy {
+                Strategy::ConsistentHash => config
+                    .load_balancer
+                    .hash_on
+                    .as_deref()
+                    .and_then(HashKey::parse),
+                _ => None,
+            },
+        })
+    }
+
+    // Length of the longest of the service's prefixes the path starts with
+    fn matches(&self, path: &str) -> Option<usize> {
+        std::iter::once(&self.prefix)
+            .chain(&self.aliases)
+            .filter(|prefix| path.starts_with(prefix.as_str()))
+            .map(String::len)
+            .max()
+    }
+
+    // The path under the service's own prefix when it came in through an
+    // alias, so routes written for the prefix apply to both
+    fn canonical_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let alias = self
+            .aliases
+            .iter()
+            .filter(|alias| path.starts_with(alias.as_str()))
+            .max_by_key(|alias| alias.len());
+        match alias {
+            // The longest matching prefix wins, as in `matches`
+            Some(alias) if self.matches(path) == Some(alias.len()) => {
+                let rest = &path[alias.trim_end_matches('/').len()..];
+                Cow::Owned(format!("{}{}", self.prefix.trim_end_matches('/'), rest))
+            }
+            _ => Cow::Borrowed(path),
+        }
+    }
+
+    // Whether `route` covers the request, reached through the prefix or an alias
+    fn route_covers(&self, route: &Route, method: &Method, path: &str) -> bool {
+        route.matches(method, path) || route.matches(method, &self.canonical_path(path))
+    }
+
+    // Path to request from the upstreams: the first applicable rewrite's output
+    pub fn upstream_path(&self, path: &str) -> String {
+        self.rewrites
+            .iter()
+            .find_map(|rule| rule.apply(path))
+            .unwrap_or_else(|| path.to_string())
+    }
+
+    // First route override matching the request, if any
+    pub fn route_for(&self, req: &HttpRequest) -> Option<&Route> {
+        self.route_matching(req.method(), req.path())
+    }
+
+    pub fn route_matching(&self, method: &Method, path: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|route| self.route_covers(route, method, path))
+    }
+
+    // Body rules of the route, or else the service's
+    pub fn body_rules_for<'a>(&'a self, route: Option<&'a Route>) -> Option<&'a BodyRules> {
+        route
+            .and_then(|r| r.body.as_ref())
+            .or(self.body.as_ref())
+    }
+
+    // How to forward requests of the route, or else of the service
+    pub fn protocol_for(&self, route: Option<&Route>) -> Protocol {
+        route.and_then(|r| r.protocol).unwrap_or(self.protocol)
+    }
+
+    pub fn timeouts_for(&self, route: Option<&Route>) -> Timeouts {
+        let route = route.map(|r| &r.timeouts);
+        let pick = |field: fn(&TimeoutConfig) -> Option<u64>, default: u64| {
+            let ms = route
+                .and_then(field)
+                .or_else(|| field(&self.timeouts))
+                .unwrap_or(default);
+            Duration::from_millis(ms)
+        };
+
+        Timeouts {
+            read: pick(|t| t.read_ms, DEFAULT_READ_TIMEOUT_MS),
+            total: pick(|t| t.total_ms, DEFAULT_TOTAL_TIMEOUT_MS),
+            idle: pick(|t| t.idle_ms, DEFAULT_IDLE_TIMEOUT_MS),
+        }
+    }
+
+    // Balancing key for strategies that need one (consistent hashing)
+    pub fn balance_key(&self, headers: &HeaderMap, path: &str) -> Option<String> {
+        self.hash_key.as_ref().map(|key| key.extract(headers, path))
+    }
+
+    // Next upstream chosen by the service's strategy, skipping any that the
+    // health checker has marked down or whose circuit breaker is open.
+    // Upstreams in `tried` are avoided unless nothing else is left.
+    pub fn next_backend(
+        &self,
+        key: Option<&str>,
+        tried: &[Arc<Upstream>],
+    ) -> Option<Arc<Upstream>> {
+        let available = |i: usize| {
+            let upstream = &self.upstreams[i];
+            upstream.health.is_healthy()
+                && !upstream.is_drained()
+                && upstream.breaker.is_available(&self.circuit_breaker)
+        };
+        let untried =
+            |i: usize| available(i) && !tried.iter().any(|t| Arc::ptr_eq(t, &self.upstreams[i]));
+
+        self.balancer
+            .pick(&self.upstreams, key, &untried)
+            .or_else(|| self.balancer.pick(&self.upstreams, key, &available))
+            .map(|i| self.upstreams[i].clone())
+    }
+}
+
+// Trust the service's CA and present its client certificate to `https://` upstreams
+fn with_upstream_tls(
+    client: reqwest::ClientBuilder,
+    tls: &UpstreamTlsConfig,
+) -> Result<reqwest::ClientBuilder, String> {
+    let read = |path: &std::path::Path| {
+        fs::read(path).map_err(|err| format!("cannot read {}: {}", path.display(), err))
+    };
+
+    let mut client = client.use_rustls_tls();
+    if let Some(ca) = &tls.ca {
+        let pem = read(ca)?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|err| format!("{}: {}", ca.display(), err))?;
+        for cert in certs {
+            client = client.add_root_certificate(cert);
+        }
+    }
+    if let (Some(cert), Some(key)) = (&tls.cert, &tls.key) {
+        // reqwest wants the chain and the key in a single PEM buffer
+        let mut pem = read(cert)?;
+        pem.push(b'\n');
+        pem.extend(read(key)?);
+        let identity = reqwest::Identity::from_pem(&pem)
+            .map_err(|err| format!("{}: {}", cert.display(), err))?;
+        client = client.identity(identity);
+    }
+    Ok(client)
+}
+
+// A `[rate_limit] api_keys` entry: 64 hex digits, compared in lowercase
+fn parse_key_digest(digest: &str) -> Result<String, ConfigError> {
+    let digest = digest.trim().to_ascii_lowercase();
+    if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ConfigError::Invalid(format!(
+            "rate_limit.api_keys entry `{}` is not a hex SHA-256 digest",
+            digest
+        )));
+    }
+    Ok(digest)
+}
+
+impl Service {
+    // Shortest wait until an open breaker lets trial requests through again
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.upstreams
+            .iter()
+            .filter_map(|u| u.breaker.retry_after(&self.circuit_breaker))
+            .min()
+    }
+}
+
+impl Registry {
+    // Build a registry, reusing upstream state from `previous` where the URL is unchanged
+    pub fn from_config(
+        config: &GatewayConfig,
+        previous: Option<&Registry>,
+    ) -> Result<Self, ConfigError> {
+        let registry = Registry {
+            services: config
+                .services
+                .iter()
+                .map(|s| {
+                    let prev = previous.and_then(|p| p.services.iter().find(|x| x.name == s.name));
+                    Service::from_config(s, prev)
+                })
+                .collect::<Result<_, _>>()?,
+            rate_limit: config.rate_limit.default.clone(),
+            api_keys: config
+                .rate_limit
+                .api_keys
+                .iter()
+                .map(|digest| parse_key_digest(digest))
+                .collect::<Result<_, _>>()?,
+            aggregates: config
+                .aggregates
+                .iter()
+                .map(Aggregate::from_config)
+                .collect::<Result<_, _>>()?,
+        };
+
+        // Parts are checked against the new service table
+        for aggregate in &registry.aggregates {
+            for part in &aggregate.parts {
+                if registry.detect_service(part.path_template()).is_none() {
+                    return Err(ConfigError::Invalid(format!(
+                        "part `{}` of aggregate `{}` matches no service",
+                        part.name, aggregate.path
+                    )));
+                }
+            }
+        }
+        Ok(registry)
+    }
+
+    // Whether `digest` is the SHA-256 of an issued API key
+    pub fn knows_api_key(&self, digest: &str) -> bool {
+        self.api_keys.contains(digest)
+    }
+
+    // Readmit the upstreams of services without a health check: nothing else
+    // would bring back one ejected by a check that a reload removed. Only
+    // called on a registry that is live, as the upstreams are shared with the
+    // one it replaced.
+    pub fn reset_unchecked_health(&self) {
+        let unchecked = self.services.iter().filter(|s| s.health_check.is_none());
+        for upstream in unchecked.flat_map(|s| &s.upstreams) {
+            upstream.health.reset();
+        }
+    }
+
+    pub fn aggregates(&self) -> &[Aggregate] {
+        &self.aggregates
+    }
+
+    pub fn services(&self) -> &[Service] {
+        &self.services
+    }
+
+    pub fn service_count(&self) -> usize {
+        self.services.len()
+    }
+
+    // Longest matching prefix wins, so `/api/v1/posts` and `/api/v1/posts-archive` can coexist
+    pub fn detect_service(&self, path: &str) -> Option<&Service> {
+        self.services
+            .iter()
+            .filter_map(|s| Some((s, s.matches(path)?)))
+            .max_by_key(|(_, len)| *len)
+            .map(|(s, _)| s)
+    }
+
+    // Access policy for a request, `None` outside every service and aggregate
+    pub fn auth_policy_for(&self, req: &HttpRequest) -> Option<&AuthPolicy> {
+        if let Some(aggregate) = self.aggregate_for(req) {
+            return Some(&aggregate.auth);
+        }
+        self.auth_policy(req.method(), req.path())
+    }
+
+    // Policy of the service route for `method` and `path`, aliases included.
+    // Routes without their own policy don't hide a later route that has one.
+    pub fn auth_policy(&self, method: &Method, path: &str) -> Option<&AuthPolicy> {
+        let service = self.detect_service(path)?;
+        let route = service
+            .routes
+            .iter()
+            .filter(|route| route.auth.is_some())
+            .find(|route| service.route_covers(route, method, path));
+        Some(route.and_then(|r| r.auth.as_ref()).unwrap_or(&service.auth))
+    }
+
+    // Composite endpoint answering the request, if any; they take precedence over services
+    pub fn aggregate_for(&self, req: &HttpRequest) -> Option<&Aggregate> {
+        self.aggregates
+            .iter()
+            .find(|aggregate| aggregate.matches(req.method(), req.path()))
+    }
+
+    // Rate limit for a request, route over service over the gateway default,
+    // with a scope naming where it was configured so each gets its own buckets
+    pub fn rate_limit_for(&self, req: &HttpRequest) -> Option<(String, &RateLimitConfig)> {
+        if let Some(aggregate) = self.aggregate_for(req) {
+            let limit = aggregate.rate_limit.as_ref().or(self.rate_limit.as_ref())?;
+            return Some((format!("aggregate:{}", aggregate.path), limit));
+        }
+        let service = self.detect_service(req.path())?;
+        if let Some(route) = service.route_for(req) {
+            if let Some(limit) = &route.rate_limit {
+                return Some((format!("{}:{}", service.name, route.path), limit));
+            }
+        }
+        if let Some(limit) = &service.rate_limit {
+            return Some((service.name.clone(), limit));
+        }
+        self.rate_limit
+            .as_ref()
+            .map(|limit| (format!("default:{}", service.name), limit))
+    }
+}
+
+#[cfg(test)]
+impl Registry {
+    // Registry of a gateway config written inline, as the tests do. A TOML
+    // typo panics; errors building the registry are returned.
+    pub fn from_toml(toml: &str, previous: Option<&Registry>) -> Result<Registry, ConfigError> {
+        let config: GatewayConfig = toml::from_str(toml).expect("test config is not valid TOML");
+        Registry::from_config(&config, previous)
+    }
+}
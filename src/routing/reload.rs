@@ -0,0 +1,139 @@
+use actix_web::rt::{self, time};
+use std::{fs, sync::Arc, time::Duration, time::SystemTime};
+use tracing::{error, info};
+
+use crate::config::ReloadConfig;
+
+use super::ServiceState;
+
+// Start the background tasks that re-read the config file on change or SIGHUP
+pub fn spawn_reloaders(state: Arc<ServiceState>, config: &ReloadConfig) {
+    if config.watch_interval_secs > 0 {
+        rt::spawn(watch_file(
+            state.clone(),
+            Duration::from_secs(config.watch_interval_secs),
+        ));
+    }
+
+    if config.on_sighup {
+        rt::spawn(watch_sighup(state));
+    }
+}
+
+pub fn reload(state: &ServiceState, trigger: &str) {
+    match state.reload() {
+        Ok(services) => info!(trigger, services, "gateway config reloaded"),
+        Err(err) => error!(trigger, %err, "gateway config reload failed; keeping current routes"),
+    }
+}
+
+fn modified_at(state: &ServiceState) -> Option<SystemTime> {
+    fs::metadata(state.config_path())
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+async fn watch_file(state: Arc<ServiceState>, interval: Duration) {
+    let mut last_modified = modified_at(&state);
+    let mut ticker = time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let modified = modified_at(&state);
+        if modified.is_some() && modified != last_modified {
+            last_modified = modified;
+            reload(&state, "file");
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn watch_sighup(state: Arc<ServiceState>) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!(%err, "cannot install SIGHUP handler");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        reload(&state, "sighup");
+    }
+}
+
+#[cfg(not(unix))]
+async fn watch_sighup(_state: Arc<ServiceState>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GatewayConfig;
+    use std::path::PathBuf;
+    use tempfile::NamedTempFile;
+
+    const POSTS: &str = r#"
+        [[services]]
+        name = "post"
+        prefix = "/api/v1/posts"
+        upstreams = [{ url = "http://post:8082" }]
+    "#;
+
+    const COMMENTS: &str = r#"
+        [[services]]
+        name = "comment"
+        prefix = "/api/v1/comments"
+        upstreams = [{ url = "http://comment:8083" }]
+    "#;
+
+    fn state(file: &NamedTempFile) -> ServiceState {
+        fs::write(file.path(), POSTS).unwrap();
+        let config = GatewayConfig::load(file.path()).unwrap();
+        ServiceState::new(PathBuf::from(file.path()), &config).unwrap()
+    }
+
+    #[test]
+    fn valid_configs_are_swapped_in_whole() {
+        let file = NamedTempFile::new().unwrap();
+        let state = state(&file);
+        let before = state.registry();
+
+        fs::write(file.path(), format!("{}{}", POSTS, COMMENTS)).unwrap();
+        reload(&state, "test");
+
+        let after = state.registry();
+        assert_eq!(after.service_count(), 2);
+        assert!(after.detect_service("/api/v1/comments/1").is_some());
+        // Requests that took a snapshot before the reload still see the old table
+        assert_eq!(before.service_count(), 1);
+        assert!(before.detect_service("/api/v1/comments/1").is_none());
+    }
+
+    #[test]
+    fn invalid_configs_leave_the_current_registry_in_place() {
+        let file = NamedTempFile::new().unwrap();
+        let state = state(&file);
+        let current = state.registry();
+
+        let broken = [
+            // Not TOML
+            format!("{}{}\n[[services", POSTS, COMMENTS),
+            // Fails validation
+            format!(
+                "{}{}",
+                POSTS,
+                COMMENTS.replace(r#"{ url = "http://comment:8083" }"#, "")
+            ),
+            // Fails building the registry
+            format!("{}routes = [{{ path = \"/api/v1/posts/{{id\" }}]", POSTS),
+        ];
+        for config in broken {
+            fs::write(file.path(), &config).unwrap();
+            reload(&state, "test");
+            assert!(Arc::ptr_eq(&state.registry(), &current), "{}", config);
+        }
+    }
+}
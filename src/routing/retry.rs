@@ -0,0 +1,199 @@
+use actix_web::http::{header::HeaderMap, Method};
+use rand::Rng;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::RetryConfig;
+
+use super::gateway::UpstreamError;
+
+// Length of the window the retry budget is measured over
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+// Caps retries per service to `budget_min_retries` plus `budget_ratio` of the
+// requests seen in the current window, so a failing backend can't get hit with
+// a multiple of its normal traffic.
+pub struct RetryBudget {
+    window: Mutex<BudgetWindow>,
+}
+
+struct BudgetWindow {
+    started: Instant,
+    requests: u32,
+    retries: u32,
+}
+
+impl RetryBudget {
+    pub fn new() -> Self {
+        RetryBudget {
+            window: Mutex::new(BudgetWindow {
+                started: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    fn current(&self) -> std::sync::MutexGuard<'_, BudgetWindow> {
+        let mut window = self.window.lock().unwrap();
+        if window.started.elapsed() >= BUDGET_WINDOW {
+            *window = BudgetWindow {
+                started: Instant::now(),
+                requests: 0,
+                retries: 0,
+            };
+        }
+        window
+    }
+
+    pub fn record_request(&self) {
+        self.current().requests += 1;
+    }
+
+    // Take one retry out of the budget; false if it is exhausted
+    pub fn try_spend(&self, config: &RetryConfig) -> bool {
+        let mut window = self.current();
+        let allowed =
+            config.budget_min_retries as f64 + config.budget_ratio * window.requests as f64;
+        if (window.retries as f64) < allowed {
+            window.retries += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Methods that are safe to replay. POST and PATCH qualify only when the client
+// marks the request with an `Idempotency-Key`.
+pub fn is_retryable_method(method: &Method, headers: &HeaderMap) -> bool {
+    match *method {
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS => true,
+        Method::POST | Method::PATCH => headers.contains_key(IDEMPOTENCY_KEY),
+        _ => false,
+    }
+}
+
+// Whether the outcome of an attempt is worth retrying on another upstream.
+// Only connect errors qualify among transport errors: the request never left.
+// Timeouts are not retried since the upstream may still be working on it.
+pub fn is_retryable_outcome(
+    outcome: &Result<reqwest::Response, UpstreamError>,
+    config: &RetryConfig,
+) -> bool {
+    match outcome {
+        Ok(resp) => config.retry_on.contains(&resp.status().as_u16()),
+        Err(UpstreamError::Request(err)) => err.is_connect(),
+        Err(UpstreamError::Timeout) => false,
+    }
+}
+
+// Exponential backoff with full jitter: uniform in [0, min(max, base * 2^attempt)]
+pub fn backoff(attempt: u32, config: &RetryConfig) -> Duration {
+    let cap = config
+        .base_backoff_ms
+        .saturating_mul(1u64 << attempt.min(16))
+        .min(config.max_backoff_ms);
+    Duration::from_millis(rand::thread_rng().gen_range(0..=cap))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+
+    fn config(toml: &str) -> RetryConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn budget_allows_the_minimum_plus_a_ratio_of_requests() {
+        let config = config("budget_min_retries = 2\nbudget_ratio = 0.5");
+        let budget = RetryBudget::new();
+        assert!(budget.try_spend(&config));
+        assert!(budget.try_spend(&config));
+        assert!(!budget.try_spend(&config));
+
+        for _ in 0..4 {
+            budget.record_request();
+        }
+        assert!(budget.try_spend(&config));
+        assert!(budget.try_spend(&config));
+        assert!(!budget.try_spend(&config));
+    }
+
+    #[test]
+    fn budget_starts_over_every_window() {
+        let config = config("budget_min_retries = 1\nbudget_ratio = 0.0");
+        let budget = RetryBudget::new();
+        assert!(budget.try_spend(&config));
+        assert!(!budget.try_spend(&config));
+
+        let mut window = budget.window.lock().unwrap();
+        window.started = Instant::now().checked_sub(BUDGET_WINDOW).unwrap();
+        drop(window);
+        assert!(budget.try_spend(&config));
+    }
+
+    #[test]
+    fn only_idempotent_requests_are_replayed() {
+        let none = HeaderMap::new();
+        let mut keyed = HeaderMap::new();
+        keyed.insert(
+            IDEMPOTENCY_KEY.try_into().unwrap(),
+            HeaderValue::from_static("order-42"),
+        );
+
+        for method in [
+            Method::GET,
+            Method::HEAD,
+            Method::PUT,
+            Method::DELETE,
+            Method::OPTIONS,
+        ] {
+            assert!(is_retryable_method(&method, &none), "{}", method);
+        }
+        for method in [Method::POST, Method::PATCH] {
+            assert!(!is_retryable_method(&method, &none), "{}", method);
+            assert!(is_retryable_method(&method, &keyed), "{}", method);
+        }
+        assert!(!is_retryable_method(&Method::CONNECT, &keyed));
+    }
+
+    #[actix_web::test]
+    async fn retries_listed_statuses_and_connect_errors_only() {
+        let config = RetryConfig::default();
+        let status = |code: u16| -> Result<reqwest::Response, UpstreamError> {
+            let resp = hyper::Response::builder().status(code).body("").unwrap();
+            Ok(reqwest::Response::from(resp))
+        };
+        assert!(is_retryable_outcome(&status(503), &config));
+        assert!(!is_retryable_outcome(&status(500), &config));
+        assert!(!is_retryable_outcome(&status(200), &config));
+        assert!(!is_retryable_outcome(&Err(UpstreamError::Timeout), &config));
+
+        // Nothing listens on port 1
+        let refused = reqwest::Client::new()
+            .get("http://127.0.0.1:1")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(is_retryable_outcome(&Err(refused.into()), &config));
+    }
+
+    #[test]
+    fn backoff_is_capped_by_the_attempt_and_the_maximum() {
+        let config = config("base_backoff_ms = 10\nmax_backoff_ms = 100");
+        for _ in 0..50 {
+            assert!(backoff(0, &config) <= Duration::from_millis(10));
+            assert!(backoff(2, &config) <= Duration::from_millis(40));
+            assert!(backoff(10, &config) <= Duration::from_millis(100));
+            // Shifts are clamped, so late attempts don't overflow
+            assert!(backoff(u32::MAX, &config) <= Duration::from_millis(100));
+        }
+    }
+}
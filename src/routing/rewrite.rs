@@ -0,0 +1,205 @@
+use regex::Regex;
+
+use crate::config::{ConfigError, RewriteConfig};
+
+// A path rewrite rule of a service, with its regex compiled
+pub struct Rewrite {
+    strip_prefix: Option<String>,
+    add_prefix: Option<String>,
+    regex: Option<(Regex, String)>,
+}
+
+impl Rewrite {
+    pub fn from_config(config: &RewriteConfig, service: &str) -> Result<Self, ConfigError> {
+        let regex = match (&config.regex, &config.replace) {
+            (Some(pattern), Some(replace)) => {
+                let regex = Regex::new(pattern).map_err(|err| {
+                    ConfigError::Invalid(format!("rewrite regex of service `{}`: {}", service, err))
+                })?;
+                Some((regex, replace.clone()))
+            }
+            _ => None,
+        };
+        let trimmed = |prefix: &Option<String>| {
+            prefix
+                .as_deref()
+                .map(|p| p.trim_end_matches('/').to_string())
+        };
+
+        Ok(Rewrite {
+            strip_prefix: trimmed(&config.strip_prefix),
+            add_prefix: trimmed(&config.add_prefix),
+            regex,
+        })
+    }
+
+    // The rewritten path, or `None` if the rule doesn't apply. `strip_prefix`
+    // only matches whole segments: `/api/v2` strips `/api/v2/posts` but not `/api/v20`.
+    pub fn apply(&self, path: &str) -> Option<String> {
+        let rest = match &self.strip_prefix {
+            Some(prefix) => path
+                .strip_prefix(prefix.as_str())
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'))?,
+            None => path,
+        };
+        let path = match (&self.add_prefix, rest) {
+            (Some(prefix), rest) => format!("{}{}", prefix, rest),
+            (None, "") => "/".to_string(),
+            (None, rest) => rest.to_string(),
+        };
+
+        match &self.regex {
+            Some((regex, replace)) => regex
+                .is_match(&path)
+                .then(|| regex.replace(&path, replace.as_str()).into_owned()),
+            None => Some(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{routing::registry::Registry, utils::build_uri};
+
+    fn rule(strip: Option<&str>, add: Option<&str>, regex: Option<(&str, &str)>) -> Rewrite {
+        let config = RewriteConfig {
+            strip_prefix: strip.map(str::to_string),
+            add_prefix: add.map(str::to_string),
+            regex: regex.map(|(pattern, _)| pattern.to_string()),
+            replace: regex.map(|(_, replace)| replace.to_string()),
+        };
+        Rewrite::from_config(&config, "test").unwrap()
+    }
+
+    #[test]
+    fn rewrites_paths() {
+        let by_slug = Some((
+            r"^/api/v1/posts/by-slug/([^/]+)$",
+            "/api/v1/posts/post-by-permalink/$1",
+        ));
+        let named = Some((r"^/users/(?P<id>\d+)/avatar$", "/storage/avatars/$id"));
+        let cases = [
+            // Prefix stripping, on segment boundaries only
+            (
+                rule(Some("/api/v2"), Some("/api/v1"), None),
+                "/api/v2/posts",
+                Some("/api/v1/posts"),
+            ),
+            (
+                rule(Some("/api/v2/"), Some("/api/v1/"), None),
+                "/api/v2/posts",
+                Some("/api/v1/posts"),
+            ),
+            (
+                rule(Some("/api/v2"), Some("/api/v1"), None),
+                "/api/v20/posts",
+                None,
+            ),
+            (
+                rule(Some("/api/v2"), Some("/api/v1"), None),
+                "/api/v1/posts",
+                None,
+            ),
+            (
+                rule(Some("/api/v2"), None, None),
+                "/api/v2/posts",
+                Some("/posts"),
+            ),
+            (rule(Some("/api/v2"), None, None), "/api/v2", Some("/")),
+            (
+                rule(None, Some("/internal"), None),
+                "/posts",
+                Some("/internal/posts"),
+            ),
+            // Captures, numbered and named
+            (
+                rule(None, None, by_slug),
+                "/api/v1/posts/by-slug/hello",
+                Some("/api/v1/posts/post-by-permalink/hello"),
+            ),
+            (rule(None, None, by_slug), "/api/v1/posts/by-slug/a/b", None),
+            (
+                rule(None, None, named),
+                "/users/42/avatar",
+                Some("/storage/avatars/42"),
+            ),
+            (rule(None, None, named), "/users/me/avatar", None),
+            // Both: the regex sees the path after the prefix swap
+            (
+                rule(
+                    Some("/api/v2"),
+                    Some("/api/v1"),
+                    Some((r"^/api/v1/p/(.+)$", "/api/v1/posts/$1")),
+                ),
+                "/api/v2/p/7",
+                Some("/api/v1/posts/7"),
+            ),
+        ];
+
+        for (rule, path, expected) in cases {
+            assert_eq!(rule.apply(path).as_deref(), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_regexes() {
+        let config = RewriteConfig {
+            regex: Some("(unclosed".into()),
+            replace: Some("$1".into()),
+            ..RewriteConfig::default()
+        };
+        assert!(Rewrite::from_config(&config, "test").is_err());
+    }
+
+    #[test]
+    fn aliases_and_rewrites_keep_the_query_string() {
+        let registry = Registry::from_toml(
+            r#"
+            [[services]]
+            name = "post"
+            prefix = "/api/v1/posts"
+            aliases = ["/api/v2/posts/"]
+            upstreams = [{ url = "http://posts:8082" }]
+
+            [[services.rewrite]]
+            strip_prefix = "/api/v2/posts"
+            add_prefix = "/api/v1/posts"
+
+            [[services.rewrite]]
+            regex = "^/api/v1/posts/by-slug/([^/]+)$"
+            replace = "/api/v1/posts/post-by-permalink/$1"
+            "#,
+            None,
+        )
+        .unwrap();
+
+        let cases = [
+            (
+                "/api/v1/posts/all",
+                "page=2",
+                "http://posts:8082/api/v1/posts/all?page=2",
+            ),
+            (
+                "/api/v2/posts/all",
+                "page=2&size=5",
+                "http://posts:8082/api/v1/posts/all?page=2&size=5",
+            ),
+            (
+                "/api/v1/posts/by-slug/hi",
+                "fields=title",
+                "http://posts:8082/api/v1/posts/post-by-permalink/hi?fields=title",
+            ),
+            ("/api/v2/posts/7", "", "http://posts:8082/api/v1/posts/7"),
+        ];
+        for (path, query, expected) in cases {
+            let service = registry.detect_service(path).unwrap();
+            let url = build_uri(
+                &service.upstreams[0].url,
+                &service.upstream_path(path),
+                query,
+            );
+            assert_eq!(url, expected, "{}?{}", path, query);
+        }
+    }
+}
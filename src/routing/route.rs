@@ -0,0 +1,149 @@
+use actix_web::{dev::ResourceDef, http::Method};
+
+use crate::config::{
+    check_path_pattern, AuthPolicy, CacheConfig, ConfigError, Protocol, RateLimitConfig,
+    RouteConfig, TimeoutConfig,
+};
+
+use super::validation::BodyRules;
+
+// A per-route override inside a service, matched on method and path pattern
+pub struct Route {
+    pub path: String,
+    pattern: ResourceDef,
+    pub methods: Vec<Method>,
+    pub timeouts: TimeoutConfig,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub auth: Option<AuthPolicy>,
+    pub cache: Option<CacheConfig>,
+    pub body: Option<BodyRules>,
+    pub protocol: Option<Protocol>,
+}
+
+impl Route {
+    pub fn from_config(config: &RouteConfig) -> Result<Self, ConfigError> {
+        check_path_pattern(&config.path).map_err(|err| {
+            ConfigError::Invalid(format!("path of route `{}`: {}", config.path, err))
+        })?;
+        Ok(Route {
+            path: config.path.clone(),
+            pattern: ResourceDef::new(config.path.as_str()),
+            // Validated when the config was loaded
+            methods: config
+                .methods
+                .iter()
+                .filter_map(|m| Method::from_bytes(m.to_uppercase().as_bytes()).ok())
+                .collect(),
+            timeouts: config.timeouts.clone(),
+            rate_limit: config.rate_limit.clone(),
+            auth: config.auth.clone(),
+            cache: config.cache.clone(),
+            body: config
+                .body
+                .as_ref()
+                .map(|body| BodyRules::from_config(body, &format!("route `{}`", config.path)))
+                .transpose()?,
+            protocol: config.protocol,
+        })
+    }
+
+    pub fn matches(&self, method: &Method, path: &str) -> bool {
+        (self.methods.is_empty() || self.methods.contains(method)) && self.pattern.is_match(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{AuthLevel, AuthPolicy},
+        routing::registry::Registry,
+    };
+
+    fn registry(route: &str) -> Result<Registry, ConfigError> {
+        let toml = format!(
+            r#"
+            [[services]]
+            name = "post"
+            prefix = "/api/v1/posts"
+            upstreams = [{{ url = "http://posts:8082" }}]
+            routes = [{{ path = '{}' }}]
+            "#,
+            route
+        );
+        Registry::from_toml(&toml, None)
+    }
+
+    #[test]
+    fn malformed_route_paths_are_config_errors() {
+        let paths = [
+            "/api/v1/posts/{id",
+            "/api/v1/posts/{id:[0-9}",
+            "/api/v1/posts/{id:(}",
+            "/api/v1/posts/{tail:.*}*",
+            "/api/v1/posts/{}",
+            "/api/v1/posts/{id}/{id}",
+        ];
+        for path in paths {
+            assert!(
+                matches!(registry(path), Err(ConfigError::Invalid(_))),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_segments_tails_and_regexes() {
+        let cases = [
+            ("/api/v1/posts/{id}", "/api/v1/posts/7"),
+            (r"/api/v1/posts/{id:\d{3}}", "/api/v1/posts/123"),
+            ("/api/v1/posts/{id}/{tail}*", "/api/v1/posts/7/comments/2"),
+            ("/api/v1/posts/drafts", "/api/v1/posts/drafts"),
+        ];
+        for (pattern, path) in cases {
+            let registry = registry(pattern).unwrap_or_else(|err| panic!("{}: {}", pattern, err));
+            let route = &registry.detect_service(path).unwrap().routes[0];
+            assert!(route.matches(&Method::GET, path), "{}", pattern);
+        }
+    }
+    #[test]
+    fn aliases_reach_the_routes_and_policies_of_the_prefix() {
+        let registry = Registry::from_toml(
+            r#"
+            [[services]]
+            name = "post"
+            prefix = "/api/v1/posts"
+            aliases = ["/api/v2/posts/"]
+            upstreams = [{ url = "http://posts:8082" }]
+
+            [[services.routes]]
+            path = "/api/v1/posts/{id}"
+            methods = ["DELETE"]
+            auth = { roles = ["admin"] }
+            "#,
+            None,
+        )
+        .unwrap();
+
+        for path in ["/api/v1/posts/7", "/api/v2/posts/7"] {
+            let policy = registry.auth_policy(&Method::DELETE, path);
+            assert!(
+                matches!(policy, Some(AuthPolicy::Require { roles, .. }) if roles == &["admin"]),
+                "{}",
+                path
+            );
+            let service = registry.detect_service(path).unwrap();
+            assert!(
+                service.route_matching(&Method::DELETE, path).is_some(),
+                "{}",
+                path
+            );
+        }
+        let policy = registry.auth_policy(&Method::GET, "/api/v2/posts/7");
+        assert!(matches!(
+            policy,
+            Some(AuthPolicy::Level(AuthLevel::Authenticated))
+        ));
+    }
+}
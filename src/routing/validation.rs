@@ -0,0 +1,155 @@
+use actix_web::{
+    http::{
+        header::{self, HeaderMap},
+        Method,
+    },
+    HttpResponse,
+};
+use jsonschema::Validator;
+use serde_json::{json, Value};
+use std::fs;
+
+use crate::config::{BodyConfig, ConfigError};
+
+// Bodies checked against a schema are buffered, up to `max_bytes` or this much
+pub const DEFAULT_MAX_VALIDATED_BODY: usize = 1024 * 1024;
+
+// Schema violations reported back per rejected request
+const MAX_REPORTED_ERRORS: usize = 10;
+
+// Request body rules of a service or route, with the schema compiled
+pub struct BodyRules {
+    pub max_bytes: Option<usize>,
+    content_types: Vec<String>,
+    schema: Option<Validator>,
+}
+
+impl BodyRules {
+    // The schema file is read on every (re)load, so edits apply with the next reload
+    pub fn from_config(config: &BodyConfig, what: &str) -> Result<Self, ConfigError> {
+        let schema = match &config.schema {
+            Some(path) => {
+                let invalid = |err: String| {
+                    ConfigError::Invalid(format!("schema {} of {}: {}", path.display(), what, err))
+                };
+                let raw = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+                let schema: Value = serde_json::from_str(&raw).map_err(|e| invalid(e.to_string()))?;
+                Some(jsonschema::validator_for(&schema).map_err(|e| invalid(e.to_string()))?)
+            }
+            None => None,
+        };
+
+        Ok(BodyRules {
+            max_bytes: config.max_bytes,
+            content_types: config
+                .content_types
+                .iter()
+                .map(|t| t.trim().to_ascii_lowercase())
+                .collect(),
+            schema,
+        })
+    }
+
+    // Whether the whole body must be read and checked before it is forwarded
+    pub fn validates_body(&self) -> bool {
+        self.schema.is_some()
+    }
+
+    pub fn buffer_limit(&self) -> usize {
+        self.max_bytes.unwrap_or(DEFAULT_MAX_VALIDATED_BODY)
+    }
+
+    // Checks on the request head alone: declared length and content type
+    pub fn check_head(&self, headers: &HeaderMap) -> Result<(), HttpResponse> {
+        let content_length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        if let (Some(max), Some(len)) = (self.max_bytes, content_length) {
+            if len > max {
+                return Err(payload_too_large(max));
+            }
+        }
+
+        let has_body =
+            headers.contains_key(header::TRANSFER_ENCODING) || content_length.unwrap_or(0) > 0;
+        if !has_body || self.content_types.is_empty() {
+            return Ok(());
+        }
+        let media_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase());
+        let accepted = media_type.is_some_and(|media_type| {
+            self.content_types
+                .iter()
+                .any(|allowed| media_type_matches(allowed, &media_type))
+        });
+        if !accepted {
+            return Err(HttpResponse::UnsupportedMediaType().json(json!({
+                "error": format!("Unsupported content type, expected {}", self.content_types.join(" or "))
+            })));
+        }
+        Ok(())
+    }
+
+    // A request without a body passes only if its method carries none; a write
+    // to a route with a schema must send something for the schema to check
+    pub fn check_missing_body(&self, method: &Method) -> Result<(), HttpResponse> {
+        let bodyless = matches!(
+            *method,
+            Method::GET | Method::HEAD | Method::DELETE | Method::OPTIONS
+        );
+        if self.schema.is_none() || bodyless {
+            return Ok(());
+        }
+        Err(invalid_body(vec!["body is required".to_string()]))
+    }
+
+    pub fn check_body(&self, body: &[u8]) -> Result<(), HttpResponse> {
+        let Some(schema) = &self.schema else {
+            return Ok(());
+        };
+        let instance: Value = serde_json::from_slice(body)
+            .map_err(|err| invalid_body(vec![format!("body is not valid JSON: {}", err)]))?;
+
+        let errors: Vec<String> = schema
+            .iter_errors(&instance)
+            .take(MAX_REPORTED_ERRORS)
+            .map(|err| match err.instance_path.to_string() {
+                path if path.is_empty() => err.to_string(),
+                path => format!("{}: {}", path, err),
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_body(errors))
+        }
+    }
+}
+
+// `image/*` accepts any image type
+fn media_type_matches(allowed: &str, media_type: &str) -> bool {
+    match allowed.strip_suffix("/*") {
+        Some("*") => true,
+        Some(kind) => media_type
+            .split_once('/')
+            .is_some_and(|(their_kind, _)| their_kind == kind),
+        None => allowed == media_type,
+    }
+}
+
+pub fn payload_too_large(max: usize) -> HttpResponse {
+    HttpResponse::PayloadTooLarge().json(json!({
+        "error": format!("Request body larger than {} bytes", max)
+    }))
+}
+
+fn invalid_body(details: Vec<String>) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "error": "Invalid request body",
+        "details": details
+    }))
+}
@@ -0,0 +1,254 @@
+use actix_web::{
+    http::{
+        header::{self, HeaderValue},
+        StatusCode,
+    },
+    rt::{net::TcpStream, time},
+    HttpRequest, HttpResponse,
+};
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, CloseCode, CloseReason, Session};
+use futures::{
+    future::{self, Either},
+    SinkExt as _, StreamExt as _,
+};
+use reqwest::header::HeaderMap;
+use std::{sync::Arc, time::Duration};
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        protocol::{frame::coding::CloseCode as UpstreamCloseCode, CloseFrame},
+        Message,
+    },
+    Connector, MaybeTlsStream, WebSocketStream,
+};
+use tracing::debug;
+
+use super::registry::InFlight;
+
+const IDLE: &str = "idle timeout";
+const UPSTREAM_LOST: &str = "upstream connection lost";
+
+pub type UpstreamSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// Whether the client asks to switch the connection to the WebSocket protocol
+pub fn is_upgrade(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+// `ws://` or `wss://` address of an upstream given by its `http(s)://` URL
+pub fn upstream_url(http_url: &str) -> String {
+    match http_url.split_once("://") {
+        Some(("https", rest)) => format!("wss://{}", rest),
+        Some(("http", rest)) => format!("ws://{}", rest),
+        _ => http_url.to_string(),
+    }
+}
+
+// Open the upstream side of the connection, offering the client's
+// subprotocols. Returns the socket and the subprotocol the upstream chose.
+pub async fn connect(
+    url: &str,
+    headers: &HeaderMap,
+    tls: Option<Arc<rustls::ClientConfig>>,
+) -> Result<(UpstreamSocket, Option<HeaderValue>), tungstenite::Error> {
+    let mut request = url.into_client_request()?;
+    for (name, value) in headers {
+        // The handshake itself is negotiated separately on each side; extensions
+        // such as compression are not supported by the relay
+        if name.as_str().starts_with("sec-websocket-") && name != header::SEC_WEBSOCKET_PROTOCOL {
+            continue;
+        }
+        request.headers_mut().append(name.clone(), value.clone());
+    }
+
+    let (socket, response) = tokio_tungstenite::connect_async_tls_with_config(
+        request,
+        None,
+        false,
+        tls.map(Connector::Rustls),
+    )
+    .await?;
+    let protocol = response
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .cloned();
+    Ok((socket, protocol))
+}
+
+// The upstream's answer when it refused the upgrade, relayed to the client
+pub fn rejected(response: tungstenite::handshake::client::Response) -> HttpResponse {
+    let (parts, body) = response.into_parts();
+    let status = StatusCode::from_u16(parts.status.as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut builder = HttpResponse::build(status);
+    if let Some(content_type) = parts.headers.get(header::CONTENT_TYPE) {
+        builder.insert_header((header::CONTENT_TYPE, content_type.clone()));
+    }
+    builder.body(body.unwrap_or_default())
+}
+
+// Pass messages both ways until either side closes or neither says anything
+// for `idle`. Pings and pongs are relayed too, so keepalives of either side
+// keep the connection open.
+pub async fn relay(
+    mut session: Session,
+    mut client: AggregatedMessageStream,
+    upstream: UpstreamSocket,
+    idle: Duration,
+    in_flight: InFlight,
+) {
+    let _in_flight = in_flight;
+    let (mut to_upstream, mut from_upstream) = upstream.split();
+
+    loop {
+        let next = future::select(client.next(), from_upstream.next());
+        let event = match time::timeout(idle, next).await {
+            Ok(event) => event,
+            Err(_) => {
+                debug!("closing idle WebSocket connection");
+                let _ = to_upstream
+                    .send(Message::Close(Some(CloseFrame {
+                        code: UpstreamCloseCode::Away,
+                        reason: IDLE.into(),
+                    })))
+                    .await;
+                let _ = session.close(reason(CloseCode::Away, IDLE)).await;
+                return;
+            }
+        };
+
+        match event {
+            Either::Left((Some(Ok(msg)), _)) => {
+                let msg = match msg {
+                    AggregatedMessage::Text(text) => Message::Text(text.to_string()),
+                    AggregatedMessage::Binary(bytes) => Message::Binary(bytes.to_vec()),
+                    AggregatedMessage::Ping(bytes) => Message::Ping(bytes.to_vec()),
+                    AggregatedMessage::Pong(bytes) => Message::Pong(bytes.to_vec()),
+                    AggregatedMessage::Close(reason) => {
+                        let frame = reason.clone().map(|r| CloseFrame {
+                            code: u16::from(r.code).into(),
+                            reason: r.description.unwrap_or_default().into(),
+                        });
+                        let _ = to_upstream.send(Message::Close(frame)).await;
+                        // Acknowledge with the client's own close code, as the protocol asks
+                        let _ = session.close(reason).await;
+                        return;
+                    }
+                };
+                if to_upstream.send(msg).await.is_err() {
+                    let _ = session.close(reason(CloseCode::Error, UPSTREAM_LOST)).await;
+                    return;
+                }
+            }
+            // The client went away or broke the protocol
+            Either::Left((Some(Err(_)) | None, _)) => {
+                let _ = to_upstream.send(Message::Close(None)).await;
+                return;
+            }
+            Either::Right((Some(Ok(msg)), _)) => {
+                let sent = match msg {
+                    Message::Text(text) => session.text(text).await,
+                    Message::Binary(bytes) => session.binary(bytes).await,
+                    Message::Ping(bytes) => session.ping(&bytes).await,
+                    Message::Pong(bytes) => session.pong(&bytes).await,
+                    Message::Close(frame) => {
+                        let close = frame.map(|f| CloseReason {
+                            code: u16::from(f.code).into(),
+                            description: Some(f.reason.into_owned()).filter(|r| !r.is_empty()),
+                        });
+                        let _ = session.close(close).await;
+                        return;
+                    }
+                    // Only produced when writing raw frames
+                    Message::Frame(_) => Ok(()),
+                };
+                if sent.is_err() {
+                    let _ = to_upstream.send(Message::Close(None)).await;
+                    return;
+                }
+            }
+            Either::Right((Some(Err(_)) | None, _)) => {
+                let _ = session.close(reason(CloseCode::Error, UPSTREAM_LOST)).await;
+                return;
+            }
+        }
+    }
+}
+
+fn reason(code: CloseCode, description: &str) -> Option<CloseReason> {
+    Some(CloseReason {
+        code,
+        description: Some(description.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{rt::net::TcpListener, test::TestRequest};
+    use std::sync::Mutex;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    #[test]
+    fn recognises_upgrades_and_maps_upstream_urls() {
+        let upgrade = |value| {
+            TestRequest::get()
+                .insert_header((header::UPGRADE, value))
+                .to_http_request()
+        };
+        assert!(is_upgrade(&upgrade("websocket")));
+        assert!(is_upgrade(&upgrade("WebSocket")));
+        assert!(!is_upgrade(&upgrade("h2c")));
+        assert!(!is_upgrade(&TestRequest::get().to_http_request()));
+
+        assert_eq!(upstream_url("http://posts:8086"), "ws://posts:8086");
+        assert_eq!(upstream_url("https://posts:8086"), "wss://posts:8086");
+        assert_eq!(upstream_url("posts:8086"), "posts:8086");
+    }
+
+    // Accepts one handshake, choosing the `chat` subprotocol, and reports the
+    // request it got
+    async fn upstream() -> (String, Arc<Mutex<Option<(String, HeaderMap)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let seen = Arc::new(Mutex::new(None));
+        let recorded = seen.clone();
+        actix_web::rt::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // The error type is tungstenite's
+            #[allow(clippy::result_large_err)]
+            let callback = move |req: &Request, mut res: Response| {
+                *recorded.lock().unwrap() = Some((req.uri().to_string(), req.headers().clone()));
+                res.headers_mut()
+                    .insert(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("chat"));
+                Ok(res)
+            };
+            let _socket = tokio_tungstenite::accept_hdr_async(stream, callback).await;
+        });
+        (url, seen)
+    }
+
+    #[actix_web::test]
+    async fn connect_forwards_headers_but_not_the_clients_handshake() {
+        let (url, seen) = upstream().await;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", HeaderValue::from_static("req-1"));
+        headers.insert(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("chat"));
+        headers.insert(header::SEC_WEBSOCKET_EXTENSIONS, HeaderValue::from_static("permessage-deflate"));
+        headers.insert(header::SEC_WEBSOCKET_KEY, HeaderValue::from_static("Y2xpZW50J3Mga2V5IQ=="));
+
+        let url = format!("{}/api/v1/feed?room=1", url);
+        let (_socket, protocol) = connect(&url, &headers, None).await.unwrap();
+        assert_eq!(protocol, Some(HeaderValue::from_static("chat")));
+
+        let (uri, forwarded) = seen.lock().unwrap().take().unwrap();
+        assert_eq!(uri, "/api/v1/feed?room=1");
+        assert_eq!(forwarded["x-request-id"], "req-1");
+        assert!(forwarded.get(header::SEC_WEBSOCKET_EXTENSIONS).is_none());
+        // The upstream handshake uses a key of its own
+        assert_ne!(forwarded[header::SEC_WEBSOCKET_KEY], "Y2xpZW50J3Mga2V5IQ==");
+    }
+}
@@ -19,7 +19,7 @@ pub async fn db() -> Database {
         username, password, host, port
     );
 
-    println!("url {:?}", mongo_uri);
+    log::info!("Connecting to MongoDB at {}:{}", host, port);
     let client_options = ClientOptions::parse(&mongo_uri)
         .await
         .unwrap();
@@ -6,12 +6,13 @@ use mongodb::{Database, Client};
 mod models;
 mod handlers;
 mod response;
-mod identify;
 mod db;
 mod jwt;
 
 pub struct AppState {
     pub db: Database,
+    // Key login tokens are signed with; must match the gateway's JWT_SECRET
+    pub jwt_secret: String,
 }
 
 #[actix_web::main]
@@ -21,22 +22,44 @@ async fn main() -> std::io::Result<()> {
 
     let port = env::var("PORT").unwrap_or_else(|_| "8081".into());
     let db = db::db().await;
+    let jwt_secret = env::var("JWT_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .expect("JWT_SECRET missing");
 
     let bind_address = format!("127.0.0.1:{}", port);
 
     println!("Starting server on port {}", port);
 
      // Create AppState
-     let app_state = web::Data::new(AppState { db });
+     let app_state = web::Data::new(AppState { db, jwt_secret });
+    let state = app_state.clone();
 
-    HttpServer::new(move || {
+    let auth = internal_auth::AuthMiddleware::from_env("auth");
+    let readiness = shutdown::Readiness::default();
+    let ready = web::Data::new(readiness.clone());
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
-            .route("/api/v1/auth/login", web::post().to(login))
-            .route("/api/v1/auth/register", web::post().to(register))
+            .app_data(ready.clone())
+            .route("/ready", web::get().to(shutdown::ready))
+            .service(
+                web::scope("/api/v1/auth")
+                    .wrap(auth.clone())
+                    .route("/login", web::post().to(login))
+                    .route("/register", web::post().to(register)),
+            )
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(&bind_address)?
-    .run()
-    .await
-    
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    state.db.client().clone().shutdown().await;
+    println!("Server stopped");
+    Ok(())
 }
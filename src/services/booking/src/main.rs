@@ -35,17 +35,36 @@ async fn main() -> std::io::Result<()> {
 
     // Create AppState
     let app_state = web::Data::new(AppState { config_db });
+    let state = app_state.clone();
 
-    HttpServer::new(move || {
+    let auth = internal_auth::AuthMiddleware::from_env("booking");
+    let readiness = shutdown::Readiness::default();
+    let ready = web::Data::new(readiness.clone());
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
-            .route("/bookings", web::get().to(get_all_bookings))
-            .route("/bookings/{id}", web::get().to(get_booking_by_id))
-            .route("/bookings", web::post().to(create_booking))
-            .route("/bookings/{id}", web::put().to(update_booking))
-            .route("/bookings/{id}", web::delete().to(delete_booking))
+            .app_data(ready.clone())
+            .route("/ready", web::get().to(shutdown::ready))
+            .service(
+                web::scope("/bookings")
+                    .wrap(auth.clone())
+                    .route("", web::get().to(get_all_bookings))
+                    .route("/{id}", web::get().to(get_booking_by_id))
+                    .route("", web::post().to(create_booking))
+                    .route("/{id}", web::put().to(update_booking))
+                    .route("/{id}", web::delete().to(delete_booking)),
+            )
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(&bind_address)?
-    .run()
-    .await
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    state.config_db.client().clone().shutdown().await;
+    println!("Server stopped");
+    Ok(())
 }
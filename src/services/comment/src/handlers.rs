@@ -2,7 +2,8 @@ use crate::{
     models::{Comment, CommentReq, Params},
     AppState,
 };
-use actix_web::{web, HttpMessage as _, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use internal_auth::Caller;
 use futures::TryStreamExt as _;
 use mongodb::bson::{self, doc, Bson, DateTime};
 
@@ -15,7 +16,7 @@ pub async fn create_comment(
     let comment_collection = state.comment_db.clone();
     let post_collection = state.post_db.clone();
 
-    let author_id = match req.extensions().get::<String>().cloned() {
+    let author_id = match Caller::of(&req).map(|caller| caller.id) {
         Some(id) => id,
         None => {
             return HttpResponse::Unauthorized().json(serde_json::json!({
@@ -151,7 +152,7 @@ pub async fn update_comment(
     let comment_id = comment_id.into_inner();
     let update_data = body.into_inner();
 
-    let author_id = match req.extensions().get::<String>().cloned() {
+    let author_id = match Caller::of(&req).map(|caller| caller.id) {
         Some(id) => id,
         None => {
             return HttpResponse::Unauthorized().json(serde_json::json!({
@@ -194,7 +195,7 @@ pub async fn delete_comment(
     let comment_collection = state.comment_db.clone();
     let comment_id = comment_id.into_inner();
 
-    let author_id = match req.extensions().get::<String>().cloned() {
+    let author_id = match Caller::of(&req).map(|caller| caller.id) {
         Some(id) => id,
         None => {
             return HttpResponse::Unauthorized().json(serde_json::json!({
@@ -10,7 +10,6 @@ use mongodb::Collection;
 
 mod db;
 mod handlers;
-mod middleware;
 mod models;
 
 pub struct AppState {
@@ -22,7 +21,7 @@ pub struct AppState {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
+    tracing_subscriber::fmt::init();
 
     let port = env::var("PORT").unwrap_or_else(|_| "8083".into());
 
@@ -39,16 +38,21 @@ async fn main() -> std::io::Result<()> {
         post_db,
         user_db,
     });
+    let state = app_state.clone();
 
-    let public_paths = vec!["/api/v1/comments/get-post-comments".to_string()];
+    let auth = internal_auth::AuthMiddleware::from_env("comment");
+    let readiness = shutdown::Readiness::default();
+    let ready = web::Data::new(readiness.clone());
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            .app_data(ready.clone())
             .wrap(Logger::default())
-            .wrap(middleware::AuthMiddleware::new(public_paths.clone()))
+            .route("/ready", web::get().to(shutdown::ready))
             .service(
                 web::scope("/api/v1/comments")
+                    .wrap(auth.clone())
                     .route("", web::post().to(create_comment))
                     .route(
                         "/get-post-comments/{permalink}",
@@ -58,7 +62,22 @@ async fn main() -> std::io::Result<()> {
                     .route("/{comment_id}", web::delete().to(delete_comment)),
             )
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(&bind_address)?
-    .run()
-    .await
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    // Every collection was opened with its own client
+    for client in [
+        state.comment_db.client(),
+        state.post_db.client(),
+        state.user_db.client(),
+    ] {
+        client.clone().shutdown().await;
+    }
+    println!("Server stopped");
+    Ok(())
 }
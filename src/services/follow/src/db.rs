@@ -16,7 +16,7 @@ async fn db() -> Database {
 
     let mongo_uri = format!("mongodb://{}:{}@{}:{}", username, password, host, port);
 
-    println!("url {:?}", mongo_uri);
+    tracing::info!("Connecting to MongoDB at {}:{}", host, port);
     let client_options = ClientOptions::parse(&mongo_uri).await.unwrap();
 
     let client = Client::with_options(client_options).unwrap();
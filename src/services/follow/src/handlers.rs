@@ -1,4 +1,5 @@
-use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, web};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use internal_auth::Caller;
 use futures::StreamExt as _;
 use mongodb::bson::{DateTime, doc, oid::ObjectId};
 use serde_json::json;
@@ -13,7 +14,7 @@ pub async fn follow(
     state: web::Data<AppState>,
     payload: web::Json<FollowRequest>,
 ) -> impl Responder {
-    let follower_id = match req.extensions().get::<String>().cloned() {
+    let follower_id = match Caller::of(&req).map(|caller| caller.id) {
         Some(id) => id,
         None => {
             return HttpResponse::Unauthorized().json(json!({
@@ -63,7 +64,7 @@ pub async fn unfollow(
     state: web::Data<AppState>,
     payload: web::Json<FollowRequest>,
 ) -> impl Responder {
-    let follower_id = match req.extensions().get::<String>().cloned() {
+    let follower_id = match Caller::of(&req).map(|caller| caller.id) {
         Some(id) => id,
         None => {
             return HttpResponse::Unauthorized().json(json!({
@@ -95,7 +96,7 @@ pub async fn follow_toggle(
     state: web::Data<AppState>,
     payload: web::Json<FollowRequest>,
 ) -> impl Responder {
-    let follower_id = match req.extensions().get::<String>().cloned() {
+    let follower_id = match Caller::of(&req).map(|caller| caller.id) {
         Some(id) => id,
         None => {
             return HttpResponse::Unauthorized().json(json!({
@@ -11,7 +11,6 @@ use mongodb::Collection;
 
 mod db;
 mod handlers;
-mod middleware;
 mod models;
 
 pub struct AppState {
@@ -22,7 +21,7 @@ pub struct AppState {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
+    tracing_subscriber::fmt::init();
 
     let port = env::var("PORT").unwrap_or_else(|_| "8085".into());
 
@@ -34,21 +33,21 @@ async fn main() -> std::io::Result<()> {
     println!("Starting server on port {}", port);
 
     let app_state = web::Data::new(AppState { follow_db, user_db });
+    let state = app_state.clone();
 
-    let public_paths = vec![
-        "/api/v1/follow/followers".to_string(),
-        "/api/v1/follow/following".to_string(),
-        "/api/v1/follow/status".to_string(),
-        "/api/v1/follow/counts".to_string(),
-    ];
+    let auth = internal_auth::AuthMiddleware::from_env("follow");
+    let readiness = shutdown::Readiness::default();
+    let ready = web::Data::new(readiness.clone());
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            .app_data(ready.clone())
             .wrap(Logger::default())
-            .wrap(middleware::AuthMiddleware::new(public_paths.clone()))
+            .route("/ready", web::get().to(shutdown::ready))
             .service(
                 web::scope("/api/v1/follow")
+                    .wrap(auth.clone())
                     .route("", web::post().to(follow))
                     .route("/unfollow", web::post().to(unfollow))
                     .route("/toggle", web::post().to(follow_toggle))
@@ -58,7 +57,21 @@ async fn main() -> std::io::Result<()> {
                     .route("/counts/{user_id}", web::get().to(follow_count)),
             )
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(&bind_address)?
-    .run()
-    .await
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    // Every collection was opened with its own client
+    for client in [
+        state.follow_db.client(),
+        state.user_db.client(),
+    ] {
+        client.clone().shutdown().await;
+    }
+    println!("Server stopped");
+    Ok(())
 }
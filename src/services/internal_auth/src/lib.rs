@@ -0,0 +1,421 @@
+use actix_web::{
+    body::{BoxBody, EitherBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    error::InternalError,
+    http::{header::HeaderMap, StatusCode},
+    rt::time,
+    Error, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, field, info_span, warn, Instrument as _, Span};
+
+// Absolute deadline (Unix epoch ms) set by the gateway; past it nobody is waiting for the answer
+const DEADLINE_HEADER: &str = "X-Request-Deadline";
+
+// Short-lived token signed by the gateway with INTERNAL_SECRET_KEY stating who the caller is
+const ASSERTION_HEADER: &str = "X-Internal-Assertion";
+const ASSERTION_ISSUER: &str = "gateway";
+
+// Correlation ids forwarded by the gateway, attached to the request's span
+const REQUEST_ID_HEADER: &str = "X-Request-ID";
+const TRACEPARENT_HEADER: &str = "traceparent";
+
+#[derive(Debug, Deserialize)]
+struct InternalClaims {
+    sub: String,
+    role: String,
+    rid: String,
+}
+
+// Signed-in caller named by the assertion, in the request extensions
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub id: String,
+    pub role: String,
+}
+
+impl Caller {
+    pub fn of(req: &HttpRequest) -> Option<Caller> {
+        req.extensions().get::<Caller>().cloned()
+    }
+}
+
+// Key and checks for the gateway's assertion. It must be addressed to this
+// service: the gateway mints one per upstream service with the service's
+// registry name as `aud`, so it can't be replayed against another service.
+struct AssertionKey {
+    // None for an empty secret, which would accept assertions anyone can sign
+    key: Option<DecodingKey>,
+    validation: Validation,
+}
+
+impl AssertionKey {
+    fn new(secret: &str, audience: &str) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[ASSERTION_ISSUER]);
+        validation.set_audience(&[audience]);
+        validation.leeway = 5;
+        AssertionKey {
+            key: (!secret.is_empty()).then(|| DecodingKey::from_secret(secret.as_bytes())),
+            validation,
+        }
+    }
+}
+
+// Accepts only requests carrying the gateway's assertion and exposes the caller
+// it names. Which routes need a caller, and with which role or scopes, is
+// decided by the gateway's route policies before the request gets here; an
+// anonymous caller on a public route comes with an empty `sub`.
+#[derive(Clone)]
+pub struct AuthMiddleware {
+    key: Arc<AssertionKey>,
+}
+
+impl AuthMiddleware {
+    // `audience` is this service's name in the gateway's registry. Panics when
+    // INTERNAL_SECRET_KEY is unset, so a misconfigured service fails at startup
+    // rather than answering every request with 401.
+    pub fn from_env(audience: &str) -> Self {
+        let secret = env::var("INTERNAL_SECRET_KEY")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .expect("INTERNAL_SECRET_KEY missing");
+        AuthMiddleware::new(&secret, audience)
+    }
+
+    pub fn new(secret: &str, audience: &str) -> Self {
+        AuthMiddleware {
+            key: Arc::new(AssertionKey::new(secret, audience)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: u16,
+    error: String,
+    message: String,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AuthMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+    type Error = Error;
+    type Transform = AuthMiddlewareService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthMiddlewareService {
+            service: Rc::new(service),
+            key: self.key.clone(),
+        })
+    }
+}
+
+pub struct AuthMiddlewareService<S> {
+    service: Rc<S>,
+    key: Arc<AssertionKey>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let key = self.key.clone();
+        let path = req.path().to_string();
+        let method = req.method().clone();
+        let headers = req.headers().clone();
+        let remaining = time_until_deadline(&headers);
+
+        let request_id = headers.get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok());
+        let span = info_span!(
+            "request",
+            request_id = request_id.map(field::display),
+            trace_id = trace_id(&headers).map(field::display),
+            %method,
+            %path,
+        );
+        let has_request_id = request_id.is_some();
+
+        let handle = async move {
+            if remaining.is_some_and(|left| left.is_zero()) {
+                warn!(
+                    "Rejecting {} {}: gateway deadline already passed",
+                    method, path
+                );
+                return respond_json(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "deadline_exceeded",
+                    "Request deadline exceeded",
+                    req,
+                );
+            }
+
+            match verify_assertion(&headers, &key) {
+                Some(claims) => {
+                    // The assertion carries the request id too
+                    if !has_request_id {
+                        Span::current().record("request_id", field::display(&claims.rid));
+                    }
+                    if claims.sub.is_empty() {
+                        debug!("Anonymous caller");
+                    } else {
+                        debug!(
+                            "Authenticated caller: ID={}, role={}",
+                            claims.sub, claims.role
+                        );
+                        req.extensions_mut().insert(Caller {
+                            id: claims.sub,
+                            role: claims.role,
+                        });
+                    }
+                    call_within_deadline(service, req, remaining).await
+                }
+                None => {
+                    warn!(
+                        "Unauthorized access: invalid internal assertion for {} {}",
+                        method, path
+                    );
+                    respond_json(
+                        StatusCode::UNAUTHORIZED,
+                        "unauthorized",
+                        "Invalid or missing internal assertion",
+                        req,
+                    )
+                }
+            }
+        };
+        Box::pin(handle.instrument(span))
+    }
+}
+
+// Claims of the gateway's assertion, if present, correctly signed, addressed
+// to this service and unexpired
+fn verify_assertion(headers: &HeaderMap, key: &AssertionKey) -> Option<InternalClaims> {
+    let decoding = key.key.as_ref()?;
+    let token = headers.get(ASSERTION_HEADER)?.to_str().ok()?;
+
+    match decode::<InternalClaims>(token, decoding, &key.validation) {
+        Ok(data) => Some(data.claims),
+        Err(err) => {
+            warn!("Rejected internal assertion: {}", err);
+            None
+        }
+    }
+}
+
+// Trace id from the W3C `traceparent` header (`00-<trace id>-<parent id>-<flags>`)
+fn trace_id(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(TRACEPARENT_HEADER)?.to_str().ok()?;
+    let trace_id = value.split('-').nth(1)?;
+    let is_hex = trace_id.bytes().all(|b| b.is_ascii_hexdigit());
+    (trace_id.len() == 32 && is_hex).then_some(trace_id)
+}
+
+// Time left until the deadline propagated by the gateway, if it sent one
+fn time_until_deadline(headers: &HeaderMap) -> Option<Duration> {
+    let deadline_ms: u64 = headers.get(DEADLINE_HEADER)?.to_str().ok()?.parse().ok()?;
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_millis() as u64;
+    Some(Duration::from_millis(deadline_ms.saturating_sub(now_ms)))
+}
+
+// Run the handler, abandoning it once the gateway's deadline passes
+async fn call_within_deadline<S, B>(
+    service: Rc<S>,
+    req: ServiceRequest,
+    remaining: Option<Duration>,
+) -> Result<ServiceResponse<EitherBody<B, BoxBody>>, Error>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    let Some(remaining) = remaining else {
+        let res = service.call(req).await?;
+        return Ok(res.map_into_left_body());
+    };
+
+    // Cloning the request here would break routing further down, so keep just what we log
+    let (method, path) = (req.method().clone(), req.path().to_string());
+    match time::timeout(remaining, service.call(req)).await {
+        Ok(res) => Ok(res?.map_into_left_body()),
+        Err(_) => {
+            warn!("Abandoned {} {}: gateway deadline exceeded", method, path);
+            let body = ErrorResponse {
+                code: StatusCode::GATEWAY_TIMEOUT.as_u16(),
+                error: "deadline_exceeded".to_string(),
+                message: "Request deadline exceeded".to_string(),
+            };
+            let res = HttpResponse::GatewayTimeout().json(body);
+            Err(InternalError::from_response("deadline exceeded", res).into())
+        }
+    }
+}
+
+fn respond_json<B>(
+    status: StatusCode,
+    error: &str,
+    message: &str,
+    req: ServiceRequest,
+) -> Result<ServiceResponse<EitherBody<B, BoxBody>>, Error> {
+    let body = ErrorResponse {
+        code: status.as_u16(),
+        error: error.to_string(),
+        message: message.to_string(),
+    };
+
+    let res = HttpResponse::build(status).json(body);
+    Ok(req.into_response(res.map_into_right_body()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::header::{HeaderName, HeaderValue},
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App,
+    };
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    const SECRET: &str = "internal-test-secret";
+    const AUDIENCE: &str = "comment";
+
+    fn assertion(secret: &str, claims: Value) -> String {
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        encode(&Header::new(Algorithm::HS256), &claims, &key).unwrap()
+    }
+
+    // Claims as the gateway mints them, with `overrides` applied
+    fn claims(overrides: Value) -> Value {
+        let now = get_current_timestamp();
+        let mut claims = json!({
+            "iss": ASSERTION_ISSUER,
+            "aud": AUDIENCE,
+            "sub": "user-1",
+            "role": "user",
+            "rid": "req-1",
+            "iat": now,
+            "exp": now + 30,
+        });
+        for (name, value) in overrides.as_object().unwrap() {
+            claims[name] = value.clone();
+        }
+        claims
+    }
+
+    fn headers(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_bytes(ASSERTION_HEADER.as_bytes()).unwrap(),
+            HeaderValue::from_str(token).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn verifies_the_gateways_assertion() {
+        let key = AssertionKey::new(SECRET, AUDIENCE);
+        let valid = headers(&assertion(SECRET, claims(json!({}))));
+        let verified = verify_assertion(&valid, &key).unwrap();
+        assert_eq!((verified.sub.as_str(), verified.role.as_str()), ("user-1", "user"));
+        assert_eq!(verified.rid, "req-1");
+
+        let expired = get_current_timestamp() - 60;
+        let rejected = [
+            ("wrong secret", assertion("another-secret", claims(json!({})))),
+            ("expired", assertion(SECRET, claims(json!({ "exp": expired })))),
+            ("wrong issuer", assertion(SECRET, claims(json!({ "iss": "client" })))),
+            ("other service", assertion(SECRET, claims(json!({ "aud": "vote" })))),
+            ("not a token", "garbage".to_string()),
+        ];
+        for (case, token) in rejected {
+            assert!(verify_assertion(&headers(&token), &key).is_none(), "{}", case);
+        }
+
+        assert!(verify_assertion(&HeaderMap::new(), &key).is_none());
+    }
+
+    #[test]
+    fn an_empty_secret_accepts_nothing() {
+        let key = AssertionKey::new("", AUDIENCE);
+        let signed_with_empty = headers(&assertion("", claims(json!({}))));
+        assert!(verify_assertion(&signed_with_empty, &key).is_none());
+    }
+
+    async fn whoami(req: HttpRequest) -> HttpResponse {
+        match Caller::of(&req) {
+            Some(caller) => HttpResponse::Ok().body(format!("{}:{}", caller.id, caller.role)),
+            None => HttpResponse::Ok().body("anonymous"),
+        }
+    }
+
+    async fn call(req: TestRequest) -> (StatusCode, String) {
+        let app = init_service(
+            App::new()
+                .wrap(AuthMiddleware::new(SECRET, AUDIENCE))
+                .route("/whoami", web::get().to(whoami)),
+        )
+        .await;
+        let res = call_service(&app, req.uri("/whoami").to_request()).await;
+        let status = res.status();
+        let body = read_body(res).await;
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn exposes_the_caller_and_leaves_anonymous_callers_without_one() {
+        let signed_in = assertion(SECRET, claims(json!({})));
+        let req = TestRequest::get().insert_header((ASSERTION_HEADER, signed_in));
+        assert_eq!(call(req).await, (StatusCode::OK, "user-1:user".into()));
+
+        let anonymous = assertion(SECRET, claims(json!({ "sub": "", "role": "" })));
+        let req = TestRequest::get().insert_header((ASSERTION_HEADER, anonymous));
+        assert_eq!(call(req).await, (StatusCode::OK, "anonymous".into()));
+
+        let (status, _) = call(TestRequest::get()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn rejects_requests_past_the_gateways_deadline() {
+        let passed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            - 1_000;
+        let req = TestRequest::get()
+            .insert_header((ASSERTION_HEADER, assertion(SECRET, claims(json!({})))))
+            .insert_header((DEADLINE_HEADER, passed.to_string()));
+        let (status, body) = call(req).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(body.contains("deadline_exceeded"));
+    }
+}
@@ -5,7 +5,8 @@ use crate::{
     models::{Post, PostWithAuthor},
     AppState,
 };
-use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use internal_auth::Caller;
 use futures::StreamExt as _;
 use futures_util::TryStreamExt;
 use mongodb::bson::{doc, oid::ObjectId, to_document, Bson, DateTime};
@@ -180,7 +181,7 @@ pub async fn get_all_posts_by_user(
     let user_collection = state.user_db.clone();
     let vote_collection = state.vote_db.clone();
 
-    let author_id = req.extensions().get::<String>().cloned();
+    let author_id = Caller::of(&req).map(|caller| caller.id);
 
     let params = query.into_inner();
     let page = params.skip.unwrap_or(1).max(1);
@@ -302,7 +303,7 @@ pub async fn get_post_by_user(
     let post_collection = state.post_db.clone();
     let vote_collection = state.vote_db.clone();
     let user_collection = state.user_db.clone();
-    let author_id = req.extensions().get::<String>().cloned();
+    let author_id = Caller::of(&req).map(|caller| caller.id);
 
     let post = post_collection
         .find_one(doc! { "_id": post_id.into_inner().to_string(), "author_id": author_id })
@@ -441,7 +442,7 @@ pub async fn create_post(
     req: HttpRequest,
 ) -> impl Responder {
     let collection = state.post_db.clone();
-    let author_id = req.extensions().get::<String>().cloned();
+    let author_id = Caller::of(&req).map(|caller| caller.id);
 
     let new_post = Post::new(
         post.content.clone(),
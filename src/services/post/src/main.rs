@@ -13,7 +13,6 @@ use models::{AuthorInfo, Follow, Post, Vote};
 mod db;
 mod handlers;
 mod identify;
-mod middleware;
 mod models;
 mod response;
 mod utils;
@@ -29,7 +28,7 @@ pub struct AppState {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
+    tracing_subscriber::fmt::init();
 
     let port = env::var("PORT").unwrap_or_else(|_| "8082".into());
 
@@ -51,18 +50,20 @@ async fn main() -> std::io::Result<()> {
         comment_db,
         follow_db,
     });
+    let state = app_state.clone();
 
-    let public_paths = vec![
-        "/api/v1/posts/all".to_string(),
-        "/api/v1/posts/post-by-permalink".to_string(),
-    ];
+    let auth = internal_auth::AuthMiddleware::from_env("post");
+    let readiness = shutdown::Readiness::default();
+    let ready = web::Data::new(readiness.clone());
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
-            .wrap(middleware::AuthMiddleware::new(public_paths.clone()))
+            .app_data(ready.clone())
+            .route("/ready", web::get().to(shutdown::ready))
             .service(
                 web::scope("/api/v1/posts")
+                    .wrap(auth.clone())
                     .route("/all", web::get().to(get_all_posts))
                     .route("", web::get().to(get_all_posts_by_user))
                     .route("", web::post().to(create_post))
@@ -75,7 +76,24 @@ async fn main() -> std::io::Result<()> {
                     .route("/{id}", web::delete().to(delete_post)),
             )
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(&bind_address)?
-    .run()
-    .await
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    // Every collection was opened with its own client
+    for client in [
+        state.post_db.client(),
+        state.user_db.client(),
+        state.vote_db.client(),
+        state.comment_db.client(),
+        state.follow_db.client(),
+    ] {
+        client.clone().shutdown().await;
+    }
+    println!("Server stopped");
+    Ok(())
 }
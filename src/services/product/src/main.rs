@@ -36,17 +36,36 @@ async fn main() -> std::io::Result<()> {
 
     // Create AppState
     let app_state = web::Data::new(AppState { product_config_db });
+    let state = app_state.clone();
 
-    HttpServer::new(move || {
+    let auth = internal_auth::AuthMiddleware::from_env("product");
+    let readiness = shutdown::Readiness::default();
+    let ready = web::Data::new(readiness.clone());
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
-            .route("/products", web::get().to(get_all_products))
-            .route("/products/{id}", web::get().to(get_product_by_id))
-            .route("/products", web::post().to(create_product))
-            .route("/products/{id}", web::put().to(update_product))
-            .route("/products/{id}", web::delete().to(delete_product))
+            .app_data(ready.clone())
+            .route("/ready", web::get().to(shutdown::ready))
+            .service(
+                web::scope("/products")
+                    .wrap(auth.clone())
+                    .route("", web::get().to(get_all_products))
+                    .route("/{id}", web::get().to(get_product_by_id))
+                    .route("", web::post().to(create_product))
+                    .route("/{id}", web::put().to(update_product))
+                    .route("/{id}", web::delete().to(delete_product)),
+            )
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(&bind_address)?
-    .run()
-    .await
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    state.product_config_db.client().clone().shutdown().await;
+    println!("Server stopped");
+    Ok(())
 }
@@ -34,17 +34,36 @@ async fn main() -> std::io::Result<()> {
     println!("Starting server on port {}", port);
 
     let app_state = web::Data::new(AppState { config_db });
+    let state = app_state.clone();
 
-    HttpServer::new(move || {
+    let auth = internal_auth::AuthMiddleware::from_env("property");
+    let readiness = shutdown::Readiness::default();
+    let ready = web::Data::new(readiness.clone());
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
-            .route("/properties", web::get().to(get_all_properties))
-            .route("/properties/{id}", web::get().to(get_property_by_id))
-            .route("/properties", web::post().to(create_property))
-            .route("/properties/{id}", web::put().to(update_property))
-            .route("/properties/{id}", web::delete().to(delete_property))
+            .app_data(ready.clone())
+            .route("/ready", web::get().to(shutdown::ready))
+            .service(
+                web::scope("/properties")
+                    .wrap(auth.clone())
+                    .route("", web::get().to(get_all_properties))
+                    .route("/{id}", web::get().to(get_property_by_id))
+                    .route("", web::post().to(create_property))
+                    .route("/{id}", web::put().to(update_property))
+                    .route("/{id}", web::delete().to(delete_property)),
+            )
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(&bind_address)?
-    .run()
-    .await
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    state.config_db.client().clone().shutdown().await;
+    println!("Server stopped");
+    Ok(())
 }
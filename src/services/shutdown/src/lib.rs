@@ -0,0 +1,203 @@
+use actix_web::{
+    dev::ServerHandle,
+    rt::{self, signal, time},
+    web, HttpResponse,
+};
+use futures::future;
+use serde_json::json;
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+const DEFAULT_DRAIN_DELAY_SECS: u64 = 5;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+// Whether the process still takes new traffic; cleared once shutdown begins
+#[derive(Clone, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        !self.0.load(Ordering::Relaxed)
+    }
+
+    // Returns whether shutdown had already begun
+    fn begin_shutdown(&self) -> bool {
+        self.0.swap(true, Ordering::Relaxed)
+    }
+}
+
+// GET /ready: for load balancers and readiness probes. Unlike `/health` it
+// only fails once a shutdown signal arrived, so traffic moves elsewhere.
+pub async fn ready(readiness: web::Data<Readiness>) -> HttpResponse {
+    if readiness.is_ready() {
+        HttpResponse::Ok().json(json!({ "status": "ready" }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(json!({ "status": "shutting down" }))
+    }
+}
+
+// How long in-flight requests get to finish once the listener is closed
+// (SHUTDOWN_TIMEOUT_SECS)
+pub fn timeout_secs() -> u64 {
+    env_secs("SHUTDOWN_TIMEOUT_SECS", DEFAULT_SHUTDOWN_TIMEOUT_SECS)
+}
+
+fn env_secs(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+// `spawn_signal_handlers` for a service's single server, draining for
+// SHUTDOWN_DRAIN_DELAY_SECS
+pub fn spawn_signal_handler(server: ServerHandle, readiness: Readiness) {
+    let drain_delay = Duration::from_secs(env_secs(
+        "SHUTDOWN_DRAIN_DELAY_SECS",
+        DEFAULT_DRAIN_DELAY_SECS,
+    ));
+    spawn_signal_handlers(vec![server], readiness, drain_delay);
+}
+
+// Graceful shutdown on SIGTERM or Ctrl-C: `/ready` fails for `drain_delay` so
+// that load balancers stop sending traffic, then the listeners close and
+// in-flight requests get the servers' shutdown timeout to finish. A second
+// signal stops right away.
+pub fn spawn_signal_handlers(
+    servers: Vec<ServerHandle>,
+    readiness: Readiness,
+    drain_delay: Duration,
+) {
+    let shut_down = move || shut_down(&servers, &readiness, drain_delay);
+
+    #[cfg(unix)]
+    {
+        let shut_down = shut_down.clone();
+        rt::spawn(async move {
+            use signal::unix::{signal, SignalKind};
+            let mut terminate = match signal(SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(err) => {
+                    error!("cannot listen for SIGTERM: {}", err);
+                    return;
+                }
+            };
+            while terminate.recv().await.is_some() {
+                shut_down();
+            }
+        });
+    }
+    rt::spawn(async move {
+        while signal::ctrl_c().await.is_ok() {
+            shut_down();
+        }
+    });
+}
+
+fn shut_down(servers: &[ServerHandle], readiness: &Readiness, drain_delay: Duration) {
+    let servers = servers.to_vec();
+    if readiness.begin_shutdown() {
+        warn!("second shutdown signal, stopping now");
+        rt::spawn(async move {
+            future::join_all(servers.iter().map(|s| s.stop(false))).await;
+        });
+        return;
+    }
+    info!(
+        drain_delay_secs = drain_delay.as_secs(),
+        "shutting down, no longer ready"
+    );
+    rt::spawn(async move {
+        time::sleep(drain_delay).await;
+        info!("closing listeners and draining in-flight requests");
+        future::join_all(servers.iter().map(|s| s.stop(true))).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        App, HttpServer,
+    };
+    use std::time::Instant;
+
+    #[actix_web::test]
+    async fn ready_fails_once_shutdown_begins() {
+        let readiness = Readiness::default();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(readiness.clone()))
+                .route("/ready", web::get().to(ready)),
+        )
+        .await;
+        let probe = || TestRequest::get().uri("/ready").to_request();
+
+        assert_eq!(call_service(&app, probe()).await.status(), StatusCode::OK);
+        assert!(!readiness.begin_shutdown());
+        assert!(readiness.begin_shutdown());
+        assert_eq!(
+            call_service(&app, probe()).await.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    fn server() -> actix_web::dev::Server {
+        HttpServer::new(App::new)
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap()
+            .run()
+    }
+
+    #[actix_web::test]
+    async fn stops_the_servers_after_the_drain_delay() {
+        let readiness = Readiness::default();
+        let (first, second) = (server(), server());
+        let handles = [first.handle(), second.handle()];
+        let started = Instant::now();
+
+        shut_down(&handles, &readiness, Duration::from_millis(200));
+        assert!(!readiness.is_ready());
+        let stopped = future::try_join(first, second);
+        time::timeout(Duration::from_secs(10), stopped)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[actix_web::test]
+    async fn a_second_signal_stops_without_draining() {
+        let readiness = Readiness::default();
+        let server = server();
+        let handles = [server.handle()];
+        let started = Instant::now();
+
+        shut_down(&handles, &readiness, Duration::from_secs(60));
+        shut_down(&handles, &readiness, Duration::from_secs(60));
+        time::timeout(Duration::from_secs(10), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn durations_fall_back_on_unset_or_invalid_values() {
+        env::set_var("SHUTDOWN_TEST_SECS", "12");
+        assert_eq!(env_secs("SHUTDOWN_TEST_SECS", 3), 12);
+        env::set_var("SHUTDOWN_TEST_SECS", "soon");
+        assert_eq!(env_secs("SHUTDOWN_TEST_SECS", 3), 3);
+        assert_eq!(env_secs("SHUTDOWN_TEST_UNSET_SECS", 3), 3);
+    }
+}
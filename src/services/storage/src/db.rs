@@ -51,7 +51,7 @@ where
         username, password, host, port
     );
 
-    println!("url {:?}", mongo_uri);
+    log::info!("Connecting to MongoDB at {}:{}", host, port);
     let client_options = ClientOptions::parse(&mongo_uri)
         .await
         .unwrap();
@@ -53,8 +53,13 @@ async fn main() -> std::io::Result<()> {
         local_storage_service,
         s3_storage_service,
     });
+    let state = app_state.clone();
 
-    HttpServer::new(move || {
+    let auth = internal_auth::AuthMiddleware::from_env("storage");
+    let readiness = shutdown::Readiness::default();
+    let ready = web::Data::new(readiness.clone());
+
+    let server = HttpServer::new(move || {
         let cors = Cors::permissive()
             .allow_any_origin()
             .allowed_methods(vec!["GET", "POST", "PATCH", "DELETE"])
@@ -69,12 +74,33 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(Logger::default())
             .app_data(app_state.clone())
-            .route("/storage/local/upload", web::post().to(upload_file))
-            .route("/storage/images/{file_name}", web::get().to(stream_image))
-            .route("/storage/{id}", web::delete().to(delete_file))
+            .app_data(ready.clone())
+            .route("/ready", web::get().to(shutdown::ready))
+            .service(
+                web::scope("/storage")
+                    .wrap(auth.clone())
+                    .route("/local/upload", web::post().to(upload_file))
+                    .route("/images/{file_name}", web::get().to(stream_image))
+                    .route("/{id}", web::delete().to(delete_file)),
+            )
             .wrap(cors)
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(&bind_address)?
-    .run()
-    .await
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    state
+        .db_config
+        .storage_repo
+        .get_collection()
+        .client()
+        .clone()
+        .shutdown()
+        .await;
+    println!("Server stopped");
+    Ok(())
 }
@@ -2,7 +2,10 @@ pub mod handlers;
 pub mod store;
 pub mod routes;
 
-use actix_web::{web::Data, App, HttpServer};
+use actix_web::{
+    web::{self, Data},
+    App, HttpServer,
+};
 use dotenv::dotenv;
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
 
@@ -20,11 +23,26 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Error building a connection pool");
 
-    HttpServer::new(move || {
+    let db = pool.clone();
+
+    let readiness = shutdown::Readiness::default();
+    let ready = Data::new(readiness.clone());
+
+    let server = HttpServer::new(move || {
         App::new().app_data(Data::new(AppState { db: pool.clone() }))
+            .app_data(ready.clone())
+            .route("/ready", web::get().to(shutdown::ready))
             .configure(routes::config)
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    db.close().await;
+    println!("Server stopped");
+    Ok(())
 }
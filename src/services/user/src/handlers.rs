@@ -1,6 +1,7 @@
 use crate::AppState;
 use crate::{db::DBConfig, models::*};
-use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use internal_auth::Caller;
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
@@ -11,7 +12,7 @@ use serde_json::json;
 pub async fn get_user(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
     let collection = state.db.collection::<User>("users");
 
-    let user_id = req.extensions().get::<String>().cloned();
+    let user_id = Caller::of(&req).map(|caller| caller.id);
 
     let user = match collection.find_one(doc! { "_id": user_id }).await {
         Ok(Some(user)) => user,
@@ -28,7 +29,7 @@ pub async fn change_password(
 ) -> impl Responder {
     let collection = DBConfig::user_collection().await;
 
-    let user_id = req.extensions().get::<String>().cloned();
+    let user_id = Caller::of(&req).map(|caller| caller.id);
 
     let user = match collection.find_one(doc! { "_id": user_id.clone() }).await {
         Ok(Some(user)) => user,
@@ -5,7 +5,6 @@ use mongodb::Database;
 
 mod db;
 mod handlers;
-mod middleware;
 mod models;
 mod response;
 
@@ -16,7 +15,7 @@ pub struct AppState {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
+    tracing_subscriber::fmt::init();
 
     let port = env::var("PORT").unwrap_or_else(|_| "8080".into());
 
@@ -27,19 +26,34 @@ async fn main() -> std::io::Result<()> {
     println!("Starting server on port {}", port);
 
     let app_state = web::Data::new(AppState { db });
+    let state = app_state.clone();
 
-    HttpServer::new(move || {
+    let auth = internal_auth::AuthMiddleware::from_env("user");
+    let readiness = shutdown::Readiness::default();
+    let ready = web::Data::new(readiness.clone());
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            .app_data(ready.clone())
             .wrap(Logger::default())
-            .wrap(middleware::AuthMiddleware::new(vec![]))
+            .route("/ready", web::get().to(shutdown::ready))
             .service(
                 web::scope("/api/v1/user")
+                    .wrap(auth.clone())
                     .route("", web::get().to(get_user))
                     .route("/password", web::post().to(change_password)),
             )
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(&bind_address)?
-    .run()
-    .await
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    state.db.client().clone().shutdown().await;
+    println!("Server stopped");
+    Ok(())
 }
@@ -2,7 +2,8 @@ use crate::{
     models::{Vote, VoteQuery, VoteReq},
     AppState,
 };
-use actix_web::{web, HttpMessage as _, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use internal_auth::Caller;
 use futures::StreamExt as _;
 use mongodb::bson::{self, doc, Bson, DateTime};
 
@@ -15,7 +16,7 @@ pub async fn create_or_remove_vote(
     let vote_collection = state.vote_db.clone();
     let post_collection = state.post_db.clone();
 
-    let author_id = match req.extensions().get::<String>().cloned() {
+    let author_id = match Caller::of(&req).map(|caller| caller.id) {
         Some(id) => id,
         None => {
             return HttpResponse::Unauthorized().json(serde_json::json!({
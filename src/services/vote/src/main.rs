@@ -7,7 +7,6 @@ use mongodb::Collection;
 
 mod db;
 mod handlers;
-mod middleware;
 mod models;
 
 pub struct AppState {
@@ -18,7 +17,7 @@ pub struct AppState {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
+    tracing_subscriber::fmt::init();
 
     let port = env::var("PORT").unwrap_or_else(|_| "8084".into());
 
@@ -30,21 +29,40 @@ async fn main() -> std::io::Result<()> {
     println!("Starting server on port {}", port);
 
     let app_state = web::Data::new(AppState { vote_db, post_db });
+    let state = app_state.clone();
 
-    let public_paths = vec!["/api/v1/votes/".to_string()];
+    let auth = internal_auth::AuthMiddleware::from_env("vote");
+    let readiness = shutdown::Readiness::default();
+    let ready = web::Data::new(readiness.clone());
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            .app_data(ready.clone())
             .wrap(Logger::default())
-            .wrap(middleware::AuthMiddleware::new(public_paths.clone()))
+            .route("/ready", web::get().to(shutdown::ready))
             .service(
                 web::scope("/api/v1/votes")
+                    .wrap(auth.clone())
                     .route("", web::post().to(create_or_remove_vote))
                     .route("/{post_id}", web::get().to(get_votes_by_post)),
             )
     })
+    .shutdown_timeout(shutdown::timeout_secs())
+    .disable_signals()
     .bind(&bind_address)?
-    .run()
-    .await
+    .run();
+
+    shutdown::spawn_signal_handler(server.handle(), readiness);
+    server.await?;
+
+    // Every collection was opened with its own client
+    for client in [
+        state.vote_db.client(),
+        state.post_db.client(),
+    ] {
+        client.clone().shutdown().await;
+    }
+    println!("Server stopped");
+    Ok(())
 }
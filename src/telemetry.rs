@@ -0,0 +1,139 @@
+use tracing_subscriber::{
+    filter::Targets, fmt, layer::SubscriberExt as _, util::SubscriberInitExt as _,
+};
+
+use crate::config::{LogFormat, LoggingConfig};
+#[cfg(feature = "otlp")]
+use crate::middleware::trace::RequestContext;
+
+// Log output in the configured format, filtered by `RUST_LOG` or else
+// `logging.level`. Built with the `otlp` feature, spans are also exported over
+// OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set, e.g.
+// `http://localhost:4318`. Keep the returned guard alive: dropping it flushes
+// pending spans.
+pub fn init(config: &LoggingConfig) -> Telemetry {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => directives.parse().unwrap_or_else(|err| {
+            eprintln!("Ignoring RUST_LOG={:?}: {}", directives, err);
+            config_level(config)
+        }),
+        Err(_) => config_level(config),
+    };
+    let (json, text) = match config.format {
+        LogFormat::Json => (
+            Some(fmt::layer().json().flatten_event(true).with_span_list(false)),
+            None,
+        ),
+        LogFormat::Text => (None, Some(fmt::layer())),
+    };
+    let registry = tracing_subscriber::registry()
+        .with(json)
+        .with(text)
+        .with(filter);
+
+    #[cfg(feature = "otlp")]
+    {
+        let provider = otlp::provider();
+        let layer = provider.as_ref().map(otlp::layer);
+        registry.with(layer).init();
+        Telemetry { provider }
+    }
+    #[cfg(not(feature = "otlp"))]
+    {
+        registry.init();
+        Telemetry {}
+    }
+}
+
+// Validated with the rest of the config
+fn config_level(config: &LoggingConfig) -> Targets {
+    config
+        .level
+        .parse()
+        .unwrap_or_else(|_| Targets::new().with_default(tracing::Level::INFO))
+}
+
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("Failed to flush spans: {}", err);
+            }
+        }
+    }
+}
+
+// Parent the request's span on the caller's trace, then adopt the exported
+// span's ids so what we forward upstream matches what the collector sees
+#[cfg(feature = "otlp")]
+pub fn link_span(span: &tracing::Span, mut context: RequestContext) -> RequestContext {
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt as _, TraceFlags, TraceId, TraceState,
+    };
+    use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+    if let Some(parent_id) = &context.parent_id {
+        let remote = SpanContext::new(
+            TraceId::from_hex(&context.trace_id).unwrap_or(TraceId::INVALID),
+            SpanId::from_hex(parent_id).unwrap_or(SpanId::INVALID),
+            TraceFlags::new(u8::from_str_radix(&context.flags, 16).unwrap_or(1)),
+            true,
+            TraceState::default(),
+        );
+        let parent = opentelemetry::Context::new().with_remote_span_context(remote);
+        // Fails only when no OTLP layer is installed
+        let _ = span.set_parent(parent);
+    }
+
+    let otel = span.context();
+    let exported = otel.span().span_context().clone();
+    if exported.is_valid() {
+        context.trace_id = exported.trace_id().to_string();
+        context.span_id = exported.span_id().to_string();
+        context.flags = format!("{:02x}", exported.trace_flags().to_u8());
+    }
+    context
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+    use tracing_subscriber::{registry::LookupSpan, Layer};
+
+    const SERVICE_NAME: &str = "gateway";
+
+    pub fn provider() -> Option<SdkTracerProvider> {
+        std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT")?;
+        // Reads the endpoint and the other OTEL_EXPORTER_OTLP_* settings itself
+        let exporter = match opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+        {
+            Ok(exporter) => exporter,
+            Err(err) => {
+                eprintln!("OTLP export disabled: {}", err);
+                return None;
+            }
+        };
+        Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+                .build(),
+        )
+    }
+
+    pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME))
+    }
+}